@@ -0,0 +1,303 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::{SinkExt, Stream, StreamExt};
+use inpixly_shared::{
+    ChatEntry, ClientMessage, ErrorKind, IceServer, MemberInfo, Negotiated, RoomId, ServerMessage,
+    SignalingPayload, Username,
+    codec::{self, Encoding, Frame},
+};
+use tokio::{net::TcpStream, select, sync::mpsc};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite::Message};
+use tracing::{debug, warn};
+
+use crate::Error;
+
+pub(crate) type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Something that happened in the room, in the order the server sent it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Everyone in the room, sent after joining and whenever the client fell behind
+    MemberList(Vec<MemberInfo>),
+    MemberJoined(Username),
+    MemberLeft(Username),
+    Chat {
+        from: Username,
+        message: String,
+    },
+    /// Chat log replayed after joining (`before` is `None`) or a page asked for, oldest first
+    ChatHistory {
+        before: Option<u64>,
+        messages: Vec<ChatEntry>,
+        has_more: bool,
+    },
+    /// WebRTC signaling from another member
+    Signaling {
+        from: String,
+        payload: SignalingPayload,
+    },
+    /// WebRTC signaling from the server's forwarding unit in SFU rooms
+    Sfu(SignalingPayload),
+    /// A member started or stopped sharing its screen
+    SharingChanged {
+        username: Username,
+        sharing: bool,
+    },
+    /// Notice from the operators of the server
+    Announcement(String),
+    /// Something we asked for failed, the connection stays open
+    Error(ErrorKind),
+    /// Always the last event
+    Disconnected(Disconnect),
+}
+
+/// Why a connection ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Disconnect {
+    /// The room owner removed us
+    Removed { banned: bool },
+    /// Our token joined again from somewhere else
+    TakenOver,
+    /// The server is restarting, rejoining with the stored token works once it is back
+    ServerGoingDown { reconnect_after_secs: Option<u64> },
+    /// The connection closed without a reason, or we left
+    Closed,
+}
+
+/// What the server told us when letting us in
+#[derive(Debug, Clone)]
+pub struct Joined {
+    pub room_id: RoomId,
+    pub username: Username,
+    pub token: String,
+    pub is_owner: bool,
+    /// We came back within the grace period, the others never saw us leave
+    pub resumed: bool,
+    /// STUN and TURN servers to build peer connections with
+    pub ice_servers: Vec<IceServer>,
+    pub protocol: Negotiated,
+}
+
+/// A joined room, split so events can be awaited while sending from elsewhere
+pub struct RoomConnection {
+    pub joined: Joined,
+    pub sender: RoomSender,
+    pub events: RoomEvents,
+}
+
+impl RoomConnection {
+    pub(crate) fn spawn(socket: Socket, joined: Joined) -> Self {
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let encoding = Encoding::negotiated(&joined.protocol);
+        tokio::spawn(run(socket, encoding, outgoing_rx, events_tx));
+        Self {
+            joined,
+            sender: RoomSender(outgoing_tx),
+            events: RoomEvents(events_rx),
+        }
+    }
+}
+
+enum Outgoing {
+    Message(ClientMessage),
+    Leave,
+}
+
+/// Sends requests to the room, cheap to clone
+#[derive(Clone)]
+pub struct RoomSender(mpsc::UnboundedSender<Outgoing>);
+
+impl RoomSender {
+    pub fn send(&self, message: ClientMessage) -> Result<(), Error> {
+        self.0
+            .send(Outgoing::Message(message))
+            .map_err(|_| Error::Closed)
+    }
+
+    pub fn chat(&self, message: impl Into<String>) -> Result<(), Error> {
+        self.send(ClientMessage::ChatMessage {
+            message: message.into(),
+        })
+    }
+
+    /// Ask for the page of chat messages older than the given entry id
+    pub fn fetch_chat_history(&self, before: u64) -> Result<(), Error> {
+        self.send(ClientMessage::FetchChatHistory { before })
+    }
+
+    /// Relay signaling to another member, `Offer`, `Answer` and `IceCandidate` alike
+    pub fn signal(&self, to: &Username, payload: SignalingPayload) -> Result<(), Error> {
+        let to = to.to_string();
+        self.send(match payload {
+            SignalingPayload::Offer { sdp } => ClientMessage::Offer { to, sdp },
+            SignalingPayload::Answer { sdp } => ClientMessage::Answer { to, sdp },
+            SignalingPayload::IceCandidate { candidate } => {
+                ClientMessage::IceCandidate { to, candidate }
+            }
+        })
+    }
+
+    /// Signaling with the server's forwarding unit in SFU rooms
+    pub fn sfu(&self, payload: SignalingPayload) -> Result<(), Error> {
+        self.send(ClientMessage::Sfu(payload))
+    }
+
+    /// Tell the room whether we are sharing a screen or other video
+    pub fn set_sharing(&self, sharing: bool) -> Result<(), Error> {
+        self.send(ClientMessage::SetSharing { sharing })
+    }
+
+    /// Owner only: disconnect a member, banning it for good if asked to
+    pub fn remove_member(&self, username: Username, ban: bool) -> Result<(), Error> {
+        self.send(match ban {
+            true => ClientMessage::BanMember { username },
+            false => ClientMessage::KickMember { username },
+        })
+    }
+
+    /// Leave the room and close the connection, the stored token stays valid
+    pub fn leave(&self) -> Result<(), Error> {
+        self.0.send(Outgoing::Leave).map_err(|_| Error::Closed)
+    }
+}
+
+/// Events of a joined room, ending after [`Event::Disconnected`]
+pub struct RoomEvents(mpsc::UnboundedReceiver<Event>);
+
+impl RoomEvents {
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.0.recv().await
+    }
+}
+
+impl Stream for RoomEvents {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+pub(crate) fn encode(message: &ClientMessage, encoding: Encoding) -> Result<Message, Error> {
+    Ok(match codec::encode(message, encoding)? {
+        Frame::Text(text) => Message::Text(text.into()),
+        Frame::Binary(bytes) => Message::Binary(bytes.into()),
+    })
+}
+
+/// Decode a data frame, `None` for control frames
+pub(crate) fn decode(frame: &Message) -> Option<Result<ServerMessage, Error>> {
+    match frame {
+        Message::Text(text) => Some(codec::decode_text(text.as_str()).map_err(Error::from)),
+        Message::Binary(bytes) => Some(codec::decode_binary(bytes).map_err(Error::from)),
+        _ => None,
+    }
+}
+
+/// Pump messages between the socket and the channels until either side is done
+async fn run(
+    mut socket: Socket,
+    encoding: Encoding,
+    mut outgoing: mpsc::UnboundedReceiver<Outgoing>,
+    events: mpsc::UnboundedSender<Event>,
+) {
+    let mut sending = true;
+    let disconnect = loop {
+        select! {
+            request = outgoing.recv(), if sending => match request {
+                Some(Outgoing::Message(message)) => {
+                    let frame = match encode(&message, encoding) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            warn!("Failed to encode {message:?}: {e}");
+                            continue;
+                        }
+                    };
+                    if socket.send(frame).await.is_err() {
+                        break Disconnect::Closed;
+                    }
+                }
+                Some(Outgoing::Leave) => {
+                    if let Ok(frame) = encode(&ClientMessage::Leave, encoding) {
+                        let _ = socket.send(frame).await;
+                    }
+                    let _ = socket.close(None).await;
+                    break Disconnect::Closed;
+                }
+                // Nobody can send anymore, events may still be of interest
+                None => sending = false,
+            },
+            frame = socket.next() => {
+                let message = match frame {
+                    Some(Ok(frame)) => match decode(&frame) {
+                        Some(Ok(message)) => message,
+                        Some(Err(e)) => {
+                            warn!("Dropping undecodable message: {e}");
+                            continue;
+                        }
+                        None if matches!(frame, Message::Close(_)) => break Disconnect::Closed,
+                        None => continue,
+                    },
+                    Some(Err(e)) => {
+                        debug!("WebSocket error: {e}");
+                        break Disconnect::Closed;
+                    }
+                    None => break Disconnect::Closed,
+                };
+                let event = match event(message) {
+                    Ok(Some(event)) => event,
+                    Ok(None) => continue,
+                    Err(disconnect) => break disconnect,
+                };
+                if events.send(event).is_err() {
+                    let _ = socket.close(None).await;
+                    return;
+                }
+            }
+        }
+    };
+    let _ = events.send(Event::Disconnected(disconnect));
+}
+
+/// The event a server message stands for, or why the server is about to close the connection
+fn event(message: ServerMessage) -> Result<Option<Event>, Disconnect> {
+    Ok(Some(match message {
+        ServerMessage::MemberList { members } => Event::MemberList(members),
+        ServerMessage::MemberJoined { username } => Event::MemberJoined(username),
+        ServerMessage::MemberLeft { username } => Event::MemberLeft(username),
+        ServerMessage::Chat { from, message } => Event::Chat { from, message },
+        ServerMessage::ChatHistory {
+            before,
+            messages,
+            has_more,
+        } => Event::ChatHistory {
+            before,
+            messages,
+            has_more,
+        },
+        ServerMessage::SignalingMessage { from, payload } => Event::Signaling { from, payload },
+        ServerMessage::Sfu(payload) => Event::Sfu(payload),
+        ServerMessage::SharingChanged { username, sharing } => {
+            Event::SharingChanged { username, sharing }
+        }
+        ServerMessage::Announcement { message } => Event::Announcement(message),
+        ServerMessage::Error(error) => Event::Error(error),
+        ServerMessage::Removed { banned } => return Err(Disconnect::Removed { banned }),
+        ServerMessage::ForceDisconnect => return Err(Disconnect::TakenOver),
+        ServerMessage::ServerGoingDown {
+            reconnect_after_secs,
+        } => {
+            return Err(Disconnect::ServerGoingDown {
+                reconnect_after_secs,
+            });
+        }
+        ServerMessage::JoinedAs { .. } => {
+            warn!("Ignoring JoinedAs after the handshake.");
+            return Ok(None);
+        }
+    }))
+}
@@ -0,0 +1,476 @@
+//! Async client for inpixly servers, for bots, tests and tools that join rooms without a browser
+
+use std::{fmt, io, sync::Arc};
+
+use futures_util::{SinkExt, StreamExt};
+use inpixly_shared::{
+    Capability, ClientMessage, CreateRoomRequest, CreateRoomResponse, ErrorKind, JoinRequest,
+    Password, ProtocolInfo, RoomId, RoomInfoResponse, ServerMessage, Username,
+    codec::{CodecError, Encoding},
+};
+use reqwest::{StatusCode, Url};
+use tokio_tungstenite::tungstenite;
+use tracing::debug;
+
+mod connection;
+mod tokens;
+
+pub use connection::{Disconnect, Event, Joined, RoomConnection, RoomEvents, RoomSender};
+pub use tokens::{FileTokenStore, MemoryTokenStore, RoomTokens, TokenStore};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The HTTP request did not get through
+    Http(reqwest::Error),
+    /// The API answered with an error status, and its reason if it gave one
+    Api {
+        status: StatusCode,
+        error: Option<ErrorKind>,
+    },
+    WebSocket(tungstenite::Error),
+    Codec(CodecError),
+    /// The server refused to let us into the room
+    Rejected(ErrorKind),
+    /// There is no stored token to rejoin the room with
+    NoToken,
+    /// The connection closed
+    Closed,
+    TokenStore(io::Error),
+    /// The base URL cannot have API paths appended to it
+    InvalidUrl,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(e) => write!(f, "HTTP request failed: {e}"),
+            Self::Api {
+                status,
+                error: Some(error),
+            } => write!(f, "API request failed with {status}: {error:?}"),
+            Self::Api {
+                status,
+                error: None,
+            } => write!(f, "API request failed with {status}"),
+            Self::WebSocket(e) => write!(f, "WebSocket error: {e}"),
+            Self::Codec(e) => write!(f, "Malformed message: {e}"),
+            Self::Rejected(error) => write!(f, "Join rejected: {error:?}"),
+            Self::NoToken => write!(f, "No token stored for the room"),
+            Self::Closed => write!(f, "Connection closed"),
+            Self::TokenStore(e) => write!(f, "Token store failed: {e}"),
+            Self::InvalidUrl => write!(f, "Invalid server URL"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Http(e) => Some(e),
+            Self::WebSocket(e) => Some(e),
+            Self::Codec(e) => Some(e),
+            Self::TokenStore(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        Self::WebSocket(e)
+    }
+}
+
+impl From<CodecError> for Error {
+    fn from(e: CodecError) -> Self {
+        Self::Codec(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::TokenStore(e)
+    }
+}
+
+/// Capabilities this client implements, it decodes binary frames but sends JSON until told otherwise
+fn client_protocol() -> ProtocolInfo {
+    ProtocolInfo::current(vec![
+        Capability::Sfu,
+        Capability::ChatHistory,
+        Capability::BinaryFraming,
+    ])
+}
+
+/// Talks to one server, remembering the tokens of the rooms it created or joined
+#[derive(Clone)]
+pub struct Client {
+    base_url: Url,
+    http: reqwest::Client,
+    tokens: Arc<dyn TokenStore>,
+}
+
+impl Client {
+    /// Client for the server at `base_url`, like `https://inpixly.example/`
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        let mut base_url = Url::parse(base_url).map_err(|_| Error::InvalidUrl)?;
+        if base_url.cannot_be_a_base() || !matches!(base_url.scheme(), "http" | "https") {
+            return Err(Error::InvalidUrl);
+        }
+        if !base_url.path().ends_with('/') {
+            let path = format!("{}/", base_url.path());
+            base_url.set_path(&path);
+        }
+        Ok(Self {
+            base_url,
+            http: reqwest::Client::new(),
+            tokens: Arc::new(MemoryTokenStore::default()),
+        })
+    }
+
+    /// Keep tokens somewhere else than in memory, [`FileTokenStore`] to rejoin after a restart
+    pub fn with_token_store(mut self, tokens: impl TokenStore + 'static) -> Self {
+        self.tokens = Arc::new(tokens);
+        self
+    }
+
+    pub fn tokens(&self, room_id: &RoomId) -> Option<RoomTokens> {
+        self.tokens.load(room_id)
+    }
+
+    fn url(&self, path: &str) -> Result<Url, Error> {
+        self.base_url.join(path).map_err(|_| Error::InvalidUrl)
+    }
+
+    fn ws_url(&self, room_id: &RoomId) -> Result<Url, Error> {
+        let mut url = self.url(&format!("api/rooms/{room_id}/ws"))?;
+        let scheme = match url.scheme() {
+            "https" => "wss",
+            _ => "ws",
+        };
+        url.set_scheme(scheme).map_err(|_| Error::InvalidUrl)?;
+        Ok(url)
+    }
+
+    /// Create a room and store its owner and member tokens
+    pub async fn create_room(
+        &self,
+        request: &CreateRoomRequest,
+    ) -> Result<CreateRoomResponse, Error> {
+        let response = self
+            .http
+            .post(self.url("api/rooms")?)
+            .json(request)
+            .send()
+            .await?;
+        let created: CreateRoomResponse = api_response(response).await?.json().await?;
+        self.tokens.save(
+            &created.room_id,
+            &RoomTokens {
+                member_token: Some(created.member_token.clone()),
+                owner_token: Some(created.owner_token.clone()),
+            },
+        )?;
+        Ok(created)
+    }
+
+    pub async fn room_info(&self, room_id: &RoomId) -> Result<RoomInfoResponse, Error> {
+        let response = self
+            .http
+            .get(self.url(&format!("api/rooms/{room_id}"))?)
+            .send()
+            .await?;
+        Ok(api_response(response).await?.json().await?)
+    }
+
+    /// Delete a room with its stored owner token, then forget its tokens
+    pub async fn delete_room(&self, room_id: &RoomId) -> Result<(), Error> {
+        let owner_token = self
+            .tokens
+            .load(room_id)
+            .and_then(|tokens| tokens.owner_token)
+            .ok_or(Error::NoToken)?;
+        let response = self
+            .http
+            .delete(self.url(&format!("api/rooms/{room_id}"))?)
+            .header("X-Owner-Token", owner_token)
+            .send()
+            .await?;
+        api_response(response).await?;
+        self.tokens.remove(room_id)?;
+        Ok(())
+    }
+
+    /// Rejoin a room as the member of the stored token, with owner privileges if we created it
+    pub async fn join(&self, room_id: &RoomId) -> Result<RoomConnection, Error> {
+        let tokens = self.tokens.load(room_id).unwrap_or_default();
+        let token = tokens.member_token.ok_or(Error::NoToken)?;
+        self.connect(
+            room_id,
+            JoinRequest::WithToken {
+                token,
+                owner_token: tokens.owner_token,
+            },
+        )
+        .await
+    }
+
+    /// Join a room as a new member, the token we get is stored for [`Client::join`]
+    pub async fn join_as(
+        &self,
+        room_id: &RoomId,
+        username: Username,
+        password: Option<Password>,
+    ) -> Result<RoomConnection, Error> {
+        self.connect(room_id, JoinRequest::WithUsername { username, password })
+            .await
+    }
+
+    async fn connect(
+        &self,
+        room_id: &RoomId,
+        request: JoinRequest,
+    ) -> Result<RoomConnection, Error> {
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(self.ws_url(room_id)?.as_str()).await?;
+        let ours = client_protocol();
+        // Nothing is negotiated before the server answers, so the join always goes as JSON
+        let join = ClientMessage::Join {
+            request,
+            protocol: ours.clone(),
+        };
+        socket
+            .send(connection::encode(&join, Encoding::Json)?)
+            .await?;
+
+        let (username, token, is_owner, resumed, ice_servers, theirs) = loop {
+            let frame = socket.next().await.ok_or(Error::Closed)??;
+            match connection::decode(&frame) {
+                Some(Ok(ServerMessage::JoinedAs {
+                    username,
+                    token,
+                    is_owner,
+                    resumed,
+                    ice_servers,
+                    protocol,
+                })) => break (username, token, is_owner, resumed, ice_servers, protocol),
+                Some(Ok(ServerMessage::Error(error))) => return Err(Error::Rejected(error)),
+                Some(Ok(message)) => debug!("Ignoring {message:?} before joining."),
+                Some(Err(e)) => return Err(e),
+                None if frame.is_close() => return Err(Error::Closed),
+                None => {}
+            }
+        };
+        let protocol = match ours.negotiate(&theirs) {
+            Ok(protocol) => protocol,
+            Err(error) => {
+                let _ = socket.close(None).await;
+                return Err(Error::Rejected(error));
+            }
+        };
+
+        let mut tokens = self.tokens.load(room_id).unwrap_or_default();
+        tokens.member_token = Some(token.clone());
+        self.tokens.save(room_id, &tokens)?;
+
+        Ok(RoomConnection::spawn(
+            socket,
+            Joined {
+                room_id: room_id.clone(),
+                username,
+                token,
+                is_owner,
+                resumed,
+                ice_servers,
+                protocol,
+            },
+        ))
+    }
+}
+
+/// Turn an error status into [`Error::Api`], with the `ErrorKind` body the API sends along
+async fn api_response(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let error = response.json().await.ok();
+    Err(Error::Api { status, error })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use inpixly_server::{
+        bus::LocalBus,
+        config::{Config, ProtocolConfig},
+        state::AppState,
+        storage::MemoryStore,
+    };
+    use inpixly_shared::{MemberInfo, RoomMode, SignalingPayload};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn serve(state: AppState) -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = inpixly_server::api::router().with_state(state);
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+        Client::new(&format!("http://{addr}")).unwrap()
+    }
+
+    async fn create_room(client: &Client, username: &str) -> RoomId {
+        client
+            .create_room(&CreateRoomRequest {
+                username: username.parse().unwrap(),
+                password: None,
+                mode: RoomMode::Mesh,
+            })
+            .await
+            .unwrap()
+            .room_id
+    }
+
+    /// The next event matching `filter`, skipping member lists and the like
+    async fn wait_for<T>(events: &mut RoomEvents, mut filter: impl FnMut(Event) -> Option<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let event = events.next_event().await.expect("connection closed");
+                if let Some(found) = filter(event) {
+                    return found;
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for an event")
+    }
+
+    #[tokio::test]
+    async fn creator_rejoins_as_owner() {
+        let client = serve(AppState::default()).await;
+        let room_id = create_room(&client, "alice").await;
+        assert!(client.room_info(&room_id).await.unwrap().exists);
+
+        let mut alice = client.join(&room_id).await.unwrap();
+        assert_eq!(alice.joined.username.as_str(), "alice");
+        assert!(alice.joined.is_owner);
+        let members = wait_for(&mut alice.events, |event| match event {
+            Event::MemberList(members) => Some(members),
+            _ => None,
+        })
+        .await;
+        assert_eq!(
+            members,
+            vec![MemberInfo {
+                username: "alice".parse().unwrap(),
+                is_online: true,
+                is_sharing: false,
+            }]
+        );
+
+        client.delete_room(&room_id).await.unwrap();
+        assert!(!client.room_info(&room_id).await.unwrap().exists);
+        assert!(matches!(client.join(&room_id).await, Err(Error::NoToken)));
+    }
+
+    #[tokio::test]
+    async fn members_chat_and_signal() {
+        let client = serve(AppState::default()).await;
+        let room_id = create_room(&client, "alice").await;
+        let mut alice = client.join(&room_id).await.unwrap();
+        // Stored tokens are per room, a second member needs its own client
+        let mut bob = Client::new(client.base_url.as_str())
+            .unwrap()
+            .join_as(&room_id, "bob".parse().unwrap(), None)
+            .await
+            .unwrap();
+        assert!(!bob.joined.is_owner);
+        wait_for(&mut alice.events, |event| {
+            (event == Event::MemberJoined("bob".parse().unwrap())).then_some(())
+        })
+        .await;
+
+        bob.sender.chat("hello").unwrap();
+        let (from, message) = wait_for(&mut alice.events, |event| match event {
+            Event::Chat { from, message } => Some((from, message)),
+            _ => None,
+        })
+        .await;
+        assert_eq!((from.as_str(), message.as_str()), ("bob", "hello"));
+
+        let offer = SignalingPayload::Offer {
+            sdp: "v=0".to_string(),
+        };
+        alice
+            .sender
+            .signal(&"bob".parse().unwrap(), offer.clone())
+            .unwrap();
+        let (from, payload) = wait_for(&mut bob.events, |event| match event {
+            Event::Signaling { from, payload } => Some((from, payload)),
+            _ => None,
+        })
+        .await;
+        assert_eq!((from.as_str(), payload), ("alice", offer));
+
+        bob.sender.leave().unwrap();
+        wait_for(&mut bob.events, |event| {
+            (event == Event::Disconnected(Disconnect::Closed)).then_some(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn unknown_room_is_rejected() {
+        let client = serve(AppState::default()).await;
+        let room_id: RoomId = "550e8400-e29b-41d4-a716-446655440000".parse().unwrap();
+        let result = client.join_as(&room_id, "bob".parse().unwrap(), None).await;
+        assert!(matches!(
+            result,
+            Err(Error::Rejected(ErrorKind::RoomNotFound))
+        ));
+        assert_eq!(client.tokens(&room_id), None);
+    }
+
+    #[tokio::test]
+    async fn binary_framing_is_used_when_enabled() {
+        let config = Config {
+            protocol: ProtocolConfig {
+                binary_framing: true,
+            },
+            ..Default::default()
+        };
+        let state = AppState::new(
+            &config,
+            Arc::new(MemoryStore::default()),
+            Arc::new(LocalBus::default()),
+        )
+        .unwrap();
+        let client = serve(state).await;
+        let room_id = create_room(&client, "alice").await;
+        let mut alice = client.join(&room_id).await.unwrap();
+        assert!(alice.joined.protocol.supports(Capability::BinaryFraming));
+
+        alice.sender.chat("packed").unwrap();
+        let message = wait_for(&mut alice.events, |event| match event {
+            Event::Chat { message, .. } => Some(message),
+            _ => None,
+        })
+        .await;
+        assert_eq!(message, "packed");
+    }
+}
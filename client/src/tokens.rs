@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use inpixly_shared::RoomId;
+use serde::{Deserialize, Serialize};
+
+/// What it takes to come back to a room as the same member
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomTokens {
+    /// Identifies the member, rejoining with it keeps the username
+    pub member_token: Option<String>,
+    /// Only known to whoever created the room
+    pub owner_token: Option<String>,
+}
+
+/// Where the client keeps its tokens between connections, like the browser's local storage
+pub trait TokenStore: Send + Sync {
+    fn load(&self, room_id: &RoomId) -> Option<RoomTokens>;
+
+    fn save(&self, room_id: &RoomId, tokens: &RoomTokens) -> io::Result<()>;
+
+    fn remove(&self, room_id: &RoomId) -> io::Result<()>;
+}
+
+/// Forgets everything once dropped
+#[derive(Debug, Default)]
+pub struct MemoryTokenStore {
+    rooms: Mutex<HashMap<RoomId, RoomTokens>>,
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn load(&self, room_id: &RoomId) -> Option<RoomTokens> {
+        self.rooms.lock().unwrap().get(room_id).cloned()
+    }
+
+    fn save(&self, room_id: &RoomId, tokens: &RoomTokens) -> io::Result<()> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .insert(room_id.clone(), tokens.clone());
+        Ok(())
+    }
+
+    fn remove(&self, room_id: &RoomId) -> io::Result<()> {
+        self.rooms.lock().unwrap().remove(room_id);
+        Ok(())
+    }
+}
+
+/// Keeps the tokens of every room in one JSON file, rewritten on each change
+#[derive(Debug)]
+pub struct FileTokenStore {
+    path: PathBuf,
+    rooms: Mutex<HashMap<RoomId, RoomTokens>>,
+}
+
+impl FileTokenStore {
+    /// Open the store at `path`, starting empty if the file does not exist yet
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let rooms = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            rooms: Mutex::new(rooms),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, rooms: &HashMap<RoomId, RoomTokens>) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // Replace the file in one step, a crash midway must not lose every token
+        let temp_path = self.path.with_extension("tmp");
+        std::fs::write(&temp_path, serde_json::to_vec_pretty(rooms)?)?;
+        std::fs::rename(temp_path, &self.path)
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self, room_id: &RoomId) -> Option<RoomTokens> {
+        self.rooms.lock().unwrap().get(room_id).cloned()
+    }
+
+    fn save(&self, room_id: &RoomId, tokens: &RoomTokens) -> io::Result<()> {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.insert(room_id.clone(), tokens.clone());
+        self.write(&rooms)
+    }
+
+    fn remove(&self, room_id: &RoomId) -> io::Result<()> {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.remove(room_id).is_some() {
+            self.write(&rooms)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn room_id() -> RoomId {
+        "550e8400-e29b-41d4-a716-446655440000".parse().unwrap()
+    }
+
+    #[test]
+    fn file_store_survives_reopening() {
+        let dir = std::env::temp_dir().join(format!("inpixly-tokens-{}", Uuid::new_v4()));
+        let path = dir.join("tokens.json");
+        let tokens = RoomTokens {
+            member_token: Some("member".to_string()),
+            owner_token: Some("owner".to_string()),
+        };
+
+        let store = FileTokenStore::open(&path).unwrap();
+        assert_eq!(store.load(&room_id()), None);
+        store.save(&room_id(), &tokens).unwrap();
+
+        let store = FileTokenStore::open(&path).unwrap();
+        assert_eq!(store.load(&room_id()), Some(tokens));
+        store.remove(&room_id()).unwrap();
+        assert_eq!(FileTokenStore::open(&path).unwrap().load(&room_id()), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
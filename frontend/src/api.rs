@@ -1,6 +1,9 @@
 use gloo_net::http::Request;
 use gloo_storage::{LocalStorage, Storage};
-use inpixly_shared::{CreateRoomRequest, CreateRoomResponse, Password, RoomInfoResponse, Username};
+use inpixly_shared::{
+    CreateRoomRequest, CreateRoomResponse, ErrorKind, Password, RoomInfoResponse, RoomMode,
+    Username,
+};
 
 const API_BASE: &str = "/api";
 
@@ -28,12 +31,17 @@ pub fn set_owner_token(room_id: &str, token: &str) {
     let _ = LocalStorage::set(&key, token);
 }
 
-/// Create a new room with the given username and optional password
+/// Create a new room with the given username, optional password and media mode
 pub async fn create_room(
     username: Username,
     password: Option<Password>,
+    mode: RoomMode,
 ) -> Result<CreateRoomResponse, String> {
-    let request = CreateRoomRequest { username, password };
+    let request = CreateRoomRequest {
+        username,
+        password,
+        mode,
+    };
 
     let response = Request::post(&format!("{}/rooms", API_BASE))
         .json(&request)
@@ -44,11 +52,22 @@ pub async fn create_room(
 
     if response.ok() {
         response.json().await.map_err(|e| e.to_string())
+    } else if let Ok(ErrorKind::TooManyAttempts { retry_after_secs }) = response.json().await {
+        Err(too_many_attempts_message(retry_after_secs))
     } else {
         Err(format!("Failed to create room: {}", response.status()))
     }
 }
 
+/// Tell the user how long to wait after being rate limited
+pub fn too_many_attempts_message(retry_after_secs: u64) -> String {
+    let wait = match retry_after_secs {
+        0..=59 => format!("{} s", retry_after_secs.max(1)),
+        _ => format!("{} min", retry_after_secs.div_ceil(60)),
+    };
+    format!("Too many attempts. Please try again in {wait}.")
+}
+
 /// Check if a room exists and whether it has a password
 pub async fn get_room_info(room_id: &str) -> Result<RoomInfoResponse, String> {
     let response = Request::get(&format!("{}/rooms/{}", API_BASE, room_id))
@@ -1,5 +1,5 @@
 use dioxus::prelude::*;
-use inpixly_shared::{Password, Username};
+use inpixly_shared::{Password, RoomMode, Username};
 
 use crate::api;
 use crate::Route;
@@ -15,6 +15,7 @@ pub fn Home() -> Element {
     });
     let mut password_input = use_signal(String::new);
     let mut show_password = use_signal(|| false);
+    let mut room_mode = use_signal(RoomMode::default);
     let navigator = use_navigator();
 
     let mut do_create_room = move || {
@@ -48,7 +49,7 @@ pub fn Home() -> Element {
             creating.set(true);
             error.set(None);
 
-            match api::create_room(username, password).await {
+            match api::create_room(username, password, room_mode()).await {
                 Ok(response) => {
                     let room_id = response.room_id.to_string();
                     // Store tokens
@@ -114,6 +115,20 @@ pub fn Home() -> Element {
                         }
                     }
 
+                    // Media mode toggle
+                    label { class: "flex items-center gap-2 text-gray-300 text-sm mb-4 cursor-pointer",
+                        input {
+                            r#type: "checkbox",
+                            class: "accent-purple-600",
+                            checked: room_mode() == RoomMode::Sfu,
+                            onchange: move |e| {
+                                room_mode
+                                    .set(if e.checked() { RoomMode::Sfu } else { RoomMode::Mesh });
+                            },
+                        }
+                        "Route media through the server (for larger rooms)"
+                    }
+
                     if let Some(err) = error() {
                         p { class: "text-red-400 text-sm mb-4", "{err}" }
                     }
@@ -1,8 +1,20 @@
 use dioxus::prelude::*;
 use inpixly_shared::Username;
 
+/// Position of the oldest chat message loaded from the server's log
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct ChatPaging {
+    pub oldest_id: Option<u64>,
+    pub has_more: bool,
+}
+
 #[component]
-pub fn Chat(messages: Vec<(Username, String)>, on_send: EventHandler<String>) -> Element {
+pub fn Chat(
+    messages: Vec<(Username, String)>,
+    has_more: bool,
+    on_send: EventHandler<String>,
+    on_load_more: EventHandler<()>,
+) -> Element {
     let mut input = use_signal(String::new);
 
     let send_message = move |_| {
@@ -22,6 +34,13 @@ pub fn Chat(messages: Vec<(Username, String)>, on_send: EventHandler<String>) ->
 
             // Messages
             div { class: "flex-1 overflow-y-auto p-4 space-y-3",
+                if has_more {
+                    button {
+                        class: "w-full text-purple-400 hover:text-purple-300 text-xs transition-colors",
+                        onclick: move |_| on_load_more.call(()),
+                        "Load older messages"
+                    }
+                }
                 for (i, (username, message)) in messages.iter().enumerate() {
                     div {
                         key: "{i}",
@@ -1,8 +1,14 @@
 use dioxus::prelude::*;
-use inpixly_shared::MemberInfo;
+use inpixly_shared::{MemberInfo, Username};
 
 #[component]
-pub fn MemberList(members: Vec<MemberInfo>) -> Element {
+pub fn MemberList(
+    members: Vec<MemberInfo>,
+    current_username: String,
+    is_owner: bool,
+    on_kick: EventHandler<Username>,
+    on_ban: EventHandler<Username>,
+) -> Element {
     let online_count = members.iter().filter(|m| m.is_online).count();
 
     rsx! {
@@ -14,7 +20,7 @@ pub fn MemberList(members: Vec<MemberInfo>) -> Element {
                 for member in members.iter() {
                     div {
                         key: "{member.username}",
-                        class: "flex items-center gap-2",
+                        class: "flex items-center gap-2 group",
                         div {
                             class: if member.is_online {
                                 "w-2 h-2 rounded-full bg-green-500"
@@ -30,6 +36,30 @@ pub fn MemberList(members: Vec<MemberInfo>) -> Element {
                             },
                             "{member.username}"
                         }
+                        if member.is_sharing {
+                            span { class: "text-blue-400 text-xs", "sharing" }
+                        }
+                        // Moderation actions, owners only
+                        if is_owner && member.username.as_str() != current_username {
+                            div { class: "ml-auto flex gap-2 opacity-0 group-hover:opacity-100 transition-opacity",
+                                button {
+                                    class: "text-gray-400 hover:text-white text-xs",
+                                    onclick: {
+                                        let username = member.username.clone();
+                                        move |_| on_kick.call(username.clone())
+                                    },
+                                    "Kick"
+                                }
+                                button {
+                                    class: "text-red-400 hover:text-red-300 text-xs",
+                                    onclick: {
+                                        let username = member.username.clone();
+                                        move |_| on_ban.call(username.clone())
+                                    },
+                                    "Ban"
+                                }
+                            }
+                        }
                     }
                 }
             }
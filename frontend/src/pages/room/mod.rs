@@ -3,10 +3,12 @@
 mod chat;
 mod member_list;
 mod screen_view;
+mod sfu;
 
 use dioxus::prelude::*;
 use inpixly_shared::{
-    ErrorKind, JoinRequest, MemberInfo, Password, SignalingPayload, Username, WsMessage,
+    codec, Capability, ClientMessage, ErrorKind, IceServer, JoinRequest, MemberInfo, Password,
+    ProtocolInfo, RoomMode, ServerMessage, SignalingPayload, Username,
 };
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -14,34 +16,77 @@ use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    MediaStream, RtcConfiguration, RtcIceCandidate, RtcIceCandidateInit, RtcPeerConnection,
+    MediaStream, RtcIceCandidate, RtcIceCandidateInit, RtcPeerConnection,
     RtcPeerConnectionIceEvent, RtcSdpType, RtcSessionDescriptionInit, RtcTrackEvent,
 };
 
 use crate::api;
+use crate::webrtc::rtc_configuration;
 
-pub use chat::Chat;
+pub use chat::{Chat, ChatPaging};
 pub use member_list::MemberList;
 pub use screen_view::ScreenView;
 
 #[derive(Clone, PartialEq)]
 pub enum RoomState {
     Loading,
-    NeedUsername { has_password: bool },
+    NeedUsername {
+        has_password: bool,
+    },
     NeedPassword,
     Joining,
-    Connected { username: String, is_owner: bool },
+    Connected {
+        username: String,
+        is_owner: bool,
+    },
+    /// The owner kicked or banned us
+    Removed {
+        banned: bool,
+    },
     Error(String),
 }
 
 type PeerConnections = Rc<RefCell<HashMap<String, RtcPeerConnection>>>;
 
+/// The server announced it is going down, with its hint of when to reconnect
+#[derive(Clone, Copy, PartialEq)]
+struct ServerRestart {
+    reconnect_after_secs: Option<u64>,
+}
+
+/// Room page state shared with the WebSocket handlers, outlives any single connection
+#[derive(Clone, Copy)]
+struct RoomSignals {
+    room_state: Signal<RoomState>,
+    members: Signal<Vec<MemberInfo>>,
+    chat_messages: Signal<Vec<(Username, String)>>,
+    chat_paging: Signal<ChatPaging>,
+    ws_ref: Signal<Option<Rc<RefCell<Option<web_sys::WebSocket>>>>>,
+    peers_ref: Signal<Option<PeerConnections>>,
+    sfu_ref: Signal<Option<RtcPeerConnection>>,
+    local_stream: Signal<Option<MediaStream>>,
+    remote_streams: Signal<Vec<(String, MediaStream)>>,
+    current_username: Signal<Option<String>>,
+    username_error: Signal<Option<String>>,
+    room_has_password: Signal<bool>,
+    room_mode: Signal<RoomMode>,
+    /// Handed out by the server on join
+    ice_servers: Signal<Vec<IceServer>>,
+    /// Number of the reconnect attempt in progress after the connection dropped
+    reconnect_attempt: Signal<Option<u32>>,
+    /// Latest notice from the server operators, until dismissed
+    announcement: Signal<Option<String>>,
+    /// Set from the server's going down notice until we are back in the room
+    server_restart: Signal<Option<ServerRestart>>,
+}
+
 #[component]
 pub fn Room(id: String) -> Element {
     let room_id = id.clone();
     let mut room_state = use_signal(|| RoomState::Loading);
     let members = use_signal(Vec::<MemberInfo>::new);
     let chat_messages = use_signal(Vec::<(Username, String)>::new);
+    let chat_paging = use_signal(ChatPaging::default);
     let mut username_input = use_signal(|| {
         api::get_last_username()
             .map(|u| u.to_string())
@@ -50,12 +95,37 @@ pub fn Room(id: String) -> Element {
     let mut password_input = use_signal(String::new);
     let mut show_password = use_signal(|| false);
     let mut room_has_password = use_signal(|| false);
+    let mut room_mode = use_signal(RoomMode::default);
     let mut username_error = use_signal(|| None::<String>);
     let ws_ref: Signal<Option<Rc<RefCell<Option<web_sys::WebSocket>>>>> = use_signal(|| None);
     let peers_ref: Signal<Option<PeerConnections>> = use_signal(|| None);
+    let sfu_ref: Signal<Option<RtcPeerConnection>> = use_signal(|| None);
     let mut local_stream: Signal<Option<MediaStream>> = use_signal(|| None);
     let remote_streams: Signal<Vec<(String, MediaStream)>> = use_signal(Vec::new);
     let current_username: Signal<Option<String>> = use_signal(|| None);
+    let ice_servers: Signal<Vec<IceServer>> = use_signal(Vec::new);
+    let reconnect_attempt: Signal<Option<u32>> = use_signal(|| None);
+    let mut announcement: Signal<Option<String>> = use_signal(|| None);
+    let server_restart: Signal<Option<ServerRestart>> = use_signal(|| None);
+    let signals = RoomSignals {
+        room_state,
+        members,
+        chat_messages,
+        chat_paging,
+        ws_ref,
+        peers_ref,
+        sfu_ref,
+        local_stream,
+        remote_streams,
+        current_username,
+        username_error,
+        room_has_password,
+        room_mode,
+        ice_servers,
+        reconnect_attempt,
+        announcement,
+        server_restart,
+    };
 
     // Check for existing token on mount
     use_effect({
@@ -66,22 +136,10 @@ pub fn Room(id: String) -> Element {
                 match api::get_room_info(&room_id).await {
                     Ok(info) if info.exists => {
                         room_has_password.set(info.has_password);
+                        room_mode.set(info.mode);
                         if let Some(_token) = api::get_member_token(&room_id) {
                             room_state.set(RoomState::Joining);
-                            connect_to_room(
-                                &room_id,
-                                None,
-                                None,
-                                room_state,
-                                members,
-                                chat_messages,
-                                ws_ref,
-                                peers_ref,
-                                remote_streams,
-                                current_username,
-                                username_error,
-                                room_has_password,
-                            );
+                            connect_to_room(&room_id, None, None, signals);
                         } else {
                             room_state.set(RoomState::NeedUsername {
                                 has_password: info.has_password,
@@ -129,20 +187,7 @@ pub fn Room(id: String) -> Element {
 
             username_error.set(None);
             room_state.set(RoomState::Joining);
-            connect_to_room(
-                &room_id,
-                Some(username),
-                password,
-                room_state,
-                members,
-                chat_messages,
-                ws_ref,
-                peers_ref,
-                remote_streams,
-                current_username,
-                username_error,
-                room_has_password,
-            );
+            connect_to_room(&room_id, Some(username), password, signals);
         }
     };
 
@@ -288,6 +333,27 @@ pub fn Room(id: String) -> Element {
                     }
                 }
 
+                if reconnect_attempt().is_some() {
+                    div { class: "bg-yellow-900/80 border-b border-yellow-700/50 px-4 py-2 text-yellow-200 text-sm text-center",
+                        if server_restart().is_some() {
+                            "The server is restarting, reconnecting..."
+                        } else {
+                            "Connection lost, reconnecting..."
+                        }
+                    }
+                }
+
+                if let Some(message) = announcement() {
+                    div { class: "bg-blue-900/80 border-b border-blue-700/50 px-4 py-2 text-blue-100 text-sm flex items-center justify-center gap-3",
+                        span { "{message}" }
+                        button {
+                            class: "text-blue-300 hover:text-white transition-colors",
+                            onclick: move |_| announcement.set(None),
+                            "Dismiss"
+                        }
+                    }
+                }
+
                 // Main content
                 div { class: "flex-1 flex overflow-hidden",
                     // Screen view (main area)
@@ -298,43 +364,33 @@ pub fn Room(id: String) -> Element {
                             on_share_start: move |stream: MediaStream| {
                                 web_sys::console::log_1(&"[DEBUG] on_share_start called".into());
                                 local_stream.set(Some(stream.clone()));
+                                send_ws_message(ws_ref(), &ClientMessage::SetSharing { sharing: true });
                                 // Add stream to all peer connections
                                 if let Some(peers) = peers_ref() {
                                     let peer_count = peers.borrow().len();
                                     web_sys::console::log_1(&format!("[DEBUG] Adding tracks to {} peer connections", peer_count).into());
-                                    for (username, pc) in peers.borrow().iter() {
-                                        let tracks = stream.get_tracks();
-                                        web_sys::console::log_1(&format!("[DEBUG] Adding {} tracks to peer {}", tracks.length(), username).into());
-                                        for i in 0..tracks.length() {
-                                            let track = tracks.get(i);
-                                            if let Ok(track) = track.dyn_into::<web_sys::MediaStreamTrack>() {
-                                                if let Ok(add_track_fn) = js_sys::Reflect::get(pc, &"addTrack".into()) {
-                                                    if let Ok(func) = add_track_fn.dyn_into::<js_sys::Function>() {
-                                                        let result = func.call2(pc, &track, &stream);
-                                                        web_sys::console::log_1(&format!("[DEBUG] addTrack result: {:?}", result.is_ok()).into());
-                                                    }
-                                                }
-                                            }
-                                        }
+                                    for pc in peers.borrow().values() {
+                                        add_stream_tracks(pc, &stream);
                                     }
                                 } else {
                                     web_sys::console::log_1(&"[DEBUG] No peers_ref available!".into());
                                 }
+                                if let Some(pc) = sfu_ref() {
+                                    add_stream_tracks(&pc, &stream);
+                                }
                             },
                             on_share_stop: move |_| {
                                 // Remove tracks from all peer connections
                                 if let Some(peers) = peers_ref() {
-                                    for (_username, pc) in peers.borrow().iter() {
-                                        // Get all senders and remove them
-                                        let senders = pc.get_senders();
-                                        for i in 0..senders.length() {
-                                            if let Some(sender) = senders.get(i).dyn_ref::<web_sys::RtcRtpSender>() {
-                                                let _ = pc.remove_track(sender);
-                                            }
-                                        }
+                                    for pc in peers.borrow().values() {
+                                        remove_all_tracks(pc);
                                     }
                                 }
+                                if let Some(pc) = sfu_ref() {
+                                    remove_all_tracks(&pc);
+                                }
                                 local_stream.set(None);
+                                send_ws_message(ws_ref(), &ClientMessage::SetSharing { sharing: false });
                             },
                         }
                     }
@@ -342,27 +398,62 @@ pub fn Room(id: String) -> Element {
                     // Sidebar
                     div { class: "w-80 bg-gray-900/80 border-l border-purple-900/50 flex flex-col",
                         div { class: "border-b border-purple-900/50",
-                            MemberList { members: members() }
+                            MemberList {
+                                members: members(),
+                                current_username: username.clone(),
+                                is_owner,
+                                on_kick: move |username| {
+                                    send_ws_message(ws_ref(), &ClientMessage::KickMember { username });
+                                },
+                                on_ban: move |username| {
+                                    send_ws_message(ws_ref(), &ClientMessage::BanMember { username });
+                                },
+                            }
                         }
                         div { class: "flex-1 overflow-hidden",
                             Chat {
                                 messages: chat_messages(),
+                                has_more: chat_paging().has_more,
                                 on_send: move |msg: String| {
                                     if let Some(ws_rc) = ws_ref() {
                                         if let Some(ws) = ws_rc.borrow().as_ref() {
-                                            let chat_msg = WsMessage::ChatMessage { message: msg };
+                                            let chat_msg = ClientMessage::ChatMessage { message: msg };
                                             if let Ok(json) = serde_json::to_string(&chat_msg) {
                                                 let _ = ws.send_with_str(&json);
                                             }
                                         }
                                     }
                                 },
+                                on_load_more: move |_| {
+                                    if let Some(before) = chat_paging().oldest_id {
+                                        send_ws_message(ws_ref(), &ClientMessage::FetchChatHistory { before });
+                                    }
+                                },
                             }
                         }
                     }
                 }
             }
         },
+        RoomState::Removed { banned } => rsx! {
+            div { class: "min-h-screen bg-gray-950 flex items-center justify-center p-4",
+                div { class: "text-center",
+                    div { class: "text-red-400 text-xl mb-2",
+                        if banned {
+                            "You were banned from this room"
+                        } else {
+                            "You were removed from this room"
+                        }
+                    }
+                    div { class: "text-gray-400 text-sm mb-4", "The room owner removed you." }
+                    a {
+                        href: "/",
+                        class: "text-purple-400 hover:text-purple-300 underline",
+                        "Go back home"
+                    }
+                }
+            }
+        },
         RoomState::Error(msg) => rsx! {
             div { class: "min-h-screen bg-gray-950 flex items-center justify-center p-4",
                 div { class: "text-center",
@@ -378,20 +469,58 @@ pub fn Room(id: String) -> Element {
     }
 }
 
+/// Delay before a reconnect attempt, doubling each time with some jitter
+fn reconnect_delay_ms(attempt: u32) -> u32 {
+    const BASE_MS: f64 = 500.0;
+    const MAX_MS: f64 = 30_000.0;
+    let delay = (BASE_MS * 2f64.powi(attempt.min(16) as i32)).min(MAX_MS);
+    (delay * (0.5 + js_sys::Math::random() / 2.0)) as u32
+}
+
+/// Delay before the first reconnect after a restart, spread so clients do not all arrive at once
+fn restart_delay_ms(reconnect_after_secs: u64) -> u32 {
+    const SPREAD_MS: f64 = 2_000.0;
+    let delay = reconnect_after_secs.min(300) as f64 * 1000.0;
+    (delay + SPREAD_MS * js_sys::Math::random()) as u32
+}
+
+/// Shown when this build and the server cannot talk to each other, a reload fetches a matching one
+const OUTDATED_PAGE: &str = "The server runs a different version, reload the page to join the room";
+
+/// What this client announces in the handshake
+fn client_protocol() -> ProtocolInfo {
+    ProtocolInfo::current(vec![
+        Capability::Sfu,
+        Capability::ChatHistory,
+        Capability::BinaryFraming,
+    ])
+}
+
 fn connect_to_room(
     room_id: &str,
     username: Option<Username>,
     password: Option<Password>,
-    mut room_state: Signal<RoomState>,
-    mut members: Signal<Vec<MemberInfo>>,
-    mut chat_messages: Signal<Vec<(Username, String)>>,
-    mut ws_ref: Signal<Option<Rc<RefCell<Option<web_sys::WebSocket>>>>>,
-    mut peers_ref: Signal<Option<PeerConnections>>,
-    mut remote_streams: Signal<Vec<(String, MediaStream)>>,
-    mut current_username: Signal<Option<String>>,
-    mut username_error: Signal<Option<String>>,
-    room_has_password: Signal<bool>,
+    signals: RoomSignals,
 ) {
+    let RoomSignals {
+        mut room_state,
+        mut members,
+        mut chat_messages,
+        mut chat_paging,
+        mut ws_ref,
+        mut peers_ref,
+        mut sfu_ref,
+        local_stream,
+        mut remote_streams,
+        mut current_username,
+        mut username_error,
+        room_has_password,
+        room_mode,
+        mut ice_servers,
+        mut reconnect_attempt,
+        mut announcement,
+        mut server_restart,
+    } = signals;
     let url = api::get_ws_url(room_id);
     let room_id = room_id.to_string();
 
@@ -402,28 +531,57 @@ fn connect_to_room(
             return;
         }
     };
+    ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
 
-    let ws_rc = Rc::new(RefCell::new(Some(ws.clone())));
-    ws_ref.set(Some(ws_rc.clone()));
+    // Peer connections keep signaling through the same holder after a reconnect
+    let existing_ws = ws_ref.peek().clone();
+    let ws_rc = match existing_ws {
+        Some(ws_rc) => {
+            ws_rc.replace(Some(ws.clone()));
+            ws_rc
+        }
+        None => {
+            let ws_rc = Rc::new(RefCell::new(Some(ws.clone())));
+            ws_ref.set(Some(ws_rc.clone()));
+            ws_rc
+        }
+    };
 
-    let peers: PeerConnections = Rc::new(RefCell::new(HashMap::new()));
-    peers_ref.set(Some(peers.clone()));
+    let existing_peers = peers_ref.peek().clone();
+    let peers: PeerConnections = match existing_peers {
+        Some(peers) => peers,
+        None => {
+            let peers: PeerConnections = Rc::new(RefCell::new(HashMap::new()));
+            peers_ref.set(Some(peers.clone()));
+            peers
+        }
+    };
 
     let room_id_for_open = room_id.clone();
     let room_id_for_msg = room_id.clone();
     let username_for_open = username.clone();
     let password_for_open = password.clone();
     let ws_for_signaling = ws_rc.clone();
+    let ws_for_close = ws_rc.clone();
     let peers_for_msg = peers.clone();
 
     let onopen = Closure::wrap(Box::new(move |_: JsValue| {
         let join_msg = if let Some(uname) = username_for_open.clone() {
-            WsMessage::Join(JoinRequest::WithUsername {
-                username: uname,
-                password: password_for_open.clone(),
-            })
+            ClientMessage::Join {
+                request: JoinRequest::WithUsername {
+                    username: uname,
+                    password: password_for_open.clone(),
+                },
+                protocol: client_protocol(),
+            }
         } else if let Some(token) = api::get_member_token(&room_id_for_open) {
-            WsMessage::Join(JoinRequest::WithToken { token })
+            ClientMessage::Join {
+                request: JoinRequest::WithToken {
+                    token,
+                    owner_token: api::get_owner_token(&room_id_for_open),
+                },
+                protocol: client_protocol(),
+            }
         } else {
             room_state.set(RoomState::NeedUsername {
                 has_password: room_has_password(),
@@ -439,158 +597,345 @@ fn connect_to_room(
     }) as Box<dyn FnMut(JsValue)>);
 
     let onmessage = Closure::wrap(Box::new(move |e: web_sys::MessageEvent| {
-        if let Some(text) = e.data().as_string() {
-            match serde_json::from_str::<WsMessage>(&text) {
-                Ok(WsMessage::JoinedAs {
-                    username,
-                    token,
-                    is_owner,
-                }) => {
-                    api::set_member_token(&room_id_for_msg, &token);
-                    api::set_last_username(&username);
-                    let username_str = username.to_string();
-                    current_username.set(Some(username_str.clone()));
-                    room_state.set(RoomState::Connected {
-                        username: username_str,
-                        is_owner,
-                    });
+        let data = e.data();
+        // Binary frames carry MessagePack, sent once both sides negotiated it
+        let message = if let Some(text) = data.as_string() {
+            codec::decode_text::<ServerMessage>(&text)
+        } else if let Some(buffer) = data.dyn_ref::<js_sys::ArrayBuffer>() {
+            codec::decode_binary(&js_sys::Uint8Array::new(buffer).to_vec())
+        } else {
+            return;
+        };
+        match message {
+            Ok(ServerMessage::JoinedAs {
+                username,
+                token,
+                is_owner,
+                resumed,
+                ice_servers: servers,
+                protocol,
+            }) => {
+                if client_protocol().negotiate(&protocol).is_err() {
+                    room_state.set(RoomState::Error(OUTDATED_PAGE.to_string()));
+                    return;
                 }
-                Ok(WsMessage::MemberList { members: m }) => {
-                    // Create peer connections for all online members
-                    let my_username = current_username();
-                    for member in m.iter() {
-                        let member_str = member.username.to_string();
-                        if member.is_online && my_username.as_deref() != Some(member_str.as_str()) {
-                            create_peer_connection_and_offer(
-                                &member_str,
-                                peers_for_msg.clone(),
-                                ws_for_signaling.clone(),
-                                remote_streams,
-                            );
-                        }
+                api::set_member_token(&room_id_for_msg, &token);
+                api::set_last_username(&username);
+                let username_str = username.to_string();
+                current_username.set(Some(username_str.clone()));
+                reconnect_attempt.set(None);
+                server_restart.set(None);
+                ice_servers.set(servers);
+                if !resumed {
+                    // The others saw us leave and will negotiate new connections
+                    for (_, pc) in peers_for_msg.borrow_mut().drain() {
+                        pc.close();
+                    }
+                    remote_streams.set(Vec::new());
+                    // The server forgot that we share once we left
+                    if local_stream.peek().is_some() {
+                        send_ws_message(
+                            Some(ws_for_signaling.clone()),
+                            &ClientMessage::SetSharing { sharing: true },
+                        );
                     }
-                    members.set(m);
                 }
-                Ok(WsMessage::MemberJoined { username }) => {
-                    members.with_mut(|list| {
-                        if !list.iter().any(|m| m.username == username) {
-                            list.push(MemberInfo {
-                                username: username.clone(),
-                                is_online: true,
-                            });
-                        } else {
-                            for m in list.iter_mut() {
-                                if m.username == username {
-                                    m.is_online = true;
-                                }
-                            }
-                        }
-                    });
-                    // Create offer for new member
-                    let my_username = current_username();
-                    let username_str = username.to_string();
-                    if my_username.as_deref() != Some(username_str.as_str()) {
+                // A resumed session keeps its forwarding unit connection on the server,
+                // which then signals through the new socket
+                if room_mode() == RoomMode::Sfu && (!resumed || sfu_ref.peek().is_none()) {
+                    if let Some(pc) = sfu_ref.peek().as_ref() {
+                        pc.close();
+                    }
+                    remote_streams.set(Vec::new());
+                    let pc = sfu::connect(
+                        ws_for_signaling.clone(),
+                        remote_streams,
+                        &ice_servers.peek(),
+                    );
+                    if let (Some(pc), Some(stream)) = (&pc, local_stream.peek().as_ref()) {
+                        add_stream_tracks(pc, stream);
+                    }
+                    sfu_ref.set(pc);
+                }
+                room_state.set(RoomState::Connected {
+                    username: username_str,
+                    is_owner,
+                });
+            }
+            Ok(ServerMessage::MemberList { members: m }) => {
+                // The list may be a resync after missed events, so drop peers that went offline
+                let online: Vec<String> = m
+                    .iter()
+                    .filter(|member| member.is_online)
+                    .map(|member| member.username.to_string())
+                    .collect();
+                peers_for_msg.borrow_mut().retain(|username, pc| {
+                    let keep = online.contains(username);
+                    if !keep {
+                        pc.close();
+                    }
+                    keep
+                });
+                remote_streams.with_mut(|streams| {
+                    streams.retain(|(u, _)| online.contains(u));
+                });
+
+                // Create peer connections for all online members
+                let my_username = current_username();
+                for member in m.iter() {
+                    let member_str = member.username.to_string();
+                    if room_mode() == RoomMode::Mesh
+                        && member.is_online
+                        && my_username.as_deref() != Some(member_str.as_str())
+                    {
                         create_peer_connection_and_offer(
-                            &username_str,
+                            &member_str,
                             peers_for_msg.clone(),
                             ws_for_signaling.clone(),
                             remote_streams,
+                            &ice_servers.peek(),
                         );
                     }
                 }
-                Ok(WsMessage::MemberLeft { username }) => {
-                    members.with_mut(|list| {
+                members.set(m);
+            }
+            Ok(ServerMessage::MemberJoined { username }) => {
+                members.with_mut(|list| {
+                    if !list.iter().any(|m| m.username == username) {
+                        list.push(MemberInfo {
+                            username: username.clone(),
+                            is_online: true,
+                            is_sharing: false,
+                        });
+                    } else {
                         for m in list.iter_mut() {
                             if m.username == username {
-                                m.is_online = false;
+                                m.is_online = true;
                             }
                         }
-                    });
-                    let username_str = username.to_string();
-                    // Close peer connection
-                    if let Some(pc) = peers_for_msg.borrow_mut().remove(&username_str) {
-                        pc.close();
                     }
-                    // Remove remote stream
-                    remote_streams.with_mut(|streams| {
-                        streams.retain(|(u, _)| u != &username_str);
-                    });
-                }
-                Ok(WsMessage::SignalingMessage { from, payload }) => {
-                    web_sys::console::log_1(
-                        &format!(
-                            "[DEBUG] Received signaling message from {}: {:?}",
-                            from,
-                            match &payload {
-                                SignalingPayload::Offer { .. } => "Offer",
-                                SignalingPayload::Answer { .. } => "Answer",
-                                SignalingPayload::IceCandidate { .. } => "IceCandidate",
-                            }
-                        )
-                        .into(),
-                    );
-                    handle_signaling_message(
-                        &from,
-                        payload,
+                });
+                // Create offer for new member
+                let my_username = current_username();
+                let username_str = username.to_string();
+                if room_mode() == RoomMode::Mesh
+                    && my_username.as_deref() != Some(username_str.as_str())
+                {
+                    create_peer_connection_and_offer(
+                        &username_str,
                         peers_for_msg.clone(),
                         ws_for_signaling.clone(),
                         remote_streams,
+                        &ice_servers.peek(),
                     );
                 }
-                Ok(WsMessage::Error(ErrorKind::TokenNotFound)) => {
-                    room_state.set(RoomState::NeedUsername {
-                        has_password: room_has_password(),
-                    });
-                }
-                Ok(WsMessage::Error(ErrorKind::InvalidUsername { message })) => {
-                    username_error.set(Some(message));
-                    room_state.set(RoomState::NeedUsername {
-                        has_password: room_has_password(),
-                    });
-                }
-                Ok(WsMessage::Error(ErrorKind::UsernameTaken)) => {
-                    username_error.set(Some(
-                        "Username is taken. Please choose a different one.".to_string(),
-                    ));
-                    room_state.set(RoomState::NeedUsername {
-                        has_password: room_has_password(),
-                    });
-                }
-                Ok(WsMessage::Error(ErrorKind::PasswordRequired)) => {
-                    username_error.set(Some("Password is required for this room.".to_string()));
-                    room_state.set(RoomState::NeedPassword);
-                }
-                Ok(WsMessage::Error(ErrorKind::IncorrectPassword)) => {
-                    username_error.set(Some("Incorrect password.".to_string()));
-                    room_state.set(RoomState::NeedPassword);
+            }
+            Ok(ServerMessage::MemberLeft { username }) => {
+                members.with_mut(|list| {
+                    for m in list.iter_mut() {
+                        if m.username == username {
+                            m.is_online = false;
+                            m.is_sharing = false;
+                        }
+                    }
+                });
+                let username_str = username.to_string();
+                // Close peer connection
+                if let Some(pc) = peers_for_msg.borrow_mut().remove(&username_str) {
+                    pc.close();
                 }
-                Ok(WsMessage::Error(ErrorKind::RoomNotFound)) => {
-                    room_state.set(RoomState::Error("Room not found".to_string()));
+                // Remove remote stream
+                remote_streams.with_mut(|streams| {
+                    streams.retain(|(u, _)| u != &username_str);
+                });
+            }
+            Ok(ServerMessage::SignalingMessage { from, payload }) => {
+                web_sys::console::log_1(
+                    &format!(
+                        "[DEBUG] Received signaling message from {}: {:?}",
+                        from,
+                        match &payload {
+                            SignalingPayload::Offer { .. } => "Offer",
+                            SignalingPayload::Answer { .. } => "Answer",
+                            SignalingPayload::IceCandidate { .. } => "IceCandidate",
+                        }
+                    )
+                    .into(),
+                );
+                handle_signaling_message(
+                    &from,
+                    payload,
+                    peers_for_msg.clone(),
+                    ws_for_signaling.clone(),
+                    remote_streams,
+                    &ice_servers.peek(),
+                );
+            }
+            Ok(ServerMessage::Sfu(payload)) => {
+                if let Some(pc) = sfu_ref.peek().as_ref() {
+                    sfu::handle_signal(pc, payload, ws_for_signaling.clone());
                 }
-                Ok(WsMessage::Error(ErrorKind::Other { message })) => {
-                    room_state.set(RoomState::Error(message));
+            }
+            Ok(ServerMessage::SharingChanged { username, sharing }) => {
+                members.with_mut(|list| {
+                    for m in list.iter_mut() {
+                        if m.username == username {
+                            m.is_sharing = sharing;
+                        }
+                    }
+                });
+            }
+            Ok(ServerMessage::Error(ErrorKind::TokenNotFound)) => {
+                room_state.set(RoomState::NeedUsername {
+                    has_password: room_has_password(),
+                });
+            }
+            Ok(ServerMessage::Error(ErrorKind::InvalidUsername { message })) => {
+                username_error.set(Some(message));
+                room_state.set(RoomState::NeedUsername {
+                    has_password: room_has_password(),
+                });
+            }
+            Ok(ServerMessage::Error(ErrorKind::UsernameTaken)) => {
+                username_error.set(Some(
+                    "Username is taken. Please choose a different one.".to_string(),
+                ));
+                room_state.set(RoomState::NeedUsername {
+                    has_password: room_has_password(),
+                });
+            }
+            Ok(ServerMessage::Error(ErrorKind::PasswordRequired)) => {
+                username_error.set(Some("Password is required for this room.".to_string()));
+                room_state.set(RoomState::NeedPassword);
+            }
+            Ok(ServerMessage::Error(ErrorKind::IncorrectPassword)) => {
+                username_error.set(Some("Incorrect password.".to_string()));
+                room_state.set(RoomState::NeedPassword);
+            }
+            Ok(ServerMessage::Error(ErrorKind::RoomNotFound)) => {
+                room_state.set(RoomState::Error("Room not found".to_string()));
+            }
+            Ok(ServerMessage::Error(ErrorKind::TooManyAttempts { retry_after_secs })) => {
+                username_error.set(Some(api::too_many_attempts_message(retry_after_secs)));
+                room_state.set(RoomState::NeedUsername {
+                    has_password: room_has_password(),
+                });
+            }
+            Ok(ServerMessage::Error(ErrorKind::Banned)) => {
+                room_state.set(RoomState::Removed { banned: true });
+            }
+            Ok(ServerMessage::Error(ErrorKind::IncompatibleProtocol { .. })) => {
+                room_state.set(RoomState::Error(OUTDATED_PAGE.to_string()));
+            }
+            Ok(ServerMessage::Error(ErrorKind::NotOwner)) => {
+                tracing::warn!("Only the room owner can remove members");
+            }
+            Ok(ServerMessage::ForceDisconnect) => {
+                // Not reconnecting, that would take the room back from the other tab
+                room_state.set(RoomState::Error(
+                    "This room was opened in another tab".to_string(),
+                ));
+            }
+            Ok(ServerMessage::Removed { banned }) => {
+                for (_, pc) in peers_for_msg.borrow_mut().drain() {
+                    pc.close();
                 }
-                Ok(WsMessage::Chat { from, message }) => {
-                    chat_messages.with_mut(|msgs| {
-                        msgs.push((from, message));
-                    });
+                room_state.set(RoomState::Removed { banned });
+            }
+            Ok(ServerMessage::Error(ErrorKind::Other { message })) => {
+                room_state.set(RoomState::Error(message));
+            }
+            Ok(ServerMessage::Error(
+                ErrorKind::MemberNotFound { username } | ErrorKind::MemberOffline { username },
+            )) => {
+                tracing::warn!("Signaling recipient {} is unavailable", username);
+                // Drop the stale peer connection, it will be recreated on MemberJoined
+                if let Some(pc) = peers_for_msg.borrow_mut().remove(&username) {
+                    pc.close();
                 }
-                Ok(_) => {}
-                Err(e) => {
-                    tracing::warn!("Failed to parse message: {}", e);
+            }
+            Ok(ServerMessage::ServerGoingDown {
+                reconnect_after_secs,
+            }) => {
+                // The server closes the connection next, reconnecting is left to onclose
+                server_restart.set(Some(ServerRestart {
+                    reconnect_after_secs,
+                }));
+            }
+            Ok(ServerMessage::Announcement { message }) => {
+                announcement.set(Some(message));
+            }
+            Ok(ServerMessage::Chat { from, message }) => {
+                chat_messages.with_mut(|msgs| {
+                    msgs.push((from, message));
+                });
+            }
+            Ok(ServerMessage::ChatHistory {
+                before,
+                messages,
+                has_more,
+            }) => {
+                let oldest_id = messages.first().map(|entry| entry.id);
+                let entries = messages
+                    .into_iter()
+                    .map(|entry| (entry.from, entry.message));
+                match before {
+                    // Replayed on join, replaces whatever a previous session showed
+                    None => chat_messages.set(entries.collect()),
+                    Some(_) => chat_messages.with_mut(|msgs| {
+                        msgs.splice(0..0, entries);
+                    }),
                 }
+                let oldest_id = oldest_id.or(chat_paging.peek().oldest_id);
+                chat_paging.set(ChatPaging {
+                    oldest_id,
+                    has_more,
+                });
+            }
+            Ok(ServerMessage::Error(
+                error @ (ErrorKind::TokenAlreadyInUse
+                | ErrorKind::JoinTimeout
+                | ErrorKind::SfuNotEnabled),
+            )) => {
+                tracing::warn!("Server error: {:?}", error);
+            }
+            Err(e) => {
+                tracing::warn!("Failed to parse message: {}", e);
             }
         }
     }) as Box<dyn FnMut(web_sys::MessageEvent)>);
 
+    let ws_closed = ws.clone();
+    let room_id_for_close = room_id.clone();
     let onclose = Closure::wrap(Box::new(move |_: web_sys::CloseEvent| {
-        if matches!(room_state(), RoomState::Connected { .. }) {
-            room_state.set(RoomState::Error("Connection lost".to_string()));
+        let is_current = ws_for_close.borrow().as_ref() == Some(&ws_closed);
+        if !is_current || !matches!(room_state(), RoomState::Connected { .. }) {
+            return;
         }
+        // Stay in the room and retry with the stored token until the server takes us back
+        let attempt = reconnect_attempt.peek().unwrap_or(0);
+        reconnect_attempt.set(Some(attempt + 1));
+        let delay = match *server_restart.peek() {
+            Some(ServerRestart {
+                reconnect_after_secs: Some(secs),
+            }) if attempt == 0 => restart_delay_ms(secs),
+            _ => reconnect_delay_ms(attempt),
+        };
+        tracing::info!("Connection lost, reconnecting in {} ms", delay);
+        let room_id = room_id_for_close.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            gloo_timers::future::TimeoutFuture::new(delay).await;
+            if matches!(*room_state.peek(), RoomState::Connected { .. }) {
+                connect_to_room(&room_id, None, None, signals);
+            }
+        });
     }) as Box<dyn FnMut(web_sys::CloseEvent)>);
 
     let onerror = Closure::wrap(Box::new(move |_: JsValue| {
-        room_state.set(RoomState::Error("WebSocket error".to_string()));
+        // Errors while reconnecting are followed by a close, which schedules the next attempt
+        if !matches!(room_state(), RoomState::Connected { .. }) {
+            room_state.set(RoomState::Error("WebSocket error".to_string()));
+        }
     }) as Box<dyn FnMut(JsValue)>);
 
     ws.set_onopen(Some(onopen.as_ref().unchecked_ref()));
@@ -604,11 +949,47 @@ fn connect_to_room(
     onerror.forget();
 }
 
+fn send_ws_message(ws: Option<Rc<RefCell<Option<web_sys::WebSocket>>>>, msg: &ClientMessage) {
+    if let Some(ws_rc) = ws {
+        if let Some(ws) = ws_rc.borrow().as_ref() {
+            if let Ok(json) = serde_json::to_string(msg) {
+                let _ = ws.send_with_str(&json);
+            }
+        }
+    }
+}
+
+fn add_stream_tracks(pc: &RtcPeerConnection, stream: &MediaStream) {
+    let tracks = stream.get_tracks();
+    for i in 0..tracks.length() {
+        if let Ok(track) = tracks.get(i).dyn_into::<web_sys::MediaStreamTrack>() {
+            if let Ok(add_track_fn) = js_sys::Reflect::get(pc, &"addTrack".into()) {
+                if let Ok(func) = add_track_fn.dyn_into::<js_sys::Function>() {
+                    let result = func.call2(pc, &track, stream);
+                    web_sys::console::log_1(
+                        &format!("[DEBUG] addTrack result: {:?}", result.is_ok()).into(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn remove_all_tracks(pc: &RtcPeerConnection) {
+    let senders = pc.get_senders();
+    for i in 0..senders.length() {
+        if let Some(sender) = senders.get(i).dyn_ref::<web_sys::RtcRtpSender>() {
+            pc.remove_track(sender);
+        }
+    }
+}
+
 fn create_peer_connection_and_offer(
     remote_username: &str,
     peers: PeerConnections,
     ws: Rc<RefCell<Option<web_sys::WebSocket>>>,
     mut remote_streams: Signal<Vec<(String, MediaStream)>>,
+    ice_servers: &[IceServer],
 ) {
     let remote = remote_username.to_string();
 
@@ -617,17 +998,7 @@ fn create_peer_connection_and_offer(
         return;
     }
 
-    let config = RtcConfiguration::new();
-    let ice_servers = js_sys::Array::new();
-    let server = js_sys::Object::new();
-    let _ = js_sys::Reflect::set(
-        &server,
-        &"urls".into(),
-        &"stun:stun.l.google.com:19302".into(),
-    );
-    ice_servers.push(&server);
-    config.set_ice_servers(&ice_servers);
-
+    let config = rtc_configuration(ice_servers);
     let pc = match RtcPeerConnection::new_with_configuration(&config) {
         Ok(pc) => pc,
         Err(_) => return,
@@ -638,7 +1009,7 @@ fn create_peer_connection_and_offer(
     let remote_ice = remote.clone();
     let on_ice = Closure::wrap(Box::new(move |e: RtcPeerConnectionIceEvent| {
         if let Some(candidate) = e.candidate() {
-            let msg = WsMessage::IceCandidate {
+            let msg = ClientMessage::IceCandidate {
                 to: remote_ice.clone(),
                 candidate: candidate.candidate(),
             };
@@ -727,7 +1098,7 @@ fn create_peer_connection_and_offer(
                             &format!("[DEBUG] Sending renegotiation offer to {}", remote_inner)
                                 .into(),
                         );
-                        let msg = WsMessage::Offer {
+                        let msg = ClientMessage::Offer {
                             to: remote_inner,
                             sdp,
                         };
@@ -762,7 +1133,7 @@ fn create_peer_connection_and_offer(
                     .await
                     .is_ok()
                 {
-                    let msg = WsMessage::Offer {
+                    let msg = ClientMessage::Offer {
                         to: remote_offer,
                         sdp,
                     };
@@ -783,6 +1154,7 @@ fn handle_signaling_message(
     peers: PeerConnections,
     ws: Rc<RefCell<Option<web_sys::WebSocket>>>,
     mut remote_streams: Signal<Vec<(String, MediaStream)>>,
+    ice_servers: &[IceServer],
 ) {
     let from = from.to_string();
 
@@ -790,24 +1162,14 @@ fn handle_signaling_message(
         SignalingPayload::Offer { sdp } => {
             // Create peer connection if not exists
             if !peers.borrow().contains_key(&from) {
-                let config = RtcConfiguration::new();
-                let ice_servers = js_sys::Array::new();
-                let server = js_sys::Object::new();
-                let _ = js_sys::Reflect::set(
-                    &server,
-                    &"urls".into(),
-                    &"stun:stun.l.google.com:19302".into(),
-                );
-                ice_servers.push(&server);
-                config.set_ice_servers(&ice_servers);
-
+                let config = rtc_configuration(ice_servers);
                 if let Ok(pc) = RtcPeerConnection::new_with_configuration(&config) {
                     // ICE handler
                     let ws_ice = ws.clone();
                     let remote_ice = from.clone();
                     let on_ice = Closure::wrap(Box::new(move |e: RtcPeerConnectionIceEvent| {
                         if let Some(candidate) = e.candidate() {
-                            let msg = WsMessage::IceCandidate {
+                            let msg = ClientMessage::IceCandidate {
                                 to: remote_ice.clone(),
                                 candidate: candidate.candidate(),
                             };
@@ -904,7 +1266,7 @@ fn handle_signaling_message(
                                         .await
                                         .is_ok()
                                     {
-                                        let msg = WsMessage::Offer {
+                                        let msg = ClientMessage::Offer {
                                             to: remote_inner,
                                             sdp,
                                         };
@@ -950,7 +1312,7 @@ fn handle_signaling_message(
                                     .await
                                     .is_ok()
                                 {
-                                    let msg = WsMessage::Answer {
+                                    let msg = ClientMessage::Answer {
                                         to: from_answer,
                                         sdp: answer_sdp,
                                     };
@@ -0,0 +1,166 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use dioxus::prelude::*;
+use inpixly_shared::{ClientMessage, IceServer, SignalingPayload};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    MediaStream, RtcIceCandidate, RtcIceCandidateInit, RtcPeerConnection,
+    RtcPeerConnectionIceEvent, RtcSdpType, RtcSessionDescriptionInit, RtcTrackEvent,
+};
+
+type WsRef = Rc<RefCell<Option<web_sys::WebSocket>>>;
+
+fn send_signal(ws: &WsRef, payload: SignalingPayload) {
+    if let Some(ws) = ws.borrow().as_ref() {
+        if let Ok(json) = serde_json::to_string(&ClientMessage::Sfu(payload)) {
+            let _ = ws.send_with_str(&json);
+        }
+    }
+}
+
+/// Open the single peer connection to the server's forwarding unit.
+/// The server sets the stream id of every forwarded track to the publisher's username.
+pub fn connect(
+    ws: WsRef,
+    mut remote_streams: Signal<Vec<(String, MediaStream)>>,
+    ice_servers: &[IceServer],
+) -> Option<RtcPeerConnection> {
+    let config = crate::webrtc::rtc_configuration(ice_servers);
+    let pc = RtcPeerConnection::new_with_configuration(&config).ok()?;
+
+    // ICE candidate handler
+    let ws_ice = ws.clone();
+    let on_ice = Closure::wrap(Box::new(move |e: RtcPeerConnectionIceEvent| {
+        if let Some(candidate) = e.candidate() {
+            send_signal(
+                &ws_ice,
+                SignalingPayload::IceCandidate {
+                    candidate: candidate.candidate(),
+                },
+            );
+        }
+    }) as Box<dyn FnMut(RtcPeerConnectionIceEvent)>);
+    pc.set_onicecandidate(Some(on_ice.as_ref().unchecked_ref()));
+    on_ice.forget();
+
+    // Track handler
+    let on_track = Closure::wrap(Box::new(move |e: RtcTrackEvent| {
+        let streams = e.streams();
+        if streams.length() == 0 {
+            return;
+        }
+        let Ok(stream) = streams.get(0).dyn_into::<MediaStream>() else {
+            return;
+        };
+        let username = stream.id();
+
+        // The server removes forwarded tracks when the publisher stops sharing
+        let stream_for_removal = stream.clone();
+        let username_for_removal = username.clone();
+        let on_remove_track = Closure::wrap(Box::new(move |_: JsValue| {
+            if stream_for_removal.get_tracks().length() == 0 {
+                remote_streams.with_mut(|list| {
+                    list.retain(|(u, _)| u != &username_for_removal);
+                });
+            }
+        }) as Box<dyn FnMut(JsValue)>);
+        stream.set_onremovetrack(Some(on_remove_track.as_ref().unchecked_ref()));
+        on_remove_track.forget();
+
+        remote_streams.with_mut(|list| {
+            list.retain(|(u, _)| u != &username);
+            list.push((username, stream));
+        });
+    }) as Box<dyn FnMut(RtcTrackEvent)>);
+    pc.set_ontrack(Some(on_track.as_ref().unchecked_ref()));
+    on_track.forget();
+
+    // Negotiation needed handler - fires for the data channel below and when sharing starts
+    let ws_neg = ws.clone();
+    let pc_neg = pc.clone();
+    let on_negotiation = Closure::wrap(Box::new(move |_: JsValue| {
+        let ws_inner = ws_neg.clone();
+        let pc_inner = pc_neg.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            if let Ok(offer) = JsFuture::from(pc_inner.create_offer()).await {
+                if let Some(sdp) = js_sys::Reflect::get(&offer, &"sdp".into())
+                    .ok()
+                    .and_then(|v| v.as_string())
+                {
+                    let mut desc = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
+                    desc.sdp(&sdp);
+                    if JsFuture::from(pc_inner.set_local_description(&desc))
+                        .await
+                        .is_ok()
+                    {
+                        send_signal(&ws_inner, SignalingPayload::Offer { sdp });
+                    }
+                }
+            }
+        });
+    }) as Box<dyn FnMut(JsValue)>);
+    pc.set_onnegotiationneeded(Some(on_negotiation.as_ref().unchecked_ref()));
+    on_negotiation.forget();
+
+    // Gives the first offer something to negotiate before anyone shares
+    let _ = pc.create_data_channel("sfu");
+
+    Some(pc)
+}
+
+/// Apply a signaling message from the server's forwarding unit
+pub fn handle_signal(pc: &RtcPeerConnection, payload: SignalingPayload, ws: WsRef) {
+    let pc = pc.clone();
+    match payload {
+        SignalingPayload::Offer { sdp } => {
+            // Setting a remote offer rolls back our own pending offer, we are the polite side
+            wasm_bindgen_futures::spawn_local(async move {
+                let mut desc = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
+                desc.sdp(&sdp);
+                if JsFuture::from(pc.set_remote_description(&desc))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                if let Ok(answer) = JsFuture::from(pc.create_answer()).await {
+                    if let Some(answer_sdp) = js_sys::Reflect::get(&answer, &"sdp".into())
+                        .ok()
+                        .and_then(|v| v.as_string())
+                    {
+                        let mut local_desc = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
+                        local_desc.sdp(&answer_sdp);
+                        if JsFuture::from(pc.set_local_description(&local_desc))
+                            .await
+                            .is_ok()
+                        {
+                            send_signal(&ws, SignalingPayload::Answer { sdp: answer_sdp });
+                        }
+                    }
+                }
+            });
+        }
+        SignalingPayload::Answer { sdp } => {
+            wasm_bindgen_futures::spawn_local(async move {
+                let mut desc = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
+                desc.sdp(&sdp);
+                let _ = JsFuture::from(pc.set_remote_description(&desc)).await;
+            });
+        }
+        SignalingPayload::IceCandidate { candidate } => {
+            wasm_bindgen_futures::spawn_local(async move {
+                let mut init = RtcIceCandidateInit::new(&candidate);
+                init.sdp_mid(Some("0"));
+                init.sdp_m_line_index(Some(0));
+                if let Ok(candidate) = RtcIceCandidate::new(&init) {
+                    let _ = JsFuture::from(
+                        pc.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&candidate)),
+                    )
+                    .await;
+                }
+            });
+        }
+    }
+}
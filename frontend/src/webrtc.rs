@@ -5,7 +5,7 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 
-use inpixly_shared::{SignalingPayload, WsMessage};
+use inpixly_shared::{ClientMessage, IceServer, SignalingPayload};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
@@ -14,27 +14,47 @@ use web_sys::{
     RtcSessionDescriptionInit, RtcTrackEvent,
 };
 
-/// ICE servers for WebRTC connection
-const ICE_SERVERS: &[&str] = &["stun:stun.l.google.com:19302", "stun:stun1.l.google.com:19302"];
+/// Build a peer connection configuration with the ICE servers handed out by the server
+pub fn rtc_configuration(ice_servers: &[IceServer]) -> RtcConfiguration {
+    let config = RtcConfiguration::new();
+    let servers = js_sys::Array::new();
+    for server in ice_servers {
+        let server_obj = js_sys::Object::new();
+        let urls: js_sys::Array = server.urls.iter().map(|url| JsValue::from_str(url)).collect();
+        let _ = js_sys::Reflect::set(&server_obj, &"urls".into(), &urls);
+        if let Some(username) = &server.username {
+            let _ = js_sys::Reflect::set(&server_obj, &"username".into(), &username.into());
+        }
+        if let Some(credential) = &server.credential {
+            let _ = js_sys::Reflect::set(&server_obj, &"credential".into(), &credential.into());
+        }
+        servers.push(&server_obj);
+    }
+    config.set_ice_servers(&servers);
+    config
+}
 
 /// Manages WebRTC peer connections for a room
 pub struct PeerManager {
     local_username: String,
+    ice_servers: Vec<IceServer>,
     peers: HashMap<String, PeerConnection>,
     on_track: Rc<RefCell<Box<dyn Fn(String, MediaStream)>>>,
     on_chat_message: Rc<RefCell<Box<dyn Fn(String, String)>>>,
-    send_signaling: Rc<RefCell<Box<dyn Fn(WsMessage)>>>,
+    send_signaling: Rc<RefCell<Box<dyn Fn(ClientMessage)>>>,
 }
 
 impl PeerManager {
     pub fn new(
         local_username: String,
+        ice_servers: Vec<IceServer>,
         on_track: impl Fn(String, MediaStream) + 'static,
         on_chat_message: impl Fn(String, String) + 'static,
-        send_signaling: impl Fn(WsMessage) + 'static,
+        send_signaling: impl Fn(ClientMessage) + 'static,
     ) -> Self {
         Self {
             local_username,
+            ice_servers,
             peers: HashMap::new(),
             on_track: Rc::new(RefCell::new(Box::new(on_track))),
             on_chat_message: Rc::new(RefCell::new(Box::new(on_chat_message))),
@@ -64,7 +84,7 @@ impl PeerManager {
             .map_err(|e| format!("Failed to set local description: {:?}", e))?;
 
         // Send offer via signaling
-        let msg = WsMessage::Offer {
+        let msg = ClientMessage::Offer {
             to: remote_username.to_string(),
             sdp: offer_sdp,
         };
@@ -102,7 +122,7 @@ impl PeerManager {
             .map_err(|e| format!("Failed to set local description: {:?}", e))?;
 
         // Send answer via signaling
-        let msg = WsMessage::Answer {
+        let msg = ClientMessage::Answer {
             to: from.to_string(),
             sdp: answer_sdp,
         };
@@ -202,6 +222,7 @@ impl PeerManager {
             let pc = PeerConnection::new(
                 remote_username,
                 &self.local_username,
+                &self.ice_servers,
                 self.on_track.clone(),
                 self.on_chat_message.clone(),
                 self.send_signaling.clone(),
@@ -225,21 +246,12 @@ impl PeerConnection {
     fn new(
         remote_username: &str,
         _local_username: &str,
+        ice_servers: &[IceServer],
         on_track: Rc<RefCell<Box<dyn Fn(String, MediaStream)>>>,
         on_chat_message: Rc<RefCell<Box<dyn Fn(String, String)>>>,
-        send_signaling: Rc<RefCell<Box<dyn Fn(WsMessage)>>>,
+        send_signaling: Rc<RefCell<Box<dyn Fn(ClientMessage)>>>,
     ) -> Result<Self, String> {
-        // Create RTCConfiguration with ICE servers
-        let config = RtcConfiguration::new();
-        let ice_servers = js_sys::Array::new();
-        for server in ICE_SERVERS {
-            let server_obj = js_sys::Object::new();
-            js_sys::Reflect::set(&server_obj, &"urls".into(), &(*server).into())
-                .map_err(|_| "Failed to set ICE server URL")?;
-            ice_servers.push(&server_obj);
-        }
-        config.set_ice_servers(&ice_servers);
-
+        let config = rtc_configuration(ice_servers);
         let pc = RtcPeerConnection::new_with_configuration(&config)
             .map_err(|e| format!("Failed to create peer connection: {:?}", e))?;
 
@@ -264,7 +276,7 @@ impl PeerConnection {
         let send_sig = send_signaling.clone();
         let on_ice_candidate = Closure::wrap(Box::new(move |e: RtcPeerConnectionIceEvent| {
             if let Some(candidate) = e.candidate() {
-                let msg = WsMessage::IceCandidate {
+                let msg = ClientMessage::IceCandidate {
                     to: remote_for_ice.clone(),
                     candidate: candidate.candidate(),
                 };
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 
 use futures::channel::mpsc;
-use inpixly_shared::WsMessage;
+use inpixly_shared::{ClientMessage, ServerMessage};
 use std::cell::RefCell;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
@@ -17,7 +17,7 @@ pub struct WsClient {
 impl WsClient {
     pub fn connect(
         url: &str,
-        on_message: impl Fn(WsMessage) + 'static,
+        on_message: impl Fn(ServerMessage) + 'static,
         on_close: impl Fn() + 'static,
         on_error: impl Fn(String) + 'static,
     ) -> Result<Self, String> {
@@ -25,9 +25,9 @@ impl WsClient {
 
         let on_message_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
             if let Some(text) = e.data().as_string() {
-                match serde_json::from_str::<WsMessage>(&text) {
+                match serde_json::from_str::<ServerMessage>(&text) {
                     Ok(msg) => on_message(msg),
-                    Err(err) => tracing::warn!("Failed to parse WsMessage: {}", err),
+                    Err(err) => tracing::warn!("Failed to parse ServerMessage: {}", err),
                 }
             }
         }) as Box<dyn FnMut(MessageEvent)>);
@@ -52,7 +52,7 @@ impl WsClient {
         })
     }
 
-    pub fn send(&self, msg: &WsMessage) -> Result<(), String> {
+    pub fn send(&self, msg: &ClientMessage) -> Result<(), String> {
         let json = serde_json::to_string(msg).map_err(|e| e.to_string())?;
         self.ws.send_with_str(&json).map_err(|e| format!("{:?}", e))
     }
@@ -74,7 +74,7 @@ impl Drop for WsClient {
 
 pub fn create_ws_channel(
     url: &str,
-) -> Result<(WsClient, mpsc::UnboundedReceiver<WsMessage>), String> {
+) -> Result<(WsClient, mpsc::UnboundedReceiver<ServerMessage>), String> {
     let (tx, rx) = mpsc::unbounded();
     let tx = Rc::new(RefCell::new(tx));
 
@@ -1,5 +1,112 @@
 {
   server = {
     bind = "0.0.0.0:3000";
+    # Serve HTTPS/WSS directly, the certificate is reloaded when the files change or on SIGHUP
+    # tls = {
+    #   cert_path = "/etc/inpixly/fullchain.pem";
+    #   key_path = "/etc/inpixly/privkey.pem";
+    #   # Redirect plain HTTP requests to HTTPS
+    #   redirect_http_bind = "0.0.0.0:80";
+    # };
+  };
+  sfu = {
+    # Public IPs advertised to peers of SFU rooms when running behind a 1:1 NAT
+    public_ips = [ ];
+  };
+  chat = {
+    # Most recent messages kept per room and replayed to members who join later
+    history_size = 500;
+    page_size = 50;
+  };
+  password_hash = {
+    # Argon2id cost of room passwords, existing hashes keep the cost they were made with
+    memory_kib = 19456;
+    iterations = 2;
+    parallelism = 1;
+  };
+  rate_limit = {
+    # Each limit allows max_attempts within window_secs, then refuses for lockout_secs.
+    # Also available: join_per_room, failed_password_per_room, failed_password_per_room_and_ip.
+    join_per_ip = { max_attempts = 20; window_secs = 60; lockout_secs = 60; };
+    failed_password_per_ip = { max_attempts = 5; window_secs = 300; lockout_secs = 300; };
+    create_room_per_ip = { max_attempts = 10; window_secs = 600; lockout_secs = 600; };
+  };
+  heartbeat = {
+    # Connections that leave this many pings unanswered are considered dead
+    interval_secs = 15;
+    max_missed_pongs = 2;
+  };
+  protocol = {
+    # Send MessagePack in binary frames to clients announcing support, JSON otherwise
+    binary_framing = false;
+  };
+  session = {
+    # Members that drop stay in the room this long, reconnecting within it goes unnoticed
+    reconnect_grace_secs = 10;
+  };
+  shutdown = {
+    # On SIGINT/SIGTERM members are told the server goes down, then it waits up to this long
+    # for them to disconnect before saving all rooms and exiting
+    drain_secs = 10;
+    # Tells clients when to reconnect, e.g. how long a restart usually takes
+    # restart_hint_secs = 5;
+  };
+  ice = {
+    # Handed to browsers for their peer connections, add a TURN server for clients behind strict NATs
+    servers = [
+      { urls = [ "stun:stun.l.google.com:19302" "stun:stun1.l.google.com:19302" ]; }
+    ];
+    # A TURN server sharing a secret with us (coturn: use-auth-secret, static-auth-secret),
+    # every member gets its own credentials valid for ttl_secs
+    # turn = {
+    #   urls = [ "turn:turn.example.com:3478" "turns:turn.example.com:5349" ];
+    #   secret = "change-me";
+    #   ttl_secs = 86400;
+    # };
+  };
+  # Runs a STUN/TURN server in this process and hands it to clients instead of ice.turn,
+  # so rooms work behind symmetric NATs without a separate coturn
+  # turn_server = {
+  #   bind = "0.0.0.0:3478";
+  #   public_ip = "203.0.113.10";
+  #   relay_port_min = 49152;
+  #   relay_port_max = 65535;
+  #   credential_ttl_secs = 86400;
+  #   max_allocations = 1000;
+  #   # Relays refuse loopback, private and link-local peers unless allowed here
+  #   allowed_peers = [ "10.0.0.0/8" ];
+  # };
+  frontend = {
+    # "none" serves only the API. "dir" serves the output of `dx bundle` from path,
+    # "embedded" the copy baked in by the embed-frontend feature.
+    # Both fall back to index.html for client routes and serve .br/.gz files when accepted.
+    source = "none";
+    # source = "dir";
+    # path = "../frontend/dist";
+  };
+  # Prometheus metrics at /metrics, on their own listener and/or behind a bearer token
+  # metrics = {
+  #   bind = "127.0.0.1:9100";
+  #   token = "change-me";
+  # };
+  # Operator API under /api/admin: list rooms and members, delete rooms, kick members
+  # and send announcements, authenticated with `Authorization: Bearer <token>`
+  # admin = {
+  #   token = "change-me";
+  # };
+  # How room events (presence, chat, signaling) reach members connected to other instances.
+  # "local" keeps them in this process. "nats" needs the nats feature and lets several
+  # instances share rooms. Rooms and members go through the storage below, so all of them
+  # must point at the same sqlite database. Each still keeps its own chat history.
+  bus = {
+    backend = "local";
+    # backend = "nats";
+    # url = "nats://127.0.0.1:4222";
+    # subject_prefix = "inpixly.rooms";
+  };
+  storage = {
+    # "memory" forgets all rooms on restart
+    backend = "sqlite";
+    path = "inpixly.db";
   };
 }
@@ -0,0 +1,391 @@
+use std::sync::Arc;
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use chrono::{DateTime, Utc};
+use inpixly_shared::{ErrorKind, RoomId, RoomMode, ServerMessage, Username};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{auth, config::AdminConfig, room::Room, state::AppState, storage::StoredRoom};
+
+type AdminError = (StatusCode, Json<ErrorKind>);
+
+#[derive(Debug, Serialize)]
+struct AdminRoomSummary {
+    id: RoomId,
+    mode: RoomMode,
+    has_password: bool,
+    last_activity: DateTime<Utc>,
+    members: usize,
+    online_members: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminRoomDetails {
+    #[serde(flatten)]
+    summary: AdminRoomSummary,
+    member_list: Vec<AdminMemberInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminMemberInfo {
+    username: Username,
+    is_online: bool,
+    last_seen: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KickQuery {
+    #[serde(default)]
+    ban: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnnouncementRequest {
+    message: String,
+}
+
+/// Operator API under `/api/admin`, every request needs the admin token
+pub fn router(config: &AdminConfig) -> anyhow::Result<Router<AppState>> {
+    anyhow::ensure!(!config.token.is_empty(), "admin.token must not be empty");
+    let token: Arc<str> = config.token.as_str().into();
+    Ok(Router::new()
+        .route("/api/admin/rooms", get(list_rooms))
+        .route("/api/admin/rooms/{id}", get(get_room).delete(delete_room))
+        .route(
+            "/api/admin/rooms/{id}/members/{username}",
+            delete(kick_member),
+        )
+        .route("/api/admin/rooms/{id}/announcements", post(announce_room))
+        .route("/api/admin/announcements", post(announce_all))
+        .layer(middleware::from_fn_with_state(token, require_token)))
+}
+
+async fn require_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    if !auth::has_bearer_token(request.headers(), &token) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+fn summary(room: &Room) -> AdminRoomSummary {
+    AdminRoomSummary {
+        id: room.id.clone(),
+        mode: room.mode(),
+        has_password: room.has_password(),
+        last_activity: room.last_activity,
+        members: room.members.len(),
+        online_members: room.members.values().filter(|m| m.is_online()).count(),
+    }
+}
+
+/// Summary of a room as the store has it, presence as written by the hosting instances
+fn stored_summary(room: &StoredRoom) -> AdminRoomSummary {
+    AdminRoomSummary {
+        id: room.id.clone(),
+        mode: room.mode,
+        has_password: room.password.is_some(),
+        last_activity: room.last_activity,
+        members: room.members.len(),
+        online_members: room.members.iter().filter(|m| m.is_online).count(),
+    }
+}
+
+fn room_not_found() -> AdminError {
+    (StatusCode::NOT_FOUND, Json(ErrorKind::RoomNotFound))
+}
+
+/// GET /api/admin/rooms - All rooms, most recently active first
+async fn list_rooms(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<AdminRoomSummary>>, AdminError> {
+    let mut summaries: Vec<_> = if state.bus.is_shared() {
+        // Rooms created on other instances sharing the store are only known to the store
+        let store = state.store.clone();
+        let stored = tokio::task::spawn_blocking(move || store.load_rooms())
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|rooms| rooms)
+            .map_err(|e| {
+                tracing::error!("Failed to load rooms: {e:#}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ErrorKind::Other {
+                        message: "Failed to load rooms".to_string(),
+                    }),
+                )
+            })?;
+        stored.iter().map(stored_summary).collect()
+    } else {
+        state.rooms.read().await.values().map(summary).collect()
+    };
+    summaries.sort_by_key(|room| std::cmp::Reverse(room.last_activity));
+    Ok(Json(summaries))
+}
+
+/// GET /api/admin/rooms/:id - A room with its members
+async fn get_room(
+    Path(room_id): Path<RoomId>,
+    State(state): State<AppState>,
+) -> Result<Json<AdminRoomDetails>, AdminError> {
+    state.sync_room(&room_id).await;
+    let rooms = state.rooms.read().await;
+    let room = rooms.get(&room_id).ok_or_else(room_not_found)?;
+    Ok(Json(AdminRoomDetails {
+        summary: summary(room),
+        member_list: room
+            .members
+            .values()
+            .map(|member| AdminMemberInfo {
+                username: member.username().clone(),
+                is_online: member.is_online(),
+                last_seen: member.last_seen(),
+            })
+            .collect(),
+    }))
+}
+
+/// DELETE /api/admin/rooms/:id - Delete a room regardless of its owner
+async fn delete_room(
+    Path(room_id): Path<RoomId>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, AdminError> {
+    state.sync_room(&room_id).await;
+    let mut rooms = state.rooms.write().await;
+    let room = rooms.remove(&room_id).ok_or_else(room_not_found)?;
+    // Otherwise connected members only see the connection drop and reconnect into RoomNotFound.
+    // Relaying reaches members connected to other instances sharing the room too.
+    for member in room.members.values().filter(|m| m.is_online()) {
+        let _ = room.relay(
+            member.username().as_str(),
+            ServerMessage::Removed { banned: false },
+        );
+    }
+    state.delete_room(&room_id);
+    state.metrics.rooms_deleted.inc();
+    info!("Deleted room {room_id} by admin request");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// DELETE /api/admin/rooms/:id/members/:username?ban=true - Kick or ban a member
+async fn kick_member(
+    Path((room_id, username)): Path<(RoomId, Username)>,
+    Query(query): Query<KickQuery>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, AdminError> {
+    state.sync_room(&room_id).await;
+    let mut rooms = state.rooms.write().await;
+    let room = rooms.get_mut(&room_id).ok_or_else(room_not_found)?;
+    let token = room
+        .remove_member(&username, query.ban)
+        .map_err(|e| (StatusCode::NOT_FOUND, Json(e)))?;
+    state.save_room(room);
+    state.delete_member(&room_id, &token);
+    info!(%room_id, %username, ban = query.ban, "Member removed by admin request.");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/admin/rooms/:id/announcements - Show a notice to everyone in a room
+async fn announce_room(
+    Path(room_id): Path<RoomId>,
+    State(state): State<AppState>,
+    Json(request): Json<AnnouncementRequest>,
+) -> Result<StatusCode, AdminError> {
+    let message = announcement(request)?;
+    state.sync_room(&room_id).await;
+    let rooms = state.rooms.read().await;
+    let room = rooms.get(&room_id).ok_or_else(room_not_found)?;
+    room.broadcast(message);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/admin/announcements - Show a notice to everyone in every room
+async fn announce_all(
+    State(state): State<AppState>,
+    Json(request): Json<AnnouncementRequest>,
+) -> Result<StatusCode, AdminError> {
+    let message = announcement(request)?;
+    let rooms = state.rooms.read().await;
+    for room in rooms.values() {
+        room.broadcast(message.clone());
+    }
+    info!("Sent announcement to {} rooms", rooms.len());
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn announcement(request: AnnouncementRequest) -> Result<ServerMessage, AdminError> {
+    let message = request.message.trim();
+    if message.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorKind::Other {
+                message: "Announcement must not be empty".to_string(),
+            }),
+        ));
+    }
+    Ok(ServerMessage::Announcement {
+        message: message.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, header},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        bus::{BrokerBus, BusEvent, FakeBroker},
+        config::Config,
+        room::RoomEvent,
+        storage::MemoryStore,
+    };
+
+    const TOKEN: &str = "admin-token";
+
+    fn app(state: &AppState) -> Router {
+        let config = AdminConfig {
+            token: TOKEN.to_string(),
+        };
+        router(&config).unwrap().with_state(state.clone())
+    }
+
+    async fn state_with_room() -> (AppState, RoomId) {
+        let state = AppState::default();
+        let mut room = Room::default();
+        room.add_member("alice".parse().unwrap(), true).unwrap();
+        let room_id = room.id.clone();
+        state.rooms.write().await.insert(room_id.clone(), room);
+        (state, room_id)
+    }
+
+    async fn send(state: &AppState, method: &str, uri: &str, body: Option<&str>) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {TOKEN}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+            .unwrap();
+        app(state).oneshot(request).await.unwrap()
+    }
+
+    async fn json(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requires_admin_token() {
+        let (state, _) = state_with_room().await;
+        let request = Request::get("/api/admin/rooms")
+            .header(header::AUTHORIZATION, "Bearer wrong")
+            .body(Body::empty())
+            .unwrap();
+        let response = app(&state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn lists_rooms_and_members() {
+        let (state, room_id) = state_with_room().await;
+        let rooms = json(send(&state, "GET", "/api/admin/rooms", None).await).await;
+        assert_eq!(rooms[0]["id"], room_id.as_str());
+        assert_eq!(rooms[0]["members"], 1);
+        assert_eq!(rooms[0]["has_password"], false);
+
+        let uri = format!("/api/admin/rooms/{room_id}");
+        let room = json(send(&state, "GET", &uri, None).await).await;
+        assert_eq!(room["member_list"][0]["username"], "alice");
+        assert!(room["member_list"][0]["last_seen"].is_string());
+    }
+
+    #[tokio::test]
+    async fn kicks_members_and_deletes_rooms() {
+        let (state, room_id) = state_with_room().await;
+        let uri = format!("/api/admin/rooms/{room_id}/members/alice?ban=true");
+        let response = send(&state, "DELETE", &uri, None).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(state.rooms.read().await[&room_id].members.is_empty());
+
+        let uri = format!("/api/admin/rooms/{room_id}");
+        let response = send(&state, "DELETE", &uri, None).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(state.rooms.read().await.is_empty());
+        let response = send(&state, "DELETE", &uri, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn tells_connected_members_their_room_was_deleted() {
+        let (state, room_id) = state_with_room().await;
+        let mut direct_rx = {
+            let mut rooms = state.rooms.write().await;
+            let room = rooms.get_mut(&room_id).unwrap();
+            let token = room.members.keys().next().unwrap().clone();
+            room.register_member_channel(&token).unwrap().0
+        };
+        let uri = format!("/api/admin/rooms/{room_id}");
+        let response = send(&state, "DELETE", &uri, None).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(matches!(
+            direct_rx.try_recv(),
+            Ok(RoomEvent::Direct(ServerMessage::Removed { banned: false }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn broadcasts_announcements() {
+        let (state, room_id) = state_with_room().await;
+        let mut events = state.rooms.read().await[&room_id].subscribe();
+        let body = r#"{"message": "Maintenance in 5 minutes"}"#;
+        let response = send(&state, "POST", "/api/admin/announcements", Some(body)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(BusEvent::Broadcast {
+                message: ServerMessage::Announcement { message },
+            }) if message == "Maintenance in 5 minutes"
+        ));
+
+        let uri = format!("/api/admin/rooms/{room_id}/announcements");
+        let response = send(&state, "POST", &uri, Some(r#"{"message": " "}"#)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn sees_rooms_of_other_instances() {
+        let store = Arc::new(MemoryStore::default());
+        let instance = || {
+            let bus = Arc::new(BrokerBus::new(FakeBroker::default(), "rooms".to_string()));
+            AppState::new(&Config::default(), store.clone(), bus).unwrap()
+        };
+        let (other, state) = (instance(), instance());
+        let mut room = Room::default();
+        room.add_member("alice".parse().unwrap(), true).unwrap();
+        let room_id = room.id.clone();
+        other.save_room(&room);
+        other.flush_store().await;
+
+        let rooms = json(send(&state, "GET", "/api/admin/rooms", None).await).await;
+        assert_eq!(rooms[0]["id"], room_id.as_str());
+        assert_eq!(rooms[0]["online_members"], 1);
+
+        let uri = format!("/api/admin/rooms/{room_id}/announcements");
+        let body = r#"{"message": "Maintenance in 5 minutes"}"#;
+        let response = send(&state, "POST", &uri, Some(body)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+}
@@ -0,0 +1,153 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    Json, Router,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+};
+use inpixly_shared::{
+    CreateRoomRequest, CreateRoomResponse, ErrorKind, RoomId, RoomInfoResponse, RoomMode,
+};
+use tracing::info;
+
+use crate::{room::Room, sfu::Sfu, state::AppState, ws};
+
+/// Public room API and the WebSocket endpoint members join through
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/rooms", post(create_room))
+        .route("/api/rooms/{id}", get(get_room).delete(delete_room))
+        .route("/api/rooms/{id}/ws", get(ws::ws_handler))
+}
+
+/// POST /api/rooms - Create a new room
+async fn create_room(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(request): Json<CreateRoomRequest>,
+) -> Result<Json<CreateRoomResponse>, (StatusCode, Json<ErrorKind>)> {
+    state
+        .rate_limits
+        .create_room(addr.ip())
+        .map_err(|e| (StatusCode::TOO_MANY_REQUESTS, Json(e)))?;
+
+    let sfu = match request.mode {
+        RoomMode::Mesh => None,
+        RoomMode::Sfu => {
+            let sfu = Sfu::new(&state.sfu_config).map_err(|e| {
+                tracing::error!("Failed to create SFU: {e:#}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ErrorKind::SfuNotEnabled),
+                )
+            })?;
+            Some(Arc::new(sfu))
+        }
+    };
+    let password_params = state.password_params.clone();
+    let chat_history_size = state.chat_config.history_size;
+    let bus = state.bus.clone();
+    // Hashing the password is slow, keep it off the async runtime
+    let mut room = tokio::task::spawn_blocking(move || {
+        Room::new(
+            request.password,
+            &password_params,
+            sfu,
+            chat_history_size,
+            bus,
+        )
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|room| room)
+    .map_err(|e| {
+        tracing::error!("Failed to create room: {e:#}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorKind::Other {
+                message: "Failed to create room".to_string(),
+            }),
+        )
+    })?;
+    let room_id = room.id.clone();
+    let owner_token = room.owner_token.clone();
+
+    // Add creator as first member
+    let (username, member_token) = room
+        .add_member(request.username, false)
+        .map_err(|e| (StatusCode::BAD_REQUEST, Json(e)))?;
+
+    state.save_room(&room);
+    state.rooms.write().await.insert(room_id.clone(), room);
+    // Members may join through any instance sharing the store, it must know the room first
+    if state.bus.is_shared() {
+        state.flush_store().await;
+    }
+    state.metrics.rooms_created.inc();
+
+    info!(
+        "Created new {:?} room: {} by {}",
+        request.mode, room_id, username
+    );
+
+    Ok(Json(CreateRoomResponse {
+        room_id,
+        owner_token,
+        member_token,
+        username,
+    }))
+}
+
+/// GET /api/rooms/:id - Check if room exists
+async fn get_room(
+    Path(room_id): Path<RoomId>,
+    State(state): State<AppState>,
+) -> Json<RoomInfoResponse> {
+    state.sync_room(&room_id).await;
+    let rooms = state.rooms.read().await;
+    match rooms.get(&room_id) {
+        Some(room) => Json(RoomInfoResponse {
+            exists: true,
+            has_password: room.has_password(),
+            mode: room.mode(),
+        }),
+        None => Json(RoomInfoResponse {
+            exists: false,
+            has_password: false,
+            mode: RoomMode::default(),
+        }),
+    }
+}
+
+/// DELETE /api/rooms/:id - Delete a room (requires owner_token)
+async fn delete_room(
+    Path(room_id): Path<RoomId>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> StatusCode {
+    let owner_token = match headers.get("X-Owner-Token") {
+        Some(token) => match token.to_str() {
+            Ok(t) => t.to_string(),
+            Err(_) => return StatusCode::BAD_REQUEST,
+        },
+        None => return StatusCode::UNAUTHORIZED,
+    };
+
+    state.sync_room(&room_id).await;
+    let mut rooms = state.rooms.write().await;
+
+    if let Some(room) = rooms.get(&room_id) {
+        if room.is_owner(&owner_token) {
+            rooms.remove(&room_id);
+            state.delete_room(&room_id);
+            state.metrics.rooms_deleted.inc();
+            info!("Deleted room: {}", room_id);
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::FORBIDDEN
+        }
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
@@ -0,0 +1,12 @@
+use axum::http::{HeaderMap, header};
+use subtle::ConstantTimeEq;
+
+/// Whether the request carries `Authorization: Bearer <token>`
+pub fn has_bearer_token(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // Response times must not reveal how much of a guess was right
+        .is_some_and(|given| given.as_bytes().ct_eq(token.as_bytes()).into())
+}
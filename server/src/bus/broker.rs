@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures_util::{StreamExt, stream::BoxStream};
+use inpixly_shared::RoomId;
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+    time::interval,
+};
+use tracing::{error, warn};
+
+use super::{BusEvent, ROOM_CHANNEL_CAPACITY, RoomBus};
+
+/// How often a room subscription checks whether anyone on this instance still listens
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Pub/sub transport between server instances, payloads are opaque to it
+#[async_trait]
+pub trait Broker: Send + Sync + 'static {
+    async fn publish(&self, subject: String, payload: Vec<u8>) -> anyhow::Result<()>;
+
+    /// Payloads published to the subject from now on, by any instance including this one
+    async fn subscribe(&self, subject: String) -> anyhow::Result<BoxStream<'static, Vec<u8>>>;
+}
+
+type Rooms = Arc<Mutex<HashMap<RoomId, broadcast::Sender<BusEvent>>>>;
+
+/// Exchanges room events with other instances through a [`Broker`], one subject per room.
+/// Events published here come back through the broker like everyone else's.
+pub struct BrokerBus<B> {
+    broker: Arc<B>,
+    subject_prefix: String,
+    /// Rooms with subscribers on this instance, fed by one broker subscription each
+    rooms: Rooms,
+    /// Publishing happens on a single task, so events of a room keep their order
+    outgoing: mpsc::UnboundedSender<(String, Vec<u8>)>,
+}
+
+impl<B: Broker> BrokerBus<B> {
+    pub fn new(broker: B, subject_prefix: String) -> Self {
+        let broker = Arc::new(broker);
+        let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<(String, Vec<u8>)>();
+        tokio::spawn({
+            let broker = Arc::clone(&broker);
+            async move {
+                while let Some((subject, payload)) = outgoing_rx.recv().await {
+                    if let Err(e) = broker.publish(subject, payload).await {
+                        error!("Failed to publish room event: {e:#}");
+                    }
+                }
+            }
+        });
+        Self {
+            broker,
+            subject_prefix,
+            rooms: Rooms::default(),
+            outgoing,
+        }
+    }
+
+    fn subject(&self, room_id: &RoomId) -> String {
+        format!("{}.{room_id}", self.subject_prefix)
+    }
+}
+
+impl<B: Broker> RoomBus for BrokerBus<B> {
+    fn publish(&self, room_id: &RoomId, event: BusEvent) {
+        match serde_json::to_vec(&event) {
+            Ok(payload) => {
+                let _ = self.outgoing.send((self.subject(room_id), payload));
+            }
+            Err(e) => error!("Failed to serialize room event {event:?}: {e}"),
+        }
+    }
+
+    fn subscribe(&self, room_id: &RoomId) -> broadcast::Receiver<BusEvent> {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(tx) = rooms.get(room_id) {
+            return tx.subscribe();
+        }
+        let (tx, rx) = broadcast::channel(ROOM_CHANNEL_CAPACITY);
+        rooms.insert(room_id.clone(), tx.clone());
+        tokio::spawn(forward_room(
+            Arc::clone(&self.broker),
+            self.subject(room_id),
+            room_id.clone(),
+            tx,
+            Arc::clone(&self.rooms),
+        ));
+        rx
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
+
+/// Hand the events of a room from the broker to its local subscribers, until there are none
+async fn forward_room<B: Broker>(
+    broker: Arc<B>,
+    subject: String,
+    room_id: RoomId,
+    tx: broadcast::Sender<BusEvent>,
+    rooms: Rooms,
+) {
+    match broker.subscribe(subject).await {
+        Ok(mut payloads) => {
+            let mut idle_check = interval(IDLE_CHECK_INTERVAL);
+            loop {
+                select! {
+                    payload = payloads.next() => {
+                        let Some(payload) = payload else {
+                            warn!(%room_id, "Broker ended the room subscription.");
+                            break;
+                        };
+                        match serde_json::from_slice(&payload) {
+                            Ok(event) => {
+                                if tx.send(event).is_err() {
+                                    break;
+                                }
+                            }
+                            Err(e) => warn!(%room_id, "Dropping malformed room event: {e}"),
+                        }
+                    }
+                    _ = idle_check.tick() => {
+                        if tx.receiver_count() == 0 {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Err(e) => error!(%room_id, "Failed to subscribe to room events: {e:#}"),
+    }
+
+    let mut rooms = rooms.lock().unwrap();
+    // A new subscription may have replaced this one in the meantime
+    if rooms
+        .get(&room_id)
+        .is_some_and(|current| current.same_channel(&tx))
+    {
+        rooms.remove(&room_id);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use inpixly_shared::ServerMessage;
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+
+    type Subscribers = HashMap<String, Vec<mpsc::UnboundedSender<Vec<u8>>>>;
+
+    /// Broker delivering within the process, shared by several buses like NATS by several instances
+    #[derive(Clone, Default)]
+    pub(crate) struct FakeBroker {
+        subscribers: Arc<Mutex<Subscribers>>,
+    }
+
+    #[async_trait]
+    impl Broker for FakeBroker {
+        async fn publish(&self, subject: String, payload: Vec<u8>) -> anyhow::Result<()> {
+            if let Some(subscribers) = self.subscribers.lock().unwrap().get_mut(&subject) {
+                subscribers.retain(|tx| tx.send(payload.clone()).is_ok());
+            }
+            Ok(())
+        }
+
+        async fn subscribe(&self, subject: String) -> anyhow::Result<BoxStream<'static, Vec<u8>>> {
+            let (tx, rx) = mpsc::unbounded_channel();
+            self.subscribers
+                .lock()
+                .unwrap()
+                .entry(subject)
+                .or_default()
+                .push(tx);
+            Ok(receiver_stream(rx))
+        }
+    }
+
+    fn receiver_stream(mut rx: mpsc::UnboundedReceiver<Vec<u8>>) -> BoxStream<'static, Vec<u8>> {
+        futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx)).boxed()
+    }
+
+    fn room_id() -> RoomId {
+        "550e8400-e29b-41d4-a716-446655440000".parse().unwrap()
+    }
+
+    fn chat(message: &str) -> BusEvent {
+        BusEvent::Broadcast {
+            message: ServerMessage::Chat {
+                from: "alice".parse().unwrap(),
+                message: message.to_string(),
+            },
+        }
+    }
+
+    async fn next_chat(rx: &mut broadcast::Receiver<BusEvent>) -> String {
+        match timeout(Duration::from_secs(5), rx.recv()).await {
+            Ok(Ok(BusEvent::Broadcast {
+                message: ServerMessage::Chat { message, .. },
+            })) => message,
+            other => panic!("expected a chat message, got {other:?}"),
+        }
+    }
+
+    /// Broker subscriptions are set up in the background
+    async fn settle() {
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn relays_events_between_instances() {
+        let broker = FakeBroker::default();
+        let first = BrokerBus::new(broker.clone(), "inpixly.rooms".to_string());
+        let second = BrokerBus::new(broker.clone(), "inpixly.rooms".to_string());
+        let mut first_rx = first.subscribe(&room_id());
+        let mut second_rx = second.subscribe(&room_id());
+        settle().await;
+
+        first.publish(&room_id(), chat("hello"));
+        second.publish(&room_id(), chat("hi"));
+        assert_eq!(next_chat(&mut first_rx).await, "hello");
+        assert_eq!(next_chat(&mut first_rx).await, "hi");
+        assert_eq!(next_chat(&mut second_rx).await, "hello");
+        assert_eq!(next_chat(&mut second_rx).await, "hi");
+    }
+
+    #[tokio::test]
+    async fn keeps_rooms_apart() {
+        let broker = FakeBroker::default();
+        let bus = BrokerBus::new(broker, "inpixly.rooms".to_string());
+        let other_room: RoomId = "6ba7b810-9dad-11d1-80b4-00c04fd430c8".parse().unwrap();
+        let mut rx = bus.subscribe(&room_id());
+        let _other_rx = bus.subscribe(&other_room);
+        settle().await;
+
+        bus.publish(&other_room, chat("elsewhere"));
+        bus.publish(&room_id(), chat("here"));
+        assert_eq!(next_chat(&mut rx).await, "here");
+    }
+
+    #[tokio::test]
+    async fn drops_subscription_without_listeners() {
+        let broker = FakeBroker::default();
+        let bus = BrokerBus::new(broker, "inpixly.rooms".to_string());
+        drop(bus.subscribe(&room_id()));
+        settle().await;
+
+        bus.publish(&room_id(), chat("nobody listens"));
+        settle().await;
+        assert!(bus.rooms.lock().unwrap().is_empty());
+    }
+}
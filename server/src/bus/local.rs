@@ -0,0 +1,37 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use inpixly_shared::RoomId;
+use tokio::sync::broadcast;
+
+use super::{BusEvent, ROOM_CHANNEL_CAPACITY, RoomBus};
+
+/// Delivers events within this process, all members of a room are connected to it
+#[derive(Default)]
+pub struct LocalBus {
+    rooms: Mutex<HashMap<RoomId, broadcast::Sender<BusEvent>>>,
+}
+
+impl RoomBus for LocalBus {
+    fn publish(&self, room_id: &RoomId, event: BusEvent) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(tx) = rooms.get(room_id)
+            && tx.send(event).is_err()
+        {
+            // Nobody listens anymore
+            rooms.remove(room_id);
+        }
+    }
+
+    fn subscribe(&self, room_id: &RoomId) -> broadcast::Receiver<BusEvent> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_insert_with(|| broadcast::channel(ROOM_CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    fn is_shared(&self) -> bool {
+        false
+    }
+}
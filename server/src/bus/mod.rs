@@ -0,0 +1,71 @@
+#[cfg(any(feature = "nats", test))]
+mod broker;
+mod local;
+#[cfg(feature = "nats")]
+mod nats;
+
+use std::sync::Arc;
+
+use inpixly_shared::{RoomId, ServerMessage, Username};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::config::BusConfig;
+
+#[cfg(all(test, not(feature = "nats")))]
+pub(crate) use broker::BrokerBus;
+#[cfg(test)]
+pub(crate) use broker::tests::FakeBroker;
+#[cfg(feature = "nats")]
+pub use broker::{Broker, BrokerBus};
+pub use local::LocalBus;
+
+/// Room event as it travels between server instances
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BusEvent {
+    /// For every member of the room
+    Broadcast { message: ServerMessage },
+    /// For a single member, delivered by whichever instance it is connected to
+    Direct {
+        to: Username,
+        message: ServerMessage,
+    },
+}
+
+/// Carries the events of a room to its members on every server instance.
+///
+/// Only events travel, rooms and members are shared through the [`RoomStore`](crate::storage::RoomStore),
+/// so instances sharing a bus must share a store too. Chat history stays with each instance.
+pub trait RoomBus: Send + Sync {
+    /// Deliver an event to the subscribers of a room on every instance, this one included
+    fn publish(&self, room_id: &RoomId, event: BusEvent);
+
+    /// Events published to a room from now on
+    fn subscribe(&self, room_id: &RoomId) -> broadcast::Receiver<BusEvent>;
+
+    /// Whether members of a room may be connected to other instances
+    fn is_shared(&self) -> bool;
+}
+
+/// Capacity of the per room channel every local subscriber reads from
+pub(crate) const ROOM_CHANNEL_CAPACITY: usize = 256;
+
+/// Open the event bus selected in the config
+pub async fn open(config: &BusConfig) -> anyhow::Result<Arc<dyn RoomBus>> {
+    Ok(match config {
+        BusConfig::Local => Arc::new(LocalBus::default()),
+        #[cfg(feature = "nats")]
+        BusConfig::Nats {
+            url,
+            subject_prefix,
+        } => {
+            let broker = nats::NatsBroker::connect(url).await?;
+            Arc::new(BrokerBus::new(broker, subject_prefix.clone()))
+        }
+        #[cfg(not(feature = "nats"))]
+        BusConfig::Nats { .. } => {
+            anyhow::bail!("bus.backend = \"nats\" needs the nats feature")
+        }
+    })
+}
@@ -0,0 +1,34 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use futures_util::{StreamExt, stream::BoxStream};
+use tracing::info;
+
+use super::Broker;
+
+/// Core NATS publish/subscribe, without JetStream persistence
+pub struct NatsBroker {
+    client: async_nats::Client,
+}
+
+impl NatsBroker {
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let client = async_nats::connect(url)
+            .await
+            .with_context(|| format!("failed to connect to NATS at {url}"))?;
+        info!("Connected to NATS at {url}");
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl Broker for NatsBroker {
+    async fn publish(&self, subject: String, payload: Vec<u8>) -> anyhow::Result<()> {
+        self.client.publish(subject, payload.into()).await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, subject: String) -> anyhow::Result<BoxStream<'static, Vec<u8>>> {
+        let subscriber = self.client.subscribe(subject).await?;
+        Ok(subscriber.map(|message| message.payload.to_vec()).boxed())
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+
+use inpixly_shared::{ChatEntry, Username};
+
+/// Bounded log of the most recent chat messages of a room
+pub struct ChatLog {
+    entries: VecDeque<ChatEntry>,
+    capacity: usize,
+    next_id: u64,
+}
+
+impl ChatLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            next_id: 0,
+        }
+    }
+
+    /// Append a message, evicting the oldest one when the log is full
+    pub fn push(&mut self, from: Username, message: String) -> ChatEntry {
+        let entry = ChatEntry {
+            id: self.next_id,
+            from,
+            message,
+        };
+        self.next_id += 1;
+        if self.capacity == 0 {
+            return entry;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry.clone());
+        entry
+    }
+
+    /// Up to `limit` messages older than `before` (or the newest ones), oldest first.
+    /// The flag tells whether even older messages are still retained.
+    pub fn page(&self, before: Option<u64>, limit: usize) -> (Vec<ChatEntry>, bool) {
+        let end = match before {
+            Some(before) => self.entries.partition_point(|e| e.id < before),
+            None => self.entries.len(),
+        };
+        let start = end.saturating_sub(limit);
+        let page = self.entries.range(start..end).cloned().collect();
+        (page, start > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_with(capacity: usize, count: usize) -> ChatLog {
+        let mut log = ChatLog::new(capacity);
+        for i in 0..count {
+            log.push("alice".parse().unwrap(), format!("message {i}"));
+        }
+        log
+    }
+
+    fn ids(page: &[ChatEntry]) -> Vec<u64> {
+        page.iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn evicts_oldest_when_full() {
+        let log = log_with(3, 5);
+        let (page, has_more) = log.page(None, 10);
+        assert_eq!(ids(&page), [2, 3, 4]);
+        assert!(!has_more);
+    }
+
+    #[test]
+    fn pages_back_through_history() {
+        let log = log_with(10, 5);
+        let (page, has_more) = log.page(None, 2);
+        assert_eq!(ids(&page), [3, 4]);
+        assert!(has_more);
+
+        let (page, has_more) = log.page(Some(3), 2);
+        assert_eq!(ids(&page), [1, 2]);
+        assert!(has_more);
+
+        let (page, has_more) = log.page(Some(1), 2);
+        assert_eq!(ids(&page), [0]);
+        assert!(!has_more);
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let log = log_with(0, 3);
+        assert_eq!(log.page(None, 10), (vec![], false));
+    }
+}
@@ -10,12 +10,13 @@ const ROOM_INACTIVE_DAYS: i64 = 30;
 
 const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
 
-/// Spawns a background task that periodically removes inactive rooms
+/// Spawns a background task that periodically removes inactive rooms and expired rate limits
 pub fn spawn_cleanup_task(state: AppState) {
     tokio::spawn(async move {
         loop {
             tokio::time::sleep(CLEANUP_INTERVAL).await;
             cleanup_inactive_rooms(&state).await;
+            state.rate_limits.prune();
         }
     });
 }
@@ -24,6 +25,19 @@ async fn cleanup_inactive_rooms(state: &AppState) {
     let now = Utc::now();
     let threshold = chrono::Duration::days(ROOM_INACTIVE_DAYS);
 
+    // Another instance sharing the store may have seen activity in the room since
+    let inactive: Vec<_> = state
+        .rooms
+        .read()
+        .await
+        .iter()
+        .filter(|(_, room)| now.signed_duration_since(room.last_activity) > threshold)
+        .map(|(room_id, _)| room_id.clone())
+        .collect();
+    for room_id in &inactive {
+        state.sync_room(room_id).await;
+    }
+
     let mut rooms = state.rooms.write().await;
     let initial_count = rooms.len();
 
@@ -35,6 +49,8 @@ async fn cleanup_inactive_rooms(state: &AppState) {
                 room_id,
                 age.num_days()
             );
+            state.delete_room(room_id);
+            state.metrics.cleanup_removals.inc();
             false
         } else {
             true
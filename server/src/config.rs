@@ -1,16 +1,459 @@
 use anyhow::{Context, anyhow};
+use inpixly_shared::IceServer;
+use ipnet::IpNet;
 use serde::Deserialize;
-use std::{net::SocketAddr, path::Path, rc::Rc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
+    #[serde(default)]
+    pub sfu: SfuConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub bus: BusConfig,
+    #[serde(default)]
+    pub chat: ChatConfig,
+    #[serde(default)]
+    pub password_hash: PasswordHashConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default)]
+    pub protocol: ProtocolConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub ice: IceConfig,
+    /// Runs a STUN/TURN server in this process when set
+    #[serde(default)]
+    pub turn_server: Option<TurnServerConfig>,
+    #[serde(default)]
+    pub frontend: FrontendConfig,
+    /// Serves Prometheus metrics at `/metrics` when set
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    /// Enables the operator API under `/api/admin` when set
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ServerConfig {
     #[serde(default = "default_bind")]
     pub bind: SocketAddr,
+    /// Serves HTTPS and WSS on `bind` instead of plain HTTP when set
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain, reloaded when it changes or on SIGHUP
+    pub cert_path: PathBuf,
+    /// PEM private key
+    pub key_path: PathBuf,
+    /// Also listen here with plain HTTP, redirecting every request to HTTPS
+    #[serde(default)]
+    pub redirect_http_bind: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SfuConfig {
+    /// Public IPs advertised in ICE candidates when the server sits behind a 1:1 NAT
+    #[serde(default)]
+    pub public_ips: Vec<String>,
+    /// Also gather loopback candidates, only useful for local testing
+    #[serde(default)]
+    pub include_loopback: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatConfig {
+    /// Number of most recent messages each room retains
+    #[serde(default = "default_chat_history_size")]
+    pub history_size: usize,
+    /// Number of messages sent on join and per page of older messages
+    #[serde(default = "default_chat_page_size")]
+    pub page_size: usize,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            history_size: default_chat_history_size(),
+            page_size: default_chat_page_size(),
+        }
+    }
+}
+
+fn default_chat_history_size() -> usize {
+    500
+}
+
+fn default_chat_page_size() -> usize {
+    50
+}
+
+/// Argon2id cost of room password hashes
+#[derive(Debug, Clone, Deserialize)]
+pub struct PasswordHashConfig {
+    #[serde(default = "default_password_memory_kib")]
+    pub memory_kib: u32,
+    #[serde(default = "default_password_iterations")]
+    pub iterations: u32,
+    #[serde(default = "default_password_parallelism")]
+    pub parallelism: u32,
+}
+
+impl PasswordHashConfig {
+    pub fn params(&self) -> anyhow::Result<argon2::Params> {
+        argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| anyhow!("invalid password hash cost: {e}"))
+    }
+}
+
+impl Default for PasswordHashConfig {
+    fn default() -> Self {
+        Self {
+            memory_kib: default_password_memory_kib(),
+            iterations: default_password_iterations(),
+            parallelism: default_password_parallelism(),
+        }
+    }
+}
+
+fn default_password_memory_kib() -> u32 {
+    argon2::Params::DEFAULT_M_COST
+}
+
+fn default_password_iterations() -> u32 {
+    argon2::Params::DEFAULT_T_COST
+}
+
+fn default_password_parallelism() -> u32 {
+    argon2::Params::DEFAULT_P_COST
+}
+
+/// WebSocket pings used to detect connections that vanished without closing
+#[derive(Debug, Clone, Deserialize)]
+pub struct HeartbeatConfig {
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub interval_secs: u64,
+    /// Unanswered pings after which the connection is closed
+    #[serde(default = "default_heartbeat_max_missed_pongs")]
+    pub max_missed_pongs: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_heartbeat_interval_secs(),
+            max_missed_pongs: default_heartbeat_max_missed_pongs(),
+        }
+    }
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    15
+}
+
+fn default_heartbeat_max_missed_pongs() -> u32 {
+    2
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProtocolConfig {
+    /// Offer MessagePack in binary frames to clients that support it, JSON is used otherwise
+    #[serde(default)]
+    pub binary_framing: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionConfig {
+    /// How long a dropped member stays online, so it can reconnect without the room noticing
+    #[serde(default = "default_reconnect_grace_secs")]
+    pub reconnect_grace_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            reconnect_grace_secs: default_reconnect_grace_secs(),
+        }
+    }
+}
+
+fn default_reconnect_grace_secs() -> u64 {
+    10
+}
+
+/// What happens on SIGINT or SIGTERM, after clients were told the server goes down
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShutdownConfig {
+    /// Longest wait for clients to disconnect before exiting
+    #[serde(default = "default_drain_secs")]
+    pub drain_secs: u64,
+    /// Passed on to clients as when to reconnect, e.g. how long a restart usually takes
+    #[serde(default)]
+    pub restart_hint_secs: Option<u64>,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_secs: default_drain_secs(),
+            restart_hint_secs: None,
+        }
+    }
+}
+
+fn default_drain_secs() -> u64 {
+    10
+}
+
+/// ICE servers handed to clients for their peer connections
+#[derive(Debug, Clone, Deserialize)]
+pub struct IceConfig {
+    #[serde(default = "default_ice_servers")]
+    pub servers: Vec<IceServer>,
+    /// TURN server sharing a secret with us, members get their own short-lived credentials
+    #[serde(default)]
+    pub turn: Option<TurnRestConfig>,
+}
+
+impl Default for IceConfig {
+    fn default() -> Self {
+        Self {
+            servers: default_ice_servers(),
+            turn: None,
+        }
+    }
+}
+
+/// A TURN server using the shared secret scheme, e.g. coturn with `use-auth-secret`
+#[derive(Clone, Deserialize)]
+pub struct TurnRestConfig {
+    pub urls: Vec<String>,
+    /// Same as coturn's `static-auth-secret`
+    pub secret: String,
+    /// How long minted credentials stay valid
+    #[serde(default = "default_turn_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+impl std::fmt::Debug for TurnRestConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TurnRestConfig")
+            .field("urls", &self.urls)
+            .field("secret", &"<redacted>")
+            .field("ttl_secs", &self.ttl_secs)
+            .finish()
+    }
+}
+
+fn default_turn_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+/// The embedded STUN/TURN server, relaying over UDP
+#[derive(Debug, Clone, Deserialize)]
+pub struct TurnServerConfig {
+    #[serde(default = "default_turn_server_bind")]
+    pub bind: SocketAddr,
+    /// Address advertised to clients and used for relays, required when `bind` is unspecified
+    #[serde(default)]
+    pub public_ip: Option<IpAddr>,
+    #[serde(default = "default_turn_server_realm")]
+    pub realm: String,
+    #[serde(default = "default_relay_port_min")]
+    pub relay_port_min: u16,
+    #[serde(default = "default_relay_port_max")]
+    pub relay_port_max: u16,
+    /// Members' credentials expire after this, relays opened with them cannot be refreshed past it
+    #[serde(default = "default_turn_ttl_secs")]
+    pub credential_ttl_secs: u64,
+    /// Relays open at the same time, across all clients
+    #[serde(default = "default_max_allocations")]
+    pub max_allocations: usize,
+    /// Networks relays may send to although they are internal. Loopback, private, link-local
+    /// and unspecified addresses are refused otherwise, which covers cloud metadata endpoints.
+    #[serde(default)]
+    pub allowed_peers: Vec<IpNet>,
+}
+
+fn default_turn_server_bind() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 3478))
+}
+
+fn default_turn_server_realm() -> String {
+    "inpixly".to_string()
+}
+
+fn default_relay_port_min() -> u16 {
+    49152
+}
+
+fn default_relay_port_max() -> u16 {
+    65535
+}
+
+fn default_max_allocations() -> usize {
+    1000
+}
+
+/// Where `/metrics` is served and who may scrape it, at least one of the two must be set
+#[derive(Clone, Deserialize)]
+pub struct MetricsConfig {
+    /// Serve metrics on their own listener instead of the main one
+    #[serde(default)]
+    pub bind: Option<SocketAddr>,
+    /// Require `Authorization: Bearer <token>` to scrape
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl std::fmt::Debug for MetricsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetricsConfig")
+            .field("bind", &self.bind)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct AdminConfig {
+    /// Sent by operators as `Authorization: Bearer <token>`
+    pub token: String,
+}
+
+impl std::fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminConfig")
+            .field("token", &"<redacted>")
+            .finish()
+    }
+}
+
+fn default_ice_servers() -> Vec<IceServer> {
+    vec![IceServer {
+        urls: vec![
+            "stun:stun.l.google.com:19302".to_string(),
+            "stun:stun1.l.google.com:19302".to_string(),
+        ],
+        username: None,
+        credential: None,
+    }]
+}
+
+/// Limits on joins and room creation, every unset limit keeps its default
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub join_per_ip: LimitConfig,
+    pub join_per_room: LimitConfig,
+    pub failed_password_per_ip: LimitConfig,
+    /// Guesses from all addresses together. Only refuses new members, rejoins with a token
+    /// need no password, so it is set higher than the other password limits.
+    pub failed_password_per_room: LimitConfig,
+    /// One address guessing a single room is stopped sooner
+    pub failed_password_per_room_and_ip: LimitConfig,
+    pub create_room_per_ip: LimitConfig,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            join_per_ip: LimitConfig::new(20, 60, 60),
+            join_per_room: LimitConfig::new(100, 60, 60),
+            failed_password_per_ip: LimitConfig::new(5, 300, 300),
+            failed_password_per_room: LimitConfig::new(20, 300, 300),
+            failed_password_per_room_and_ip: LimitConfig::new(3, 300, 300),
+            create_room_per_ip: LimitConfig::new(10, 600, 600),
+        }
+    }
+}
+
+/// At most `max_attempts` within `window_secs`, then refuse everything for `lockout_secs`
+#[derive(Debug, Clone, Deserialize)]
+pub struct LimitConfig {
+    pub max_attempts: u32,
+    pub window_secs: u64,
+    pub lockout_secs: u64,
+}
+
+impl LimitConfig {
+    const fn new(max_attempts: u32, window_secs: u64, lockout_secs: u64) -> Self {
+        Self {
+            max_attempts,
+            window_secs,
+            lockout_secs,
+        }
+    }
+}
+
+/// Where rooms are kept between restarts
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum StorageConfig {
+    /// Rooms are lost when the server stops
+    #[default]
+    Memory,
+    Sqlite {
+        path: PathBuf,
+    },
+}
+
+/// How room events reach members connected to other instances of the server
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum BusConfig {
+    /// Every member of a room is connected to this instance
+    #[default]
+    Local,
+    /// Instances exchange room events through a NATS server
+    #[cfg_attr(not(feature = "nats"), allow(dead_code))]
+    Nats {
+        url: String,
+        /// Events of a room are published on `<subject_prefix>.<room id>`
+        #[serde(default = "default_nats_subject_prefix")]
+        subject_prefix: String,
+    },
+}
+
+fn default_nats_subject_prefix() -> String {
+    "inpixly.rooms".to_string()
+}
+
+/// Where the built frontend is served from, next to the API
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum FrontendConfig {
+    /// Only the API is served, the frontend is hosted elsewhere
+    #[default]
+    None,
+    /// A directory holding the output of `dx bundle`
+    Dir { path: PathBuf },
+    /// Assets baked into the binary by the `embed-frontend` feature
+    Embedded,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: default_bind(),
+            tls: None,
+        }
+    }
 }
 
 fn default_bind() -> SocketAddr {
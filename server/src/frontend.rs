@@ -0,0 +1,270 @@
+use std::{convert::Infallible, path::Path};
+
+use anyhow::ensure;
+use axum::{
+    Router,
+    body::Body,
+    extract::Request,
+    http::{HeaderValue, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+};
+use tower::{ServiceExt, service_fn};
+use tower_http::services::{ServeDir, ServeFile};
+
+use crate::config::FrontendConfig;
+
+/// Router serving the built frontend, meant as the fallback of the API router.
+/// Paths of the client side router get `index.html`, missing files a 404.
+pub fn router(config: &FrontendConfig) -> anyhow::Result<Option<Router>> {
+    let router = match config {
+        FrontendConfig::None => return Ok(None),
+        FrontendConfig::Dir { path } => dir_router(path)?,
+        #[cfg(feature = "embed-frontend")]
+        FrontendConfig::Embedded => embedded::router()?,
+        #[cfg(not(feature = "embed-frontend"))]
+        FrontendConfig::Embedded => {
+            anyhow::bail!("frontend.source = \"embedded\" needs the embed-frontend feature")
+        }
+    };
+    Ok(Some(router.layer(middleware::from_fn(cache_control))))
+}
+
+fn dir_router(path: &Path) -> anyhow::Result<Router> {
+    let index = path.join("index.html");
+    ensure!(
+        index.is_file(),
+        "frontend directory {} has no index.html",
+        path.display()
+    );
+    let index = ServeFile::new(index)
+        .precompressed_br()
+        .precompressed_gzip();
+    let fallback = service_fn(move |request: Request| {
+        let index = index.clone();
+        async move {
+            if !is_client_route(request.uri().path()) {
+                return Ok::<_, Infallible>(StatusCode::NOT_FOUND.into_response());
+            }
+            let response = index.oneshot(request).await?;
+            Ok(response.map(Body::new))
+        }
+    });
+    let files = ServeDir::new(path)
+        .precompressed_br()
+        .precompressed_gzip()
+        .fallback(fallback);
+    Ok(Router::new().fallback_service(files))
+}
+
+/// Whether a path belongs to the client side router rather than to a file
+fn is_client_route(path: &str) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    !path.starts_with("/api/") && !file_name.contains('.')
+}
+
+/// Assets bundled by dx carry a content hash in their name and never change,
+/// everything else is revalidated on every load.
+async fn cache_control(request: Request, next: Next) -> Response {
+    let hashed = request.uri().path().contains("-dxh");
+    let mut response = next.run(request).await;
+    if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+        let value = if hashed {
+            "public, max-age=31536000, immutable"
+        } else {
+            "no-cache"
+        };
+        response
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, HeaderValue::from_static(value));
+    }
+    response
+}
+
+#[cfg(feature = "embed-frontend")]
+mod embedded {
+    use std::borrow::Cow;
+
+    use axum::{
+        Router,
+        http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header},
+        response::{IntoResponse, Response},
+    };
+    use rust_embed::{EmbeddedFile, RustEmbed};
+
+    use super::is_client_route;
+
+    #[derive(RustEmbed)]
+    #[folder = "../frontend/dist"]
+    struct Assets;
+
+    pub fn router() -> anyhow::Result<Router> {
+        anyhow::ensure!(
+            Assets::get("index.html").is_some(),
+            "the embedded frontend has no index.html, build it into frontend/dist first"
+        );
+        Ok(Router::new().fallback(serve))
+    }
+
+    async fn serve(method: Method, uri: Uri, headers: HeaderMap) -> Response {
+        if method != Method::GET && method != Method::HEAD {
+            return StatusCode::METHOD_NOT_ALLOWED.into_response();
+        }
+        let mut path = uri.path().trim_start_matches('/').to_string();
+        if path.is_empty() || path.ends_with('/') {
+            path.push_str("index.html");
+        }
+        let (path, file) = match Assets::get(&path) {
+            Some(file) => (path, file),
+            None if is_client_route(uri.path()) => {
+                let index = Assets::get("index.html").expect("checked on startup");
+                ("index.html".to_string(), index)
+            }
+            None => return StatusCode::NOT_FOUND.into_response(),
+        };
+
+        let etag = etag(&file);
+        if headers
+            .get(header::IF_NONE_MATCH)
+            .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+        {
+            return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+        }
+
+        let content_type = file.metadata.mimetype().to_string();
+        let (encoding, data) = precompressed(&path, &headers)
+            .map(|(encoding, file)| (Some(encoding), file.data))
+            .unwrap_or((None, file.data));
+        let mut response = (
+            [
+                (header::CONTENT_TYPE, content_type),
+                (header::ETAG, etag),
+                (header::VARY, "accept-encoding".to_string()),
+            ],
+            match data {
+                Cow::Borrowed(bytes) => axum::body::Body::from(bytes),
+                Cow::Owned(bytes) => axum::body::Body::from(bytes),
+            },
+        )
+            .into_response();
+        if let Some(encoding) = encoding {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        response
+    }
+
+    /// A `.br` or `.gz` sibling of the file, if the client accepts it
+    fn precompressed(path: &str, headers: &HeaderMap) -> Option<(&'static str, EmbeddedFile)> {
+        let accepted = headers
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        [("br", "br"), ("gzip", "gz")]
+            .into_iter()
+            .filter(|(encoding, _)| accepts(accepted, encoding))
+            .find_map(|(encoding, extension)| {
+                Assets::get(&format!("{path}.{extension}")).map(|file| (encoding, file))
+            })
+    }
+
+    fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+        accept_encoding.split(',').any(|entry| {
+            let mut params = entry.split(';').map(str::trim);
+            params.next() == Some(encoding) && !params.any(|param| param == "q=0")
+        })
+    }
+
+    fn etag(file: &EmbeddedFile) -> String {
+        let hash: String = file.metadata.sha256_hash()[..16]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        format!("\"{hash}\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use axum::http::Request;
+    use uuid::Uuid;
+
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn bundle() -> (TempDir, Router) {
+        let dir = std::env::temp_dir().join(format!("inpixly-frontend-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("index.html"), "<html>index</html>").unwrap();
+        fs::write(dir.join("assets/main-dxh1234.css"), "body {}").unwrap();
+        fs::write(dir.join("assets/main-dxh1234.css.br"), "brotli").unwrap();
+        let router = router(&FrontendConfig::Dir { path: dir.clone() })
+            .unwrap()
+            .unwrap();
+        (TempDir(dir), router)
+    }
+
+    async fn get(router: &Router, path: &str, accept_encoding: Option<&str>) -> Response {
+        let mut request = Request::get(path);
+        if let Some(encoding) = accept_encoding {
+            request = request.header(header::ACCEPT_ENCODING, encoding);
+        }
+        router
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn text(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_index_for_client_routes() {
+        let (_dir, router) = bundle();
+        let response = get(&router, "/room/abc-def", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+        assert_eq!(text(response).await, "<html>index</html>");
+
+        let response = get(&router, "/assets/missing-dxh1.js", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get(&router, "/api/unknown", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn caches_hashed_assets_forever() {
+        let (_dir, router) = bundle();
+        let response = get(&router, "/assets/main-dxh1234.css", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(text(response).await, "body {}");
+    }
+
+    #[tokio::test]
+    async fn serves_precompressed_assets() {
+        let (_dir, router) = bundle();
+        let response = get(&router, "/assets/main-dxh1234.css", Some("gzip, br")).await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/css");
+        assert_eq!(text(response).await, "brotli");
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use hmac::{Hmac, Mac};
+use inpixly_shared::IceServer;
+use sha1::{Digest, Sha1};
+
+use crate::config::{IceConfig, TurnRestConfig};
+
+/// ICE servers for a member, with freshly minted credentials for the TURN server if one is configured
+pub fn ice_servers_for(config: &IceConfig, member_token: &str) -> Vec<IceServer> {
+    let mut servers = config.servers.clone();
+    if let Some(turn) = &config.turn {
+        servers.push(turn_server(turn, member_token, SystemTime::now()));
+    }
+    servers
+}
+
+fn turn_server(config: &TurnRestConfig, member_token: &str, now: SystemTime) -> IceServer {
+    let expires_at = now + Duration::from_secs(config.ttl_secs);
+    let (username, credential) = turn_credentials(&config.secret, member_token, expires_at);
+    IceServer {
+        urls: config.urls.clone(),
+        username: Some(username),
+        credential: Some(credential),
+    }
+}
+
+/// Credentials in the TURN REST format understood by coturn's `use-auth-secret`:
+/// the username is `<expiry unix timestamp>:<user id>`, the password its base64 HMAC-SHA1.
+///
+/// The user id is derived from the member token, which itself must stay secret.
+pub fn turn_credentials(
+    secret: &str,
+    member_token: &str,
+    expires_at: SystemTime,
+) -> (String, String) {
+    let expiry = expires_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let username = format!("{expiry}:{}", member_tag(member_token));
+    let credential = turn_password(secret, &username);
+    (username, credential)
+}
+
+/// The password a TURN server sharing `secret` expects for `username`
+pub fn turn_password(secret: &str, username: &str) -> String {
+    sign(secret.as_bytes(), username.as_bytes())
+}
+
+/// Short stable identifier of a member that does not reveal its token
+fn member_tag(member_token: &str) -> String {
+    Sha1::digest(member_token.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn sign(key: &[u8], message: &[u8]) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "2f1c6a52-3d5e-4b7a-9c1e-8d0f4a6b2c3e";
+
+    #[test]
+    fn signs_rfc_2202_vector() {
+        // RFC 2202 test case 2, digest effcdf6ae5eb2fa2d27416d5f184df9c259a7c79
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "7/zfauXrL6LSdBbV8YTfnCWafHk="
+        );
+    }
+
+    #[test]
+    fn mints_coturn_credentials() {
+        let expires_at = UNIX_EPOCH + Duration::from_secs(1_700_086_400);
+        let (username, credential) = turn_credentials("inpixly-turn-secret", TOKEN, expires_at);
+        assert_eq!(username, "1700086400:d367a9f4dfc04719");
+        assert_eq!(credential, "R3Wmon9UKeTAhvNBy9tsm28vj4w=");
+    }
+
+    #[test]
+    fn credentials_expire_after_ttl() {
+        let config = TurnRestConfig {
+            urls: vec!["turn:turn.example.com:3478".to_string()],
+            secret: "inpixly-turn-secret".to_string(),
+            ttl_secs: 86_400,
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let server = turn_server(&config, TOKEN, now);
+        assert_eq!(server.urls, config.urls);
+        assert_eq!(
+            server.username.as_deref(),
+            Some("1700086400:d367a9f4dfc04719")
+        );
+        assert_eq!(
+            server.credential.as_deref(),
+            Some("R3Wmon9UKeTAhvNBy9tsm28vj4w=")
+        );
+    }
+}
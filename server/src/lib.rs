@@ -0,0 +1,20 @@
+pub mod admin;
+pub mod api;
+pub mod auth;
+pub mod bus;
+pub mod chat;
+pub mod cleanup;
+pub mod config;
+pub mod frontend;
+pub mod ice;
+pub mod metrics;
+pub mod password;
+pub mod rate_limit;
+pub mod room;
+pub mod sfu;
+pub mod shutdown;
+pub mod state;
+pub mod storage;
+pub mod tls;
+pub mod turn_server;
+pub mod ws;
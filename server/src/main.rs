@@ -1,20 +1,11 @@
-mod cleanup;
-mod config;
-mod room;
-mod state;
-mod ws;
-
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use anyhow::Context;
-use axum::{
-    Json, Router,
-    extract::{Path, State},
-    http::{HeaderMap, Method, StatusCode},
-    routing::{get, post},
+use axum::http::Method;
+use inpixly_server::{
+    admin, api, bus, cleanup, config::Config, frontend, metrics, shutdown, state::AppState,
+    storage, tls, turn_server::TurnServer,
 };
-use config::Config;
-use state::AppState;
 use tower_http::{
     cors::CorsLayer,
     trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
@@ -23,84 +14,6 @@ use tracing::Level;
 use tracing::info;
 use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
-use inpixly_shared::{CreateRoomRequest, CreateRoomResponse, ErrorKind, RoomId, RoomInfoResponse};
-
-use crate::room::Room;
-
-/// POST /api/rooms - Create a new room
-async fn create_room(
-    State(state): State<AppState>,
-    Json(request): Json<CreateRoomRequest>,
-) -> Result<Json<CreateRoomResponse>, (StatusCode, Json<ErrorKind>)> {
-    let mut room = Room::new(request.password);
-    let room_id = room.id.clone();
-    let owner_token = room.owner_token.clone();
-
-    // Add creator as first member
-    let (username, member_token) = room
-        .add_member(request.username, false)
-        .map_err(|e| (StatusCode::BAD_REQUEST, Json(e)))?;
-
-    let mut rooms = state.rooms.write().await;
-    rooms.insert(room_id.clone(), room);
-
-    info!("Created new room: {} by {}", room_id, username);
-
-    Ok(Json(CreateRoomResponse {
-        room_id,
-        owner_token,
-        member_token,
-        username,
-    }))
-}
-
-/// GET /api/rooms/:id - Check if room exists
-async fn get_room(
-    Path(room_id): Path<RoomId>,
-    State(state): State<AppState>,
-) -> Json<RoomInfoResponse> {
-    let rooms = state.rooms.read().await;
-    match rooms.get(&room_id) {
-        Some(room) => Json(RoomInfoResponse {
-            exists: true,
-            has_password: room.has_password(),
-        }),
-        None => Json(RoomInfoResponse {
-            exists: false,
-            has_password: false,
-        }),
-    }
-}
-
-/// DELETE /api/rooms/:id - Delete a room (requires owner_token)
-async fn delete_room(
-    Path(room_id): Path<RoomId>,
-    State(state): State<AppState>,
-    headers: HeaderMap,
-) -> StatusCode {
-    let owner_token = match headers.get("X-Owner-Token") {
-        Some(token) => match token.to_str() {
-            Ok(t) => t.to_string(),
-            Err(_) => return StatusCode::BAD_REQUEST,
-        },
-        None => return StatusCode::UNAUTHORIZED,
-    };
-
-    let mut rooms = state.rooms.write().await;
-
-    if let Some(room) = rooms.get(&room_id) {
-        if room.is_owner(&owner_token) {
-            rooms.remove(&room_id);
-            info!("Deleted room: {}", room_id);
-            StatusCode::NO_CONTENT
-        } else {
-            StatusCode::FORBIDDEN
-        }
-    } else {
-        StatusCode::NOT_FOUND
-    }
-}
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::registry()
@@ -108,9 +21,24 @@ async fn main() -> anyhow::Result<()> {
         .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
         .init();
 
-    let config = Config::load("config.nix").await?;
+    let mut config = Config::load("config.nix").await?;
+
+    let turn_server = match &config.turn_server {
+        Some(turn_config) => {
+            anyhow::ensure!(
+                config.ice.turn.is_none(),
+                "ice.turn and turn_server cannot both be set"
+            );
+            let turn_server = TurnServer::start(turn_config).await?;
+            config.ice.turn = Some(turn_server.rest_config());
+            Some(turn_server)
+        }
+        None => None,
+    };
 
-    let state = AppState::new();
+    let store = storage::open(&config.storage, &config.password_hash.params()?)?;
+    let bus = bus::open(&config.bus).await?;
+    let state = AppState::load(&config, store, bus)?;
 
     // Spawn cleanup task
     cleanup::spawn_cleanup_task(state.clone());
@@ -121,28 +49,84 @@ async fn main() -> anyhow::Result<()> {
         .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
         .allow_headers(tower_http::cors::Any);
 
-    let app = Router::new()
-        .route("/api/rooms", post(create_room))
-        .route("/api/rooms/{id}", get(get_room).delete(delete_room))
-        .route("/api/rooms/{id}/ws", get(ws::ws_handler))
-        .layer(cors)
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
-                .on_response(DefaultOnResponse::new().level(Level::INFO)),
-        )
-        .with_state(state);
-
-    let listener = tokio::net::TcpListener::bind(config.server.bind)
-        .await
-        .context("failed to bind to address")?;
-    info!("Server listening on http://{}", config.server.bind);
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .context("server error")?;
+    let mut app = api::router();
+    if let Some(metrics_config) = &config.metrics {
+        let metrics = metrics::router(metrics_config)?;
+        match metrics_config.bind {
+            Some(bind) => {
+                let metrics = metrics.with_state(state.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = metrics::serve(bind, metrics).await {
+                        tracing::error!("Metrics listener stopped: {e:#}");
+                    }
+                });
+            }
+            None => app = app.merge(metrics),
+        }
+    }
+    if let Some(admin_config) = &config.admin {
+        app = app.merge(admin::router(admin_config)?);
+    }
+    let app = app.with_state(state.clone());
+    let app = match frontend::router(&config.frontend)? {
+        Some(frontend) => app.fallback_service(frontend),
+        None => app,
+    };
+    let app = app.layer(cors).layer(
+        TraceLayer::new_for_http()
+            .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+            .on_response(DefaultOnResponse::new().level(Level::INFO)),
+    );
+
+    let shutdown_signal = shutdown::signal(state.clone(), config.shutdown.clone());
+    match &config.server.tls {
+        Some(tls_config) => {
+            let rustls = tls::load(tls_config).await?;
+            tls::spawn_reload_task(tls_config.clone(), rustls.clone());
+            if let Some(redirect_bind) = tls_config.redirect_http_bind {
+                let https_port = config.server.bind.port();
+                tokio::spawn(async move {
+                    if let Err(e) = tls::serve_redirect(redirect_bind, https_port).await {
+                        tracing::error!("HTTP redirect listener stopped: {e:#}");
+                    }
+                });
+            }
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                let drain = Duration::from_secs(config.shutdown.drain_secs);
+                async move {
+                    shutdown_signal.await;
+                    handle.graceful_shutdown(Some(drain));
+                }
+            });
+            info!("Server listening on https://{}", config.server.bind);
+            axum_server::bind_rustls(config.server.bind, rustls)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .context("server error")?;
+            // The handle already waited out the drain period
+            shutdown::persist(&state).await;
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(config.server.bind)
+                .await
+                .context("failed to bind to address")?;
+            info!("Server listening on http://{}", config.server.bind);
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown_signal)
+            .await
+            .context("server error")?;
+            shutdown::drain(&state, &config.shutdown).await;
+        }
+    }
 
+    if let Some(turn_server) = turn_server {
+        turn_server.close().await?;
+    }
     Ok(())
 }
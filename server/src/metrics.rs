@@ -0,0 +1,307 @@
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use axum::{
+    Router,
+    extract::State,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use inpixly_shared::{ErrorKind, JoinRequest, SignalingPayload};
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{counter::Counter, family::Family, gauge::Gauge},
+    registry::Registry,
+};
+use tracing::info;
+
+use crate::{auth, config::MetricsConfig, state::AppState};
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct KindLabel {
+    kind: &'static str,
+}
+
+/// Counters and gauges exported at `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    rooms: Gauge,
+    online_members: Gauge,
+    connections: Gauge,
+    pub rooms_created: Counter,
+    pub rooms_deleted: Counter,
+    joins: Family<KindLabel, Counter>,
+    errors: Family<KindLabel, Counter>,
+    pub chat_messages: Counter,
+    signaling_relayed: Family<KindLabel, Counter>,
+    pub broadcast_lags: Counter,
+    pub cleanup_removals: Counter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("inpixly"),
+            rooms: Gauge::default(),
+            online_members: Gauge::default(),
+            connections: Gauge::default(),
+            rooms_created: Counter::default(),
+            rooms_deleted: Counter::default(),
+            joins: Family::default(),
+            errors: Family::default(),
+            chat_messages: Counter::default(),
+            signaling_relayed: Family::default(),
+            broadcast_lags: Counter::default(),
+            cleanup_removals: Counter::default(),
+        };
+        metrics
+            .registry
+            .register("rooms", "Rooms kept by the server", metrics.rooms.clone());
+        metrics.registry.register(
+            "online_members",
+            "Members connected or within their reconnect grace period",
+            metrics.online_members.clone(),
+        );
+        metrics.registry.register(
+            "websocket_connections",
+            "Open WebSocket connections, including ones still joining",
+            metrics.connections.clone(),
+        );
+        metrics.registry.register(
+            "rooms_created",
+            "Rooms created",
+            metrics.rooms_created.clone(),
+        );
+        metrics.registry.register(
+            "rooms_deleted",
+            "Rooms deleted on request",
+            metrics.rooms_deleted.clone(),
+        );
+        metrics.registry.register(
+            "joins",
+            "Successful joins by join request kind",
+            metrics.joins.clone(),
+        );
+        metrics.registry.register(
+            "errors_sent",
+            "Errors sent to WebSocket clients by error kind",
+            metrics.errors.clone(),
+        );
+        metrics.registry.register(
+            "chat_messages",
+            "Chat messages posted",
+            metrics.chat_messages.clone(),
+        );
+        metrics.registry.register(
+            "signaling_relayed",
+            "Signaling messages relayed between members by payload kind",
+            metrics.signaling_relayed.clone(),
+        );
+        metrics.registry.register(
+            "broadcast_lags",
+            "Times a member fell behind the room broadcast and was resynced",
+            metrics.broadcast_lags.clone(),
+        );
+        metrics.registry.register(
+            "cleanup_removals",
+            "Inactive rooms removed by the cleanup task",
+            metrics.cleanup_removals.clone(),
+        );
+        metrics
+    }
+
+    /// Count a WebSocket connection until the returned guard is dropped
+    pub fn connection_opened(&self) -> ConnectionGuard {
+        self.connections.inc();
+        ConnectionGuard(self.connections.clone())
+    }
+
+    pub fn open_connections(&self) -> i64 {
+        self.connections.get()
+    }
+
+    pub fn member_joined(&self, request: &JoinRequest) {
+        let kind = match request {
+            JoinRequest::WithToken { .. } => "with_token",
+            JoinRequest::WithUsername { .. } => "with_username",
+        };
+        self.joins.get_or_create(&KindLabel { kind }).inc();
+    }
+
+    pub fn error_sent(&self, error: &ErrorKind) {
+        let kind = match error {
+            ErrorKind::TokenNotFound => "token_not_found",
+            ErrorKind::TokenAlreadyInUse => "token_already_in_use",
+            ErrorKind::RoomNotFound => "room_not_found",
+            ErrorKind::InvalidUsername { .. } => "invalid_username",
+            ErrorKind::UsernameTaken => "username_taken",
+            ErrorKind::PasswordRequired => "password_required",
+            ErrorKind::IncorrectPassword => "incorrect_password",
+            ErrorKind::JoinTimeout => "join_timeout",
+            ErrorKind::TooManyAttempts { .. } => "too_many_attempts",
+            ErrorKind::MemberNotFound { .. } => "member_not_found",
+            ErrorKind::MemberOffline { .. } => "member_offline",
+            ErrorKind::SfuNotEnabled => "sfu_not_enabled",
+            ErrorKind::NotOwner => "not_owner",
+            ErrorKind::Banned => "banned",
+            ErrorKind::IncompatibleProtocol { .. } => "incompatible_protocol",
+            ErrorKind::Other { .. } => "other",
+        };
+        self.errors.get_or_create(&KindLabel { kind }).inc();
+    }
+
+    /// Counter to increment once a payload of this kind was relayed
+    pub fn relayed_signaling(&self, payload: &SignalingPayload) -> Counter {
+        let kind = match payload {
+            SignalingPayload::Offer { .. } => "offer",
+            SignalingPayload::Answer { .. } => "answer",
+            SignalingPayload::IceCandidate { .. } => "ice_candidate",
+        };
+        self.signaling_relayed
+            .get_or_create(&KindLabel { kind })
+            .clone()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct ConnectionGuard(Gauge);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Router with the `/metrics` endpoint, merged into the main router unless `bind` is set
+pub fn router(config: &MetricsConfig) -> anyhow::Result<Router<AppState>> {
+    anyhow::ensure!(
+        config.bind.is_some() || config.token.is_some(),
+        "metrics needs a separate bind address or a token"
+    );
+    let token = config.token.clone();
+    Ok(Router::new().route(
+        "/metrics",
+        get(move |state: State<AppState>, headers: HeaderMap| {
+            scrape(state, headers, token.clone())
+        }),
+    ))
+}
+
+/// Serve the metrics router on its own listener
+pub async fn serve(bind: SocketAddr, router: Router) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(bind)
+        .await
+        .context("failed to bind metrics listener")?;
+    info!("Serving metrics on http://{bind}/metrics");
+    axum::serve(listener, router)
+        .await
+        .context("metrics server error")
+}
+
+async fn scrape(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    token: Option<String>,
+) -> Response {
+    if let Some(token) = token
+        && !auth::has_bearer_token(&headers, &token)
+    {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let metrics = &state.metrics;
+    {
+        let rooms = state.rooms.read().await;
+        let online = rooms
+            .values()
+            .flat_map(|room| room.members.values())
+            .filter(|member| member.is_online())
+            .count();
+        metrics.rooms.set(rooms.len() as i64);
+        metrics.online_members.set(online as i64);
+    }
+
+    let mut body = String::new();
+    if let Err(e) = encode(&mut body, &metrics.registry) {
+        tracing::error!("Failed to encode metrics: {e}");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/openmetrics-text; version=1.0.0; charset=utf-8"),
+        )],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn get_metrics(state: &AppState, token: Option<&str>) -> Response {
+        let config = MetricsConfig {
+            bind: None,
+            token: Some("scrape-token".to_string()),
+        };
+        let app = router(&config).unwrap().with_state(state.clone());
+        let mut request = Request::get("/metrics");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn requires_token() {
+        let state = AppState::default();
+        let response = get_metrics(&state, None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = get_metrics(&state, Some("wrong")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn exports_counters_by_kind() {
+        let state = AppState::default();
+        state.metrics.error_sent(&ErrorKind::Banned);
+        state.metrics.error_sent(&ErrorKind::Banned);
+        state
+            .metrics
+            .relayed_signaling(&SignalingPayload::Offer { sdp: String::new() })
+            .inc();
+        let _connection = state.metrics.connection_opened();
+
+        let response = get_metrics(&state, Some("scrape-token")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("inpixly_errors_sent_total{kind=\"banned\"} 2"));
+        assert!(body.contains("inpixly_signaling_relayed_total{kind=\"offer\"} 1"));
+        assert!(body.contains("inpixly_websocket_connections 1"));
+        assert!(body.contains("inpixly_rooms 0"));
+    }
+
+    #[test]
+    fn refuses_unprotected_endpoint() {
+        let config = MetricsConfig {
+            bind: None,
+            token: None,
+        };
+        assert!(router(&config).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+
+use argon2::{
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use inpixly_shared::{ErrorKind, Password};
+
+/// Argon2id hash of a room password with its salt and cost, in PHC string format
+#[derive(Clone, PartialEq)]
+pub struct HashedPassword(String);
+
+impl HashedPassword {
+    /// Hash a password with a fresh random salt
+    pub fn new(password: &Password, params: &Params) -> anyhow::Result<Self> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = argon2(params.clone())
+            .hash_password(password.as_str().as_bytes(), &salt)
+            .map_err(|e| anyhow::anyhow!("failed to hash password: {e}"))?;
+        Ok(Self(hash.to_string()))
+    }
+
+    /// Restore a hash created by [`HashedPassword::new`]
+    pub fn from_phc(phc: String) -> anyhow::Result<Self> {
+        PasswordHash::new(&phc).map_err(|e| anyhow::anyhow!("invalid password hash: {e}"))?;
+        Ok(Self(phc))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Check a password against this hash, the cost is taken from the hash itself
+    pub fn matches(&self, password: &Password) -> bool {
+        let Ok(hash) = PasswordHash::new(&self.0) else {
+            return false;
+        };
+        argon2(Params::default())
+            .verify_password(password.as_str().as_bytes(), &hash)
+            .is_ok()
+    }
+}
+
+impl fmt::Debug for HashedPassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HashedPassword(..)")
+    }
+}
+
+/// Verify a provided password against the room's hash.
+/// Slow by design, keep it off the async runtime.
+pub fn verify(hash: Option<&HashedPassword>, password: Option<&Password>) -> Result<(), ErrorKind> {
+    match (hash, password) {
+        (None, _) => Ok(()), // No password required
+        (Some(_), None) => Err(ErrorKind::PasswordRequired),
+        (Some(hash), Some(password)) if hash.matches(password) => Ok(()),
+        (Some(_), Some(_)) => Err(ErrorKind::IncorrectPassword),
+    }
+}
+
+fn argon2(params: Params) -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cheap_params() -> Params {
+        Params::new(Params::MIN_M_COST, 1, 1, None).unwrap()
+    }
+
+    #[test]
+    fn verifies_hashed_password() {
+        let password: Password = "hunter2".parse().unwrap();
+        let hash = HashedPassword::new(&password, &cheap_params()).unwrap();
+        assert!(hash.as_str().starts_with("$argon2id$"));
+        assert!(!hash.as_str().contains("hunter2"));
+        assert!(verify(Some(&hash), Some(&password)).is_ok());
+        assert!(matches!(
+            verify(Some(&hash), Some(&"hunter3".parse().unwrap())),
+            Err(ErrorKind::IncorrectPassword)
+        ));
+        assert!(matches!(
+            verify(Some(&hash), None),
+            Err(ErrorKind::PasswordRequired)
+        ));
+        assert!(verify(None, Some(&password)).is_ok());
+    }
+
+    #[test]
+    fn salts_every_hash() {
+        let password: Password = "hunter2".parse().unwrap();
+        let first = HashedPassword::new(&password, &cheap_params()).unwrap();
+        let second = HashedPassword::new(&password, &cheap_params()).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn restores_from_phc() {
+        let password: Password = "hunter2".parse().unwrap();
+        let hash = HashedPassword::new(&password, &cheap_params()).unwrap();
+        let restored = HashedPassword::from_phc(hash.as_str().to_string()).unwrap();
+        assert!(restored.matches(&password));
+        assert!(HashedPassword::from_phc("hunter2".to_string()).is_err());
+    }
+}
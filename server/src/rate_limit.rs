@@ -0,0 +1,253 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use inpixly_shared::{ErrorKind, RoomId};
+use tracing::warn;
+
+use crate::config::{LimitConfig, RateLimitConfig};
+
+/// Counts attempts per key within a window and locks the key out once it goes over the limit
+pub struct RateLimiter<K> {
+    max_attempts: u32,
+    window: Duration,
+    lockout: Duration,
+    entries: Mutex<HashMap<K, Entry>>,
+}
+
+struct Entry {
+    window_start: Instant,
+    attempts: u32,
+    locked_until: Option<Instant>,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    pub fn new(config: &LimitConfig) -> Self {
+        Self {
+            max_attempts: config.max_attempts,
+            window: Duration::from_secs(config.window_secs),
+            lockout: Duration::from_secs(config.lockout_secs),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the remaining lockout if the key is locked out
+    pub fn check(&self, key: &K) -> Result<(), Duration> {
+        let now = Instant::now();
+        match self.entries.lock().unwrap().get(key) {
+            Some(Entry {
+                locked_until: Some(until),
+                ..
+            }) if *until > now => Err(*until - now),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count an attempt, refusing it and starting the lockout when it goes over the limit
+    pub fn hit(&self, key: &K) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key.clone()).or_insert(Entry {
+            window_start: now,
+            attempts: 0,
+            locked_until: None,
+        });
+        if let Some(until) = entry.locked_until {
+            if until > now {
+                return Err(until - now);
+            }
+            entry.locked_until = None;
+        }
+        if now.duration_since(entry.window_start) > self.window {
+            entry.window_start = now;
+            entry.attempts = 0;
+        }
+        entry.attempts += 1;
+        if entry.attempts > self.max_attempts {
+            entry.locked_until = Some(now + self.lockout);
+            entry.window_start = now;
+            entry.attempts = 0;
+            return Err(self.lockout);
+        }
+        Ok(())
+    }
+
+    /// Forget keys whose window and lockout have both passed
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.entries.lock().unwrap().retain(|_, entry| {
+            entry.locked_until.is_some_and(|until| until > now)
+                || now.duration_since(entry.window_start) <= self.window
+        });
+    }
+}
+
+/// All limits applied to joins and room creation
+pub struct RateLimits {
+    join_per_ip: RateLimiter<IpAddr>,
+    join_per_room: RateLimiter<RoomId>,
+    failed_password_per_ip: RateLimiter<IpAddr>,
+    /// Caps guesses from many addresses, token rejoins never check a password
+    failed_password_per_room: RateLimiter<RoomId>,
+    failed_password_per_room_and_ip: RateLimiter<(RoomId, IpAddr)>,
+    create_room_per_ip: RateLimiter<IpAddr>,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            join_per_ip: RateLimiter::new(&config.join_per_ip),
+            join_per_room: RateLimiter::new(&config.join_per_room),
+            failed_password_per_ip: RateLimiter::new(&config.failed_password_per_ip),
+            failed_password_per_room: RateLimiter::new(&config.failed_password_per_room),
+            failed_password_per_room_and_ip: RateLimiter::new(
+                &config.failed_password_per_room_and_ip,
+            ),
+            create_room_per_ip: RateLimiter::new(&config.create_room_per_ip),
+        }
+    }
+
+    /// Count a join attempt. Members rejoining with a valid token skip the room limit,
+    /// a flood of strangers must not keep them out of their own room.
+    pub fn join(&self, ip: IpAddr, room_id: &RoomId, is_member: bool) -> Result<(), ErrorKind> {
+        self.join_per_ip
+            .hit(&ip)
+            .and_then(|_| match is_member {
+                true => Ok(()),
+                false => self.join_per_room.hit(room_id),
+            })
+            .map_err(|wait| too_many_attempts("join", ip, wait))
+    }
+
+    /// Refuse password guesses while the IP, the room or the IP in that room is locked out
+    pub fn check_password(&self, ip: IpAddr, room_id: &RoomId) -> Result<(), ErrorKind> {
+        let room_and_ip = (room_id.clone(), ip);
+        self.failed_password_per_ip
+            .check(&ip)
+            .and_then(|_| self.failed_password_per_room_and_ip.check(&room_and_ip))
+            .and_then(|_| self.failed_password_per_room.check(room_id))
+            .map_err(|wait| too_many_attempts("password", ip, wait))
+    }
+
+    pub fn password_failed(&self, ip: IpAddr, room_id: &RoomId) {
+        let _ = self.failed_password_per_ip.hit(&ip);
+        let _ = self.failed_password_per_room.hit(room_id);
+        let _ = self
+            .failed_password_per_room_and_ip
+            .hit(&(room_id.clone(), ip));
+    }
+
+    pub fn create_room(&self, ip: IpAddr) -> Result<(), ErrorKind> {
+        self.create_room_per_ip
+            .hit(&ip)
+            .map_err(|wait| too_many_attempts("create room", ip, wait))
+    }
+
+    pub fn prune(&self) {
+        self.join_per_ip.prune();
+        self.join_per_room.prune();
+        self.failed_password_per_ip.prune();
+        self.failed_password_per_room.prune();
+        self.failed_password_per_room_and_ip.prune();
+        self.create_room_per_ip.prune();
+    }
+}
+
+fn too_many_attempts(action: &str, ip: IpAddr, wait: Duration) -> ErrorKind {
+    let retry_after_secs = wait.as_secs_f64().ceil() as u64;
+    warn!(%ip, retry_after_secs, "Too many {action} attempts.");
+    ErrorKind::TooManyAttempts { retry_after_secs }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_attempts: u32, lockout_secs: u64) -> RateLimiter<&'static str> {
+        RateLimiter::new(&LimitConfig {
+            max_attempts,
+            window_secs: 60,
+            lockout_secs,
+        })
+    }
+
+    #[test]
+    fn locks_out_over_the_limit() {
+        let limiter = limiter(3, 30);
+        for _ in 0..3 {
+            assert!(limiter.hit(&"a").is_ok());
+        }
+        let wait = limiter.hit(&"a").unwrap_err();
+        assert_eq!(wait, Duration::from_secs(30));
+        assert!(limiter.check(&"a").is_err());
+        assert!(limiter.hit(&"a").is_err());
+
+        // Other keys are unaffected
+        assert!(limiter.check(&"b").is_ok());
+        assert!(limiter.hit(&"b").is_ok());
+    }
+
+    #[test]
+    fn lockout_expires() {
+        let limiter = limiter(1, 0);
+        assert!(limiter.hit(&"a").is_ok());
+        assert!(limiter.hit(&"a").is_err());
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(limiter.check(&"a").is_ok());
+        assert!(limiter.hit(&"a").is_ok());
+    }
+
+    #[test]
+    fn prune_keeps_active_entries() {
+        let limiter = limiter(1, 30);
+        assert!(limiter.hit(&"a").is_ok());
+        assert!(limiter.hit(&"a").is_err());
+        limiter.prune();
+        assert!(limiter.check(&"a").is_err());
+    }
+
+    fn room_id() -> RoomId {
+        "550e8400-e29b-41d4-a716-446655440000".parse().unwrap()
+    }
+
+    fn ip(n: u8) -> IpAddr {
+        IpAddr::from([203, 0, 113, n])
+    }
+
+    #[test]
+    fn members_rejoin_a_flooded_room() {
+        let limits = RateLimits::new(&RateLimitConfig::default());
+        let max_attempts = RateLimitConfig::default().join_per_room.max_attempts;
+        for n in 0..=max_attempts {
+            let _ = limits.join(ip(n as u8), &room_id(), false);
+        }
+        assert!(limits.join(ip(250), &room_id(), false).is_err());
+        assert!(limits.join(ip(250), &room_id(), true).is_ok());
+    }
+
+    #[test]
+    fn password_guesses_lock_out_the_address_then_the_room() {
+        let limits = RateLimits::new(&RateLimitConfig::default());
+        let per_address = RateLimitConfig::default()
+            .failed_password_per_room_and_ip
+            .max_attempts;
+        for _ in 0..=per_address {
+            limits.password_failed(ip(1), &room_id());
+        }
+        assert!(limits.check_password(ip(1), &room_id()).is_err());
+        assert!(limits.check_password(ip(2), &room_id()).is_ok());
+
+        // Spread over many addresses the guesses still add up for the room
+        let per_room = RateLimitConfig::default()
+            .failed_password_per_room
+            .max_attempts;
+        for n in 0..per_room {
+            limits.password_failed(ip(10 + n as u8), &room_id());
+        }
+        assert!(limits.check_password(ip(2), &room_id()).is_err());
+    }
+}
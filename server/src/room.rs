@@ -1,56 +1,157 @@
 use chrono::{DateTime, Utc};
-use inpixly_shared::{ErrorKind, MemberInfo, Password, RoomId, Username, WsMessage};
-use std::{collections::BTreeMap, str::FromStr, sync::Arc};
-use subtle::ConstantTimeEq;
-use tokio::sync::broadcast;
+use inpixly_shared::{ErrorKind, MemberInfo, Password, RoomId, RoomMode, ServerMessage, Username};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicI64, Ordering},
+    },
+};
+use tokio::sync::{
+    broadcast,
+    mpsc::{self, error::TrySendError},
+};
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{info, warn};
 use uuid::Uuid;
 
+use crate::{
+    bus::{BusEvent, LocalBus, RoomBus},
+    chat::ChatLog,
+    config::ChatConfig,
+    password::HashedPassword,
+    sfu::Sfu,
+    storage::{StoredBan, StoredMember, StoredRoom},
+};
+
+/// Events a member's connection may fall behind on before further ones are dropped
+pub const MEMBER_CHANNEL_CAPACITY: usize = 64;
+
 pub struct Room {
     pub id: RoomId,
     pub owner_token: String,
-    pub password: Option<Password>,
+    pub password: Option<HashedPassword>,
     pub members: BTreeMap<MemberToken, Member>,
     pub last_activity: DateTime<Utc>,
-    pub broadcast_tx: broadcast::Sender<RoomEvent>,
+    /// Carries room wide events to the members, wherever they are connected
+    bus: Arc<dyn RoomBus>,
+    /// Directed delivery channels of connected members
+    member_txs: HashMap<MemberToken, mpsc::Sender<RoomEvent>>,
+    /// Forwarding unit, present only in SFU rooms
+    pub sfu: Option<Arc<Sfu>>,
+    pub chat: ChatLog,
+    /// Members banned by the owner, keyed by their former token
+    banned: HashMap<MemberToken, Ban>,
+}
+
+/// What a banned member is recognized by when it tries to come back
+struct Ban {
+    username: Username,
+    /// Where it was connected from, new members joining from there are refused too
+    ip: Option<IpAddr>,
 }
 
 impl Room {
-    pub fn new(password: Option<Password>) -> Self {
-        let (broadcast_tx, _) = broadcast::channel(256);
-        Self {
+    /// Create a room, hashing its password with the given cost
+    pub fn new(
+        password: Option<Password>,
+        password_params: &argon2::Params,
+        sfu: Option<Arc<Sfu>>,
+        chat_history_size: usize,
+        bus: Arc<dyn RoomBus>,
+    ) -> anyhow::Result<Self> {
+        let password = password
+            .map(|p| HashedPassword::new(&p, password_params))
+            .transpose()?;
+        Ok(Self {
             id: Uuid::new_v4().to_string().parse().unwrap(),
             owner_token: Uuid::new_v4().to_string(),
             password,
             members: BTreeMap::new(),
             last_activity: Utc::now(),
-            broadcast_tx,
+            bus,
+            member_txs: HashMap::new(),
+            sfu,
+            chat: ChatLog::new(chat_history_size),
+            banned: HashMap::new(),
+        })
+    }
+
+    /// Rebuild a room from storage, all members start offline
+    pub fn from_stored(
+        stored: StoredRoom,
+        sfu: Option<Arc<Sfu>>,
+        chat_history_size: usize,
+        bus: Arc<dyn RoomBus>,
+    ) -> Self {
+        Self {
+            id: stored.id,
+            owner_token: stored.owner_token,
+            password: stored.password,
+            members: stored
+                .members
+                .into_iter()
+                .map(|m| (m.token.clone(), Member::from_stored(m)))
+                .collect(),
+            last_activity: stored.last_activity,
+            bus,
+            member_txs: HashMap::new(),
+            sfu,
+            chat: ChatLog::new(chat_history_size),
+            banned: stored
+                .bans
+                .into_iter()
+                .map(|b| {
+                    let ban = Ban {
+                        username: b.username,
+                        ip: b.ip,
+                    };
+                    (b.token, ban)
+                })
+                .collect(),
         }
     }
 
-    /// Check if room has a password
-    pub fn has_password(&self) -> bool {
-        self.password.is_some()
+    pub fn to_stored(&self) -> StoredRoom {
+        StoredRoom {
+            id: self.id.clone(),
+            owner_token: self.owner_token.clone(),
+            password: self.password.clone(),
+            mode: self.mode(),
+            last_activity: self.last_activity,
+            members: self
+                .members
+                .values()
+                .filter(|m| m.hosted)
+                .map(|m| m.to_stored())
+                .collect(),
+            bans: self
+                .banned
+                .iter()
+                .map(|(token, ban)| StoredBan {
+                    token: token.clone(),
+                    username: ban.username.clone(),
+                    ip: ban.ip,
+                })
+                .collect(),
+        }
     }
 
-    /// Verify the provided password (uses constant-time comparison)
-    pub fn verify_password(&self, password: Option<&Password>) -> Result<(), ErrorKind> {
-        match (&self.password, password) {
-            (None, _) => Ok(()), // No password required
-            (Some(_), None) => Err(ErrorKind::PasswordRequired),
-            (Some(room_pass), Some(provided_pass)) => {
-                let room_bytes = room_pass.as_str().as_bytes();
-                let provided_bytes = provided_pass.as_str().as_bytes();
-                if room_bytes.ct_eq(provided_bytes).into() {
-                    Ok(())
-                } else {
-                    Err(ErrorKind::IncorrectPassword)
-                }
-            }
+    pub fn mode(&self) -> RoomMode {
+        if self.sfu.is_some() {
+            RoomMode::Sfu
+        } else {
+            RoomMode::Mesh
         }
     }
 
+    /// Check if room has a password
+    pub fn has_password(&self) -> bool {
+        self.password.is_some()
+    }
+
     pub fn touch(&mut self) {
         self.last_activity = Utc::now();
     }
@@ -85,87 +186,288 @@ impl Room {
         requested_username: Username,
         is_online: bool,
     ) -> Result<(Username, MemberToken), ErrorKind> {
-        let username = self
-            .generate_unique_username(requested_username)
-            .ok_or(ErrorKind::UsernameTaken)?;
+        let username = self.pick_username(requested_username)?;
+        Ok(self.insert_member(Member::new(username, is_online)))
+    }
+
+    /// The username a new member would get, the requested one or one with a numeric suffix
+    pub fn pick_username(&self, requested_username: Username) -> Result<Username, ErrorKind> {
+        if self
+            .banned
+            .values()
+            .any(|b| b.username == requested_username)
+        {
+            return Err(ErrorKind::Banned);
+        }
+        self.generate_unique_username(requested_username)
+            .ok_or(ErrorKind::UsernameTaken)
+    }
 
-        let member = Member::new(username.clone(), is_online);
+    /// Add a member whose username was already picked, and claimed in the store if shared
+    pub fn insert_member(&mut self, member: Member) -> (Username, MemberToken) {
+        let username = member.username.clone();
         let token = member.token.clone();
         self.members.insert(token.clone(), member);
-        let _ = self
-            .broadcast_tx
-            .send(RoomEvent::Broadcast(WsMessage::MemberJoined {
-                username: username.clone(),
-            }));
+        self.broadcast(ServerMessage::MemberJoined {
+            username: username.clone(),
+        });
         self.touch();
-        Ok((username, token))
+        (username, token)
+    }
+
+    /// Bring the room up to date with what other instances sharing the store wrote.
+    /// Members with a session on this instance keep their state, members missing from
+    /// the store were removed elsewhere and are disconnected here.
+    pub fn merge_stored(&mut self, stored: StoredRoom) {
+        self.last_activity = self.last_activity.max(stored.last_activity);
+        for ban in stored.bans {
+            self.banned.entry(ban.token).or_insert(Ban {
+                username: ban.username,
+                ip: ban.ip,
+            });
+        }
+        let mut members = BTreeMap::new();
+        for stored in stored.members {
+            let token = stored.token.clone();
+            let member = match self.members.remove(&token) {
+                Some(member) if self.member_txs.contains_key(&token) => member,
+                _ => Member::from_shared(stored),
+            };
+            members.insert(token, member);
+        }
+        for token in std::mem::replace(&mut self.members, members).into_keys() {
+            if let Some(tx) = self.member_txs.remove(&token) {
+                let _ = tx.try_send(RoomEvent::Kick {
+                    success: Arc::new(std::sync::Mutex::new(None)),
+                });
+            }
+        }
+    }
+
+    /// Drop a member that was removed on another instance, without telling the room again
+    pub fn forget_member(&mut self, token: &str) {
+        self.member_txs.remove(token);
+        self.members.remove(token);
     }
 
-    /// Handle member login by token, returns the username if successful.
-    pub fn login_member(&mut self, token: &str) -> Result<Username, ErrorKind> {
+    /// Whether the token belongs to a member that may still log in
+    pub fn is_member(&self, token: &str) -> bool {
+        self.members.contains_key(token) && !self.banned.contains_key(token)
+    }
+
+    /// Handle member login by token, returns the username and whether an
+    /// interrupted session was resumed. Resuming announces nothing to the room.
+    pub fn login_member(&mut self, token: &str) -> Result<(Username, bool), ErrorKind> {
+        if self.banned.contains_key(token) {
+            return Err(ErrorKind::Banned);
+        }
+        let live_session = self.member_txs.get(token).is_some_and(|tx| !tx.is_closed());
         let Some(member) = self.members.get_mut(token) else {
             return Err(ErrorKind::TokenNotFound);
         };
-        if member.is_online {
+        if live_session {
             return Err(ErrorKind::TokenAlreadyInUse);
         }
-        member.set_online(true);
-        let _ = self
-            .broadcast_tx
-            .send(RoomEvent::Broadcast(WsMessage::MemberJoined {
-                username: member.username.clone(),
-            }));
         let username = member.username.clone();
+        // Still online means the previous connection is within its reconnect grace period
+        let resumed = member.is_online;
+        member.set_online(true);
+        member.hosted = true;
+        if !resumed {
+            self.broadcast(ServerMessage::MemberJoined {
+                username: username.clone(),
+            });
+        }
         self.touch();
-        Ok(username)
+        Ok((username, resumed))
     }
 
+    /// Disconnect the current session of a member so a new connection can take it over.
+    /// The member stays online, the new session resumes it.
     pub fn force_logout_member(&mut self, token: &str) -> Option<CancellationToken> {
-        let Some(member) = self.members.get_mut(token) else {
-            return None;
-        };
+        let member = self.members.get(token)?;
         if !member.is_online {
             return None;
         }
-        member.set_online(false);
 
         let disconnect_token = CancellationToken::new();
-        let _ = self.broadcast_tx.send(RoomEvent::Kick {
-            token: token.to_string(),
-            success: Arc::new(std::sync::Mutex::new(Some(
-                disconnect_token.clone().drop_guard(),
-            ))),
+        // Tells the old client not to reconnect and take the session back
+        let _ = self.send_to(token, RoomEvent::Direct(ServerMessage::ForceDisconnect));
+        let _ = self.send_to(
+            token,
+            RoomEvent::Kick {
+                success: Arc::new(std::sync::Mutex::new(Some(
+                    disconnect_token.clone().drop_guard(),
+                ))),
+            },
+        );
+        // Closing the channel ends the old session even if it misses the kick
+        self.member_txs.remove(token);
+        Some(disconnect_token)
+    }
+
+    /// Refuse new members joining from the address of a banned one.
+    /// Members that already have a token keep rejoining from there.
+    pub fn check_address(&self, ip: IpAddr) -> Result<(), ErrorKind> {
+        if self.banned.values().any(|b| b.ip == Some(ip)) {
+            return Err(ErrorKind::Banned);
+        }
+        Ok(())
+    }
+
+    /// Remember where a member connected from, in case it gets banned
+    pub fn record_address(&mut self, token: &str, ip: IpAddr) {
+        if let Some(member) = self.members.get_mut(token) {
+            member.ip = Some(ip);
+        }
+    }
+
+    /// Remove a member on behalf of the owner, disconnecting it if online.
+    /// With `ban` its token and username are refused from then on, and so are
+    /// new members joining from the address it last connected from.
+    /// Returns the token of the removed member.
+    pub fn remove_member(
+        &mut self,
+        username: &Username,
+        ban: bool,
+    ) -> Result<MemberToken, ErrorKind> {
+        let token = self
+            .members
+            .iter()
+            .find(|(_, m)| &m.username == username)
+            .map(|(token, _)| token.clone())
+            .ok_or_else(|| ErrorKind::MemberNotFound {
+                username: username.to_string(),
+            })?;
+
+        // Both fail if the member is offline, there is nothing to disconnect then.
+        // Connected to another instance, that one disconnects it on seeing the message.
+        let _ = self.relay(username.as_str(), ServerMessage::Removed { banned: ban });
+        let _ = self.send_to(
+            &token,
+            RoomEvent::Kick {
+                success: Arc::new(std::sync::Mutex::new(None)),
+            },
+        );
+        self.member_txs.remove(&token);
+        let member = self.members.remove(&token);
+        if ban {
+            let ban = Ban {
+                username: username.clone(),
+                ip: member.and_then(|m| m.ip),
+            };
+            self.banned.insert(token.clone(), ban);
+        }
+
+        info!(room_id = %self.id, %username, ban, "Member removed by owner.");
+        self.broadcast(ServerMessage::MemberList {
+            members: self.get_member_list(),
         });
-        let _ = self
-            .broadcast_tx
-            .send(RoomEvent::Broadcast(WsMessage::MemberLeft {
-                username: member.username.clone(),
-            }));
         self.touch();
-        Some(disconnect_token)
+        Ok(token)
     }
 
-    /// Handle member disconnection
+    /// Handle the end of a member session, once its reconnect grace period is over.
+    /// Nothing happens if the session was taken over or the member reconnected since.
     pub fn on_disconnect(
         &mut self,
         token: &str,
+        session: u64,
         disconnect_token: Option<tokio_util::sync::DropGuard>,
     ) {
-        if let Some(member) = self.members.get_mut(token) {
-            info!(
-                room_id = %self.id,
-                username = %member.username(),
-                "User left room."
-            );
-            member.set_online(false);
-            let _ = self
-                .broadcast_tx
-                .send(RoomEvent::Broadcast(WsMessage::MemberLeft {
-                    username: member.username.clone(),
-                }));
-            self.touch();
-        }
-        drop(disconnect_token);
+        if disconnect_token.is_some() {
+            return;
+        }
+        let Some(member) = self.members.get_mut(token) else {
+            return;
+        };
+        if member.session != session {
+            return;
+        }
+        self.member_txs.remove(token);
+
+        info!(
+            room_id = %self.id,
+            username = %member.username(),
+            "User left room."
+        );
+        member.set_online(false);
+        member.is_sharing = false;
+        let username = member.username.clone();
+        self.broadcast(ServerMessage::MemberLeft { username });
+        self.touch();
+    }
+
+    /// Record that a member started or stopped sharing, telling the room if that changed
+    pub fn set_sharing(&mut self, token: &str, sharing: bool) {
+        let Some(member) = self.members.get_mut(token) else {
+            return;
+        };
+        if member.is_sharing == sharing {
+            return;
+        }
+        member.is_sharing = sharing;
+        let username = member.username.clone();
+        self.broadcast(ServerMessage::SharingChanged { username, sharing });
+    }
+
+    /// Register the directed delivery channel of a member, replacing any previous one.
+    /// Returns the receiver and the id of the new session.
+    pub fn register_member_channel(
+        &mut self,
+        token: &str,
+    ) -> Option<(mpsc::Receiver<RoomEvent>, u64)> {
+        let member = self.members.get_mut(token)?;
+        member.session += 1;
+        let (tx, rx) = mpsc::channel(MEMBER_CHANNEL_CAPACITY);
+        self.member_txs.insert(token.to_string(), tx);
+        Some((rx, member.session))
+    }
+
+    /// Get a sender for the directed delivery channel of a connected member
+    pub fn member_channel(&self, token: &str) -> Option<mpsc::Sender<RoomEvent>> {
+        self.member_txs.get(token).cloned()
+    }
+
+    /// Send an event to a single member, identified by username or token
+    pub fn send_to(&self, username_or_token: &str, event: RoomEvent) -> Result<(), ErrorKind> {
+        let (token, member) = self
+            .members
+            .get_key_value(username_or_token)
+            .or_else(|| self.find_by_username(username_or_token))
+            .ok_or_else(|| ErrorKind::MemberNotFound {
+                username: username_or_token.to_string(),
+            })?;
+        self.deliver(token, member, event)
+    }
+
+    fn find_by_username(&self, username: &str) -> Option<(&MemberToken, &Member)> {
+        self.members
+            .iter()
+            .find(|(_, m)| m.username.as_str() == username)
+    }
+
+    fn deliver(&self, token: &str, member: &Member, event: RoomEvent) -> Result<(), ErrorKind> {
+        let offline = || ErrorKind::MemberOffline {
+            username: member.username.to_string(),
+        };
+        if !member.is_online {
+            return Err(offline());
+        }
+        let tx = self.member_txs.get(token).ok_or_else(offline)?;
+        match tx.try_send(event) {
+            Ok(()) => Ok(()),
+            // Buffering without bound would let one member flood another into memory exhaustion
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    room_id = %self.id,
+                    username = %member.username,
+                    "Delivery channel full, dropping event."
+                );
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(offline()),
+        }
     }
 
     /// Get list of all members
@@ -178,28 +480,61 @@ impl Room {
         self.owner_token == token
     }
 
-    /// Broadcast a room event to all subscribers
-    pub fn broadcast(&self, msg: RoomEvent) {
-        let _ = self.broadcast_tx.send(msg);
+    /// Send a message to every member of the room
+    pub fn broadcast(&self, message: ServerMessage) {
+        self.bus.publish(&self.id, BusEvent::Broadcast { message });
+    }
+
+    /// Send a message to a single member, which may be connected to another instance.
+    /// Only usernames are valid recipients, never tokens.
+    pub fn relay(&self, to: &str, message: ServerMessage) -> Result<(), ErrorKind> {
+        let not_found = || ErrorKind::MemberNotFound {
+            username: to.to_string(),
+        };
+        let to: Username = to.parse().map_err(|_| not_found())?;
+        let (token, member) = self.find_by_username(to.as_str()).ok_or_else(not_found)?;
+        // Online without a channel here, so connected to another instance sharing the store
+        if self.bus.is_shared() && member.is_online && !self.member_txs.contains_key(token) {
+            self.bus.publish(&self.id, BusEvent::Direct { to, message });
+            return Ok(());
+        }
+        self.deliver(token, member, RoomEvent::Direct(message))
+    }
+
+    /// Send a message to the members connected to this instance only
+    pub fn notify_connected(&self, message: ServerMessage) {
+        for tx in self.member_txs.values() {
+            let _ = tx.try_send(RoomEvent::Direct(message.clone()));
+        }
     }
 
     /// Subscribe to room events
-    pub fn subscribe(&self) -> broadcast::Receiver<RoomEvent> {
-        self.broadcast_tx.subscribe()
+    pub fn subscribe(&self) -> broadcast::Receiver<BusEvent> {
+        self.bus.subscribe(&self.id)
     }
 }
 
 impl Default for Room {
     fn default() -> Self {
-        Self::new(None)
+        Self::new(
+            None,
+            &argon2::Params::default(),
+            None,
+            ChatConfig::default().history_size,
+            Arc::new(LocalBus::default()),
+        )
+        .expect("a room without a password is always created")
     }
 }
 
+/// Event delivered to the connection of a member
 #[derive(Clone)]
 pub enum RoomEvent {
-    Broadcast(WsMessage),
+    /// Message for every member of the room
+    Broadcast(ServerMessage),
+    /// Message addressed to a single member
+    Direct(ServerMessage),
     Kick {
-        token: String,
         success: Arc<std::sync::Mutex<Option<tokio_util::sync::DropGuard>>>,
     },
 }
@@ -210,8 +545,18 @@ pub type MemberToken = String;
 pub struct Member {
     username: Username,
     token: MemberToken,
-    last_seen: DateTime<Utc>,
+    last_seen: LastSeen,
     is_online: bool,
+    /// Kept through a resumed session, a screen share survives a short disconnect
+    is_sharing: bool,
+    /// Address of the latest connection, not persisted
+    ip: Option<IpAddr>,
+    /// Connected through this instance at some point, only such members are written back
+    /// to the store. The others are as another instance wrote them.
+    hosted: bool,
+    /// Incremented for every connection, tells a stale session from the current one
+    session: u64,
+    lag_count: u64,
 }
 
 impl Member {
@@ -220,7 +565,46 @@ impl Member {
             username,
             token: Uuid::new_v4().to_string(),
             is_online,
-            last_seen: Utc::now(),
+            is_sharing: false,
+            ip: None,
+            hosted: true,
+            last_seen: LastSeen::new(Utc::now()),
+            session: 0,
+            lag_count: 0,
+        }
+    }
+
+    fn from_stored(stored: StoredMember) -> Self {
+        Self {
+            username: stored.username,
+            token: stored.token,
+            last_seen: LastSeen::new(stored.last_seen),
+            is_online: false,
+            is_sharing: false,
+            ip: None,
+            hosted: false,
+            session: 0,
+            lag_count: 0,
+        }
+    }
+
+    /// A member connected to another instance, as that one wrote it
+    fn from_shared(stored: StoredMember) -> Self {
+        let (is_online, is_sharing) = (stored.is_online, stored.is_sharing);
+        Self {
+            is_online,
+            is_sharing,
+            ..Self::from_stored(stored)
+        }
+    }
+
+    pub fn to_stored(&self) -> StoredMember {
+        StoredMember {
+            token: self.token.clone(),
+            username: self.username.clone(),
+            last_seen: self.last_seen.get(),
+            is_online: self.is_online,
+            is_sharing: self.is_sharing,
         }
     }
 
@@ -228,15 +612,283 @@ impl Member {
         MemberInfo {
             username: self.username.clone(),
             is_online: self.is_online,
+            is_sharing: self.is_sharing,
         }
     }
 
     pub fn set_online(&mut self, online: bool) {
         self.is_online = online;
-        self.last_seen = Utc::now();
+        self.last_seen.mark();
+    }
+
+    /// Shared with the member's connection, which records pongs there without the rooms lock
+    pub fn seen_handle(&self) -> LastSeen {
+        self.last_seen.clone()
     }
 
     pub fn username(&self) -> &Username {
         &self.username
     }
+
+    pub fn is_online(&self) -> bool {
+        self.is_online
+    }
+
+    pub fn last_seen(&self) -> DateTime<Utc> {
+        self.last_seen.get()
+    }
+
+    /// Record that this member fell behind the room broadcast, returns the total lag count
+    pub fn record_lag(&mut self) -> u64 {
+        self.lag_count += 1;
+        self.lag_count
+    }
+}
+
+/// When a member was last heard from, in milliseconds since the epoch
+#[derive(Debug, Clone)]
+pub struct LastSeen(Arc<AtomicI64>);
+
+impl LastSeen {
+    fn new(at: DateTime<Utc>) -> Self {
+        Self(Arc::new(AtomicI64::new(at.timestamp_millis())))
+    }
+
+    /// Record that the member's connection is still alive
+    pub fn mark(&self) {
+        self.0
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.0.load(Ordering::Relaxed)).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn left_or_joined(rx: &mut broadcast::Receiver<BusEvent>) -> Vec<String> {
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            match event {
+                BusEvent::Broadcast {
+                    message: ServerMessage::MemberJoined { username },
+                } => events.push(format!("joined {username}")),
+                BusEvent::Broadcast {
+                    message: ServerMessage::MemberLeft { username },
+                } => events.push(format!("left {username}")),
+                _ => {}
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn reconnect_within_grace_period_is_silent() {
+        let mut room = Room::default();
+        let (_, token) = room.add_member("alice".parse().unwrap(), true).unwrap();
+        let (rx, old_session) = room.register_member_channel(&token).unwrap();
+        let mut events = room.subscribe();
+
+        // The connection drops, its channel closes before the grace period ends
+        drop(rx);
+        let (_, resumed) = room.login_member(&token).unwrap();
+        assert!(resumed);
+        let (_rx, _) = room.register_member_channel(&token).unwrap();
+
+        room.on_disconnect(&token, old_session, None);
+        assert!(room.members[&token].is_online);
+        assert!(left_or_joined(&mut events).is_empty());
+    }
+
+    #[test]
+    fn member_leaves_after_grace_period() {
+        let mut room = Room::default();
+        let (_, token) = room.add_member("alice".parse().unwrap(), true).unwrap();
+        let (rx, session) = room.register_member_channel(&token).unwrap();
+        let mut events = room.subscribe();
+
+        drop(rx);
+        room.on_disconnect(&token, session, None);
+        assert!(!room.members[&token].is_online);
+        assert_eq!(left_or_joined(&mut events), ["left alice"]);
+
+        let (_, resumed) = room.login_member(&token).unwrap();
+        assert!(!resumed);
+        assert_eq!(left_or_joined(&mut events), ["joined alice"]);
+    }
+
+    /// Local delivery posing as a bus shared with other instances
+    struct SharedBus(LocalBus);
+
+    impl RoomBus for SharedBus {
+        fn publish(&self, room_id: &RoomId, event: BusEvent) {
+            self.0.publish(room_id, event)
+        }
+
+        fn subscribe(&self, room_id: &RoomId) -> broadcast::Receiver<BusEvent> {
+            self.0.subscribe(room_id)
+        }
+
+        fn is_shared(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn relays_to_members_of_other_instances() {
+        let bob: Username = "bob".parse().unwrap();
+        let message = || ServerMessage::Chat {
+            from: "alice".parse().unwrap(),
+            message: "hi".to_string(),
+        };
+
+        let local = Room::default();
+        assert!(matches!(
+            local.relay(bob.as_str(), message()),
+            Err(ErrorKind::MemberNotFound { .. })
+        ));
+
+        let mut shared = Room::new(
+            None,
+            &argon2::Params::default(),
+            None,
+            10,
+            Arc::new(SharedBus(LocalBus::default())),
+        )
+        .unwrap();
+        assert!(matches!(
+            shared.relay(bob.as_str(), message()),
+            Err(ErrorKind::MemberNotFound { .. })
+        ));
+
+        // bob is online without a channel here, so connected elsewhere. carol is offline everywhere.
+        shared.add_member(bob.clone(), true).unwrap();
+        shared.add_member("carol".parse().unwrap(), false).unwrap();
+        let mut events = shared.subscribe();
+        shared.relay(bob.as_str(), message()).unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(BusEvent::Direct { to, .. }) if to == bob
+        ));
+        assert!(matches!(
+            shared.relay("carol", message()),
+            Err(ErrorKind::MemberOffline { .. })
+        ));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn live_session_is_not_resumed() {
+        let mut room = Room::default();
+        let (_, token) = room.add_member("alice".parse().unwrap(), true).unwrap();
+        let (_rx, _) = room.register_member_channel(&token).unwrap();
+
+        assert!(matches!(
+            room.login_member(&token),
+            Err(ErrorKind::TokenAlreadyInUse)
+        ));
+    }
+
+    fn chat(message: &str) -> ServerMessage {
+        ServerMessage::Chat {
+            from: "alice".parse().unwrap(),
+            message: message.to_string(),
+        }
+    }
+
+    fn direct_chat(rx: &mut mpsc::Receiver<RoomEvent>) -> Option<String> {
+        match rx.try_recv() {
+            Ok(RoomEvent::Direct(ServerMessage::Chat { message, .. })) => Some(message),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn relays_only_to_the_addressed_member() {
+        let mut room = Room::default();
+        let (_, alice) = room.add_member("alice".parse().unwrap(), true).unwrap();
+        let (_, bob) = room.add_member("bob".parse().unwrap(), true).unwrap();
+        let (mut alice_rx, _) = room.register_member_channel(&alice).unwrap();
+        let (mut bob_rx, _) = room.register_member_channel(&bob).unwrap();
+        let mut events = room.subscribe();
+
+        room.relay("bob", chat("hi bob")).unwrap();
+        assert_eq!(direct_chat(&mut bob_rx).as_deref(), Some("hi bob"));
+        assert_eq!(direct_chat(&mut alice_rx), None);
+        // Nothing goes through the room wide broadcast
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn relay_reports_unknown_and_offline_members() {
+        let mut room = Room::default();
+        room.add_member("bob".parse().unwrap(), false).unwrap();
+
+        assert!(matches!(
+            room.relay("carol", chat("hi")),
+            Err(ErrorKind::MemberNotFound { username }) if username == "carol"
+        ));
+        assert!(matches!(
+            room.relay("bob", chat("hi")),
+            Err(ErrorKind::MemberOffline { username }) if username == "bob"
+        ));
+    }
+
+    #[test]
+    fn tokens_are_never_relay_recipients() {
+        let mut room = Room::default();
+        let (_, token) = room.add_member("bob".parse().unwrap(), true).unwrap();
+        let (mut rx, _) = room.register_member_channel(&token).unwrap();
+
+        assert!(matches!(
+            room.relay(&token, chat("hi")),
+            Err(ErrorKind::MemberNotFound { .. })
+        ));
+        assert_eq!(direct_chat(&mut rx), None);
+
+        // Internal events still reach members by token
+        room.send_to(&token, RoomEvent::Direct(chat("by token")))
+            .unwrap();
+        assert_eq!(direct_chat(&mut rx).as_deref(), Some("by token"));
+    }
+
+    #[test]
+    fn drops_events_for_a_flooded_member() {
+        let mut room = Room::default();
+        let (_, token) = room.add_member("bob".parse().unwrap(), true).unwrap();
+        let (mut rx, _) = room.register_member_channel(&token).unwrap();
+
+        for i in 0..MEMBER_CHANNEL_CAPACITY + 10 {
+            room.relay("bob", chat(&i.to_string())).unwrap();
+        }
+        let mut received = 0;
+        while direct_chat(&mut rx).is_some() {
+            received += 1;
+        }
+        assert_eq!(received, MEMBER_CHANNEL_CAPACITY);
+    }
+
+    #[test]
+    fn ban_refuses_new_members_from_the_same_address() {
+        let mut room = Room::default();
+        let mallory_ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let (_, token) = room.add_member("mallory".parse().unwrap(), true).unwrap();
+        room.record_address(&token, mallory_ip);
+        room.remove_member(&"mallory".parse().unwrap(), true)
+            .unwrap();
+
+        assert!(matches!(room.login_member(&token), Err(ErrorKind::Banned)));
+        assert!(matches!(
+            room.add_member("mallory".parse().unwrap(), true),
+            Err(ErrorKind::Banned)
+        ));
+        assert!(matches!(
+            room.check_address(mallory_ip),
+            Err(ErrorKind::Banned)
+        ));
+        assert!(room.check_address("198.51.100.1".parse().unwrap()).is_ok());
+    }
 }
@@ -0,0 +1,666 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use inpixly_shared::{SignalingPayload, Username};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+use webrtc::{
+    api::{
+        API, APIBuilder, interceptor_registry::register_default_interceptors,
+        media_engine::MediaEngine, setting_engine::SettingEngine,
+    },
+    ice_transport::{ice_candidate::RTCIceCandidateInit, ice_candidate_type::RTCIceCandidateType},
+    interceptor::registry::Registry,
+    peer_connection::{
+        RTCPeerConnection, configuration::RTCConfiguration,
+        sdp::session_description::RTCSessionDescription, signaling_state::RTCSignalingState,
+    },
+    rtcp::payload_feedbacks::{
+        full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+    },
+    rtp_transceiver::rtp_sender::RTCRtpSender,
+    track::{
+        track_local::{TrackLocal, TrackLocalWriter, track_local_static_rtp::TrackLocalStaticRTP},
+        track_remote::TrackRemote,
+    },
+};
+
+use crate::config::SfuConfig;
+
+/// Delivers signaling messages from the SFU to a single peer
+pub type SignalSink = Arc<dyn Fn(SignalingPayload) + Send + Sync>;
+
+/// Selective forwarding unit of a single room.
+/// Every member holds one peer connection to it, RTP received from a publisher
+/// is forwarded to all other peers.
+pub struct Sfu {
+    api: API,
+    peers: Mutex<HashMap<String, Arc<SfuPeer>>>,
+    tracks: Mutex<Vec<Arc<ForwardedTrack>>>,
+}
+
+impl Sfu {
+    pub fn new(config: &SfuConfig) -> anyhow::Result<Self> {
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs()?;
+        let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+
+        let mut setting_engine = SettingEngine::default();
+        if !config.public_ips.is_empty() {
+            setting_engine.set_nat_1to1_ips(config.public_ips.clone(), RTCIceCandidateType::Host);
+        }
+        setting_engine.set_include_loopback_candidate(config.include_loopback);
+
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(setting_engine)
+            .build();
+
+        Ok(Self {
+            api,
+            peers: Mutex::new(HashMap::new()),
+            tracks: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Handle a signaling message from a peer.
+    /// The peer connection is created on its first offer, `sink` is used to reply.
+    pub async fn signal(
+        self: &Arc<Self>,
+        peer_id: &str,
+        username: &Username,
+        payload: SignalingPayload,
+        sink: SignalSink,
+    ) -> anyhow::Result<()> {
+        let peer = match payload {
+            SignalingPayload::Offer { ref sdp } => {
+                // A reloaded page resumes the member's session with a new peer connection
+                if self.is_replaced(peer_id, sdp).await {
+                    self.remove_peer(peer_id).await;
+                }
+                self.get_or_create_peer(peer_id, username, sink).await?
+            }
+            _ => match self.peers.lock().await.get(peer_id) {
+                Some(peer) => Arc::clone(peer),
+                None => {
+                    debug!(peer_id, "Ignoring signaling for unknown SFU peer.");
+                    return Ok(());
+                }
+            },
+        };
+
+        match payload {
+            SignalingPayload::Offer { sdp } => {
+                let _negotiation = peer.negotiation.lock().await;
+                if peer.pc.signaling_state() != RTCSignalingState::Stable {
+                    // The server is the impolite side, the client rolls back its own offer
+                    debug!(peer_id, "Ignoring colliding offer.");
+                    return Ok(());
+                }
+                peer.pc
+                    .set_remote_description(RTCSessionDescription::offer(sdp)?)
+                    .await?;
+                let answer = peer.pc.create_answer(None).await?;
+                peer.pc.set_local_description(answer.clone()).await?;
+                peer.send(SignalingPayload::Answer { sdp: answer.sdp });
+            }
+            SignalingPayload::Answer { sdp } => {
+                let _negotiation = peer.negotiation.lock().await;
+                peer.pc
+                    .set_remote_description(RTCSessionDescription::answer(sdp)?)
+                    .await?;
+            }
+            SignalingPayload::IceCandidate { candidate } => {
+                peer.pc
+                    .add_ice_candidate(RTCIceCandidateInit {
+                        candidate,
+                        sdp_mid: Some("0".to_string()),
+                        sdp_mline_index: Some(0),
+                        username_fragment: None,
+                    })
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        if peer.renegotiate.load(Ordering::SeqCst) {
+            peer.negotiate().await?;
+        }
+        Ok(())
+    }
+
+    /// Close the connection of a peer and stop forwarding everything it published
+    pub async fn remove_peer(&self, peer_id: &str) {
+        let Some(peer) = self.peers.lock().await.remove(peer_id) else {
+            return;
+        };
+        let published: Vec<String> = self
+            .tracks
+            .lock()
+            .await
+            .iter()
+            .filter(|t| t.publisher_id == peer_id)
+            .map(|t| t.id.clone())
+            .collect();
+        for track_id in published {
+            self.unpublish(&track_id).await;
+        }
+        if let Err(err) = peer.pc.close().await {
+            warn!(peer_id, "Failed to close SFU peer connection: {err}");
+        }
+        info!(username = %peer.username, "SFU peer removed.");
+    }
+
+    /// Whether an offer comes from another peer connection than the one already known,
+    /// a renegotiation keeps the ICE credentials
+    async fn is_replaced(&self, peer_id: &str, offer: &str) -> bool {
+        let Some(peer) = self.peers.lock().await.get(peer_id).cloned() else {
+            return false;
+        };
+        match peer.pc.current_remote_description().await {
+            Some(current) => ice_ufrag(&current.sdp) != ice_ufrag(offer),
+            None => false,
+        }
+    }
+
+    /// Send further signaling for a peer through `sink`, once its member resumed
+    /// the session on a new connection
+    pub async fn reattach(&self, peer_id: &str, sink: SignalSink) {
+        if let Some(peer) = self.peers.lock().await.get(peer_id) {
+            *peer.signal.lock().unwrap() = sink;
+        }
+    }
+
+    /// Offer the tracks currently forwarded to a peer again, in case it missed an update
+    pub async fn renegotiate(&self, peer_id: &str) -> anyhow::Result<()> {
+        let peer = self.peers.lock().await.get(peer_id).cloned();
+        match peer {
+            Some(peer) => peer.negotiate().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn get_or_create_peer(
+        self: &Arc<Self>,
+        peer_id: &str,
+        username: &Username,
+        sink: SignalSink,
+    ) -> anyhow::Result<Arc<SfuPeer>> {
+        let mut peers = self.peers.lock().await;
+        if let Some(peer) = peers.get(peer_id) {
+            return Ok(Arc::clone(peer));
+        }
+
+        let pc = Arc::new(
+            self.api
+                .new_peer_connection(RTCConfiguration::default())
+                .await?,
+        );
+
+        let signal = Arc::new(std::sync::Mutex::new(sink));
+        let candidate_signal = Arc::clone(&signal);
+        pc.on_ice_candidate(Box::new(move |candidate| {
+            let signal = candidate_signal.lock().unwrap().clone();
+            Box::pin(async move {
+                let Some(candidate) = candidate else {
+                    return;
+                };
+                match candidate.to_json() {
+                    Ok(init) => signal(SignalingPayload::IceCandidate {
+                        candidate: init.candidate,
+                    }),
+                    Err(err) => warn!("Failed to serialize ICE candidate: {err}"),
+                }
+            })
+        }));
+
+        let sfu = Arc::downgrade(self);
+        let publisher = Arc::downgrade(&pc);
+        let publisher_id = peer_id.to_string();
+        let stream_id = username.to_string();
+        pc.on_track(Box::new(move |remote, _, _| {
+            if let Some(sfu) = sfu.upgrade() {
+                tokio::spawn(sfu.forward_track(
+                    publisher_id.clone(),
+                    stream_id.clone(),
+                    publisher.clone(),
+                    remote,
+                ));
+            }
+            Box::pin(async {})
+        }));
+
+        let peer = Arc::new(SfuPeer {
+            username: username.clone(),
+            pc,
+            signal,
+            senders: Mutex::new(HashMap::new()),
+            negotiation: Mutex::new(()),
+            renegotiate: AtomicBool::new(false),
+        });
+        peers.insert(peer_id.to_string(), Arc::clone(&peer));
+        drop(peers);
+
+        info!(%username, "SFU peer connected.");
+
+        // Subscribe the new peer to everything that is already being published
+        let tracks = self.tracks.lock().await.clone();
+        for track in tracks {
+            peer.subscribe(&track).await?;
+        }
+        Ok(peer)
+    }
+
+    /// Forward RTP of a published track to all other peers until the track ends
+    async fn forward_track(
+        self: Arc<Self>,
+        publisher_id: String,
+        stream_id: String,
+        publisher: Weak<RTCPeerConnection>,
+        remote: Arc<TrackRemote>,
+    ) {
+        let local = Arc::new(TrackLocalStaticRTP::new(
+            remote.codec().capability,
+            remote.id(),
+            stream_id,
+        ));
+        let track = Arc::new(ForwardedTrack {
+            id: local.id().to_string(),
+            publisher_id,
+            publisher,
+            media_ssrc: remote.ssrc(),
+            local,
+        });
+        info!(stream_id = %track.local.stream_id(), kind = %remote.kind(), "Forwarding published track.");
+
+        self.tracks.lock().await.push(Arc::clone(&track));
+        let subscribers: Vec<Arc<SfuPeer>> = self
+            .peers
+            .lock()
+            .await
+            .iter()
+            .filter(|(id, _)| **id != track.publisher_id)
+            .map(|(_, peer)| Arc::clone(peer))
+            .collect();
+        for peer in subscribers {
+            if let Err(err) = peer.subscribe(&track).await {
+                warn!(username = %peer.username, "Failed to subscribe to track: {err}");
+            }
+        }
+
+        while let Ok((packet, _)) = remote.read_rtp().await {
+            if let Err(err) = track.local.write_rtp(&packet).await {
+                debug!("Failed to forward RTP packet: {err}");
+            }
+        }
+
+        self.unpublish(&track.id).await;
+    }
+
+    async fn unpublish(&self, track_id: &str) {
+        let mut tracks = self.tracks.lock().await;
+        let Some(position) = tracks.iter().position(|t| t.id == track_id) else {
+            return;
+        };
+        tracks.remove(position);
+        drop(tracks);
+
+        let peers: Vec<Arc<SfuPeer>> = self.peers.lock().await.values().cloned().collect();
+        for peer in peers {
+            if let Err(err) = peer.unsubscribe(track_id).await {
+                warn!(username = %peer.username, "Failed to unsubscribe from track: {err}");
+            }
+        }
+    }
+}
+
+struct SfuPeer {
+    username: Username,
+    pc: Arc<RTCPeerConnection>,
+    /// Replaced when the member resumes its session on a new connection
+    signal: Arc<std::sync::Mutex<SignalSink>>,
+    /// Senders of the tracks forwarded to this peer, keyed by track id
+    senders: Mutex<HashMap<String, Arc<RTCRtpSender>>>,
+    /// Serializes offer/answer exchanges with this peer
+    negotiation: Mutex<()>,
+    /// Set when local tracks changed while an exchange was in progress
+    renegotiate: AtomicBool,
+}
+
+impl SfuPeer {
+    fn send(&self, payload: SignalingPayload) {
+        let signal = self.signal.lock().unwrap().clone();
+        signal(payload);
+    }
+    /// Send a fresh offer, or defer it until the current exchange completes
+    async fn negotiate(&self) -> anyhow::Result<()> {
+        let _negotiation = self.negotiation.lock().await;
+        self.renegotiate.store(true, Ordering::SeqCst);
+        if self.pc.signaling_state() != RTCSignalingState::Stable
+            || self.pc.current_remote_description().await.is_none()
+        {
+            return Ok(());
+        }
+        self.renegotiate.store(false, Ordering::SeqCst);
+
+        let offer = self.pc.create_offer(None).await?;
+        self.pc.set_local_description(offer.clone()).await?;
+        self.send(SignalingPayload::Offer { sdp: offer.sdp });
+        Ok(())
+    }
+
+    async fn subscribe(&self, track: &ForwardedTrack) -> anyhow::Result<()> {
+        let sender = self
+            .pc
+            .add_track(Arc::clone(&track.local) as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+        self.senders
+            .lock()
+            .await
+            .insert(track.id.clone(), Arc::clone(&sender));
+
+        // Relay keyframe requests of this subscriber to the publisher
+        let publisher = track.publisher.clone();
+        let media_ssrc = track.media_ssrc;
+        tokio::spawn(async move {
+            while let Ok((packets, _)) = sender.read_rtcp().await {
+                let wants_keyframe = packets.iter().any(|p| {
+                    p.as_any().is::<PictureLossIndication>() || p.as_any().is::<FullIntraRequest>()
+                });
+                if wants_keyframe {
+                    request_keyframe(&publisher, media_ssrc).await;
+                }
+            }
+        });
+        request_keyframe(&track.publisher, track.media_ssrc).await;
+
+        self.negotiate().await
+    }
+
+    async fn unsubscribe(&self, track_id: &str) -> anyhow::Result<()> {
+        let Some(sender) = self.senders.lock().await.remove(track_id) else {
+            return Ok(());
+        };
+        self.pc.remove_track(&sender).await?;
+        self.negotiate().await
+    }
+}
+
+struct ForwardedTrack {
+    id: String,
+    publisher_id: String,
+    publisher: Weak<RTCPeerConnection>,
+    media_ssrc: u32,
+    local: Arc<TrackLocalStaticRTP>,
+}
+
+fn ice_ufrag(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|line| line.strip_prefix("a=ice-ufrag:"))
+        .map(str::trim)
+}
+
+/// Ask the publisher for a keyframe so new subscribers can start decoding
+async fn request_keyframe(publisher: &Weak<RTCPeerConnection>, media_ssrc: u32) {
+    let Some(publisher) = publisher.upgrade() else {
+        return;
+    };
+    let pli = PictureLossIndication {
+        sender_ssrc: 0,
+        media_ssrc,
+    };
+    if let Err(err) = publisher.write_rtcp(&[Box::new(pli)]).await {
+        debug!("Failed to request keyframe: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+    use webrtc::{
+        api::media_engine::MIME_TYPE_VP8, media::Sample,
+        rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+        track::track_local::track_local_static_sample::TrackLocalStaticSample,
+    };
+
+    use super::*;
+
+    /// Headless client holding one peer connection to the SFU
+    struct Client {
+        sfu: Arc<Sfu>,
+        peer_id: String,
+        username: Username,
+        pc: Arc<RTCPeerConnection>,
+        sink: SignalSink,
+    }
+
+    impl Client {
+        async fn new(sfu: &Arc<Sfu>, peer_id: &str, username: &str) -> Self {
+            let pc = Arc::new(
+                sfu.api
+                    .new_peer_connection(RTCConfiguration::default())
+                    .await
+                    .unwrap(),
+            );
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let sink: SignalSink = Arc::new(move |payload| {
+                let _ = tx.send(payload);
+            });
+            let username: Username = username.parse().unwrap();
+
+            // Apply everything the SFU sends and answer its renegotiation offers
+            let client_pc = Arc::clone(&pc);
+            let client_sfu = Arc::clone(sfu);
+            let client_peer_id = peer_id.to_string();
+            let client_username = username.clone();
+            let client_sink = Arc::clone(&sink);
+            tokio::spawn(async move {
+                while let Some(payload) = rx.recv().await {
+                    match payload {
+                        SignalingPayload::Offer { sdp } => {
+                            let offer = RTCSessionDescription::offer(sdp).unwrap();
+                            client_pc.set_remote_description(offer).await.unwrap();
+                            let answer = client_pc.create_answer(None).await.unwrap();
+                            client_pc
+                                .set_local_description(answer.clone())
+                                .await
+                                .unwrap();
+                            client_sfu
+                                .signal(
+                                    &client_peer_id,
+                                    &client_username,
+                                    SignalingPayload::Answer { sdp: answer.sdp },
+                                    Arc::clone(&client_sink),
+                                )
+                                .await
+                                .unwrap();
+                        }
+                        SignalingPayload::Answer { sdp } => {
+                            let answer = RTCSessionDescription::answer(sdp).unwrap();
+                            client_pc.set_remote_description(answer).await.unwrap();
+                        }
+                        SignalingPayload::IceCandidate { candidate } => {
+                            let _ = client_pc
+                                .add_ice_candidate(RTCIceCandidateInit {
+                                    candidate,
+                                    sdp_mid: Some("0".to_string()),
+                                    sdp_mline_index: Some(0),
+                                    username_fragment: None,
+                                })
+                                .await;
+                        }
+                    }
+                }
+            });
+
+            Self {
+                sfu: Arc::clone(sfu),
+                peer_id: peer_id.to_string(),
+                username,
+                pc,
+                sink,
+            }
+        }
+
+        /// Offer with all candidates gathered up front, so the client never trickles
+        async fn connect(&self) {
+            let offer = self.pc.create_offer(None).await.unwrap();
+            let mut gathered = self.pc.gathering_complete_promise().await;
+            self.pc.set_local_description(offer).await.unwrap();
+            let _ = gathered.recv().await;
+            let sdp = self.pc.local_description().await.unwrap().sdp;
+            self.sfu
+                .signal(
+                    &self.peer_id,
+                    &self.username,
+                    SignalingPayload::Offer { sdp },
+                    Arc::clone(&self.sink),
+                )
+                .await
+                .unwrap();
+        }
+    }
+
+    fn sfu() -> Arc<Sfu> {
+        let config = SfuConfig {
+            include_loopback: true,
+            ..Default::default()
+        };
+        Arc::new(Sfu::new(&config).unwrap())
+    }
+
+    /// Publish a VP8 track and keep writing samples into it
+    async fn publish(client: &Client) {
+        let track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_VP8.to_string(),
+                ..Default::default()
+            },
+            "screen".to_string(),
+            "local".to_string(),
+        ));
+        client
+            .pc
+            .add_track(Arc::clone(&track) as Arc<dyn TrackLocal + Send + Sync>)
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            loop {
+                let sample = Sample {
+                    data: vec![0u8; 64].into(),
+                    duration: Duration::from_millis(33),
+                    ..Default::default()
+                };
+                if track.write_sample(&sample).await.is_err() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(33)).await;
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn forwards_published_track_to_subscriber() {
+        let sfu = sfu();
+
+        let viewer = Client::new(&sfu, "viewer", "bob").await;
+        viewer.pc.create_data_channel("sfu", None).await.unwrap();
+        let (track_tx, mut track_rx) = mpsc::unbounded_channel();
+        viewer.pc.on_track(Box::new(move |remote, _, _| {
+            let track_tx = track_tx.clone();
+            Box::pin(async move {
+                let stream_id = remote.stream_id();
+                let received = remote.read_rtp().await.is_ok();
+                let _ = track_tx.send((stream_id, received));
+            })
+        }));
+        viewer.connect().await;
+
+        let sharer = Client::new(&sfu, "sharer", "alice").await;
+        publish(&sharer).await;
+        sharer.connect().await;
+
+        let (stream_id, received) = tokio::time::timeout(Duration::from_secs(20), track_rx.recv())
+            .await
+            .expect("subscriber never received the forwarded track")
+            .unwrap();
+        assert_eq!(stream_id, "alice");
+        assert!(received);
+    }
+
+    #[tokio::test]
+    async fn removing_publisher_unpublishes_its_tracks() {
+        let sfu = sfu();
+
+        let sharer = Client::new(&sfu, "sharer", "alice").await;
+        publish(&sharer).await;
+        sharer.connect().await;
+
+        tokio::time::timeout(Duration::from_secs(20), async {
+            while sfu.tracks.lock().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("published track never reached the SFU");
+
+        sfu.remove_peer("sharer").await;
+        assert!(sfu.tracks.lock().await.is_empty());
+        assert!(sfu.peers.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reattached_peer_signals_through_the_new_connection() {
+        let sfu = sfu();
+
+        let viewer = Client::new(&sfu, "viewer", "bob").await;
+        viewer.pc.create_data_channel("sfu", None).await.unwrap();
+        viewer.connect().await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        sfu.reattach(
+            "viewer",
+            Arc::new(move |payload| {
+                let _ = tx.send(payload);
+            }),
+        )
+        .await;
+
+        let sharer = Client::new(&sfu, "sharer", "alice").await;
+        publish(&sharer).await;
+        sharer.connect().await;
+
+        let payload = tokio::time::timeout(Duration::from_secs(20), async {
+            loop {
+                match rx.recv().await.unwrap() {
+                    SignalingPayload::Offer { .. } => break,
+                    SignalingPayload::IceCandidate { .. } | SignalingPayload::Answer { .. } => {}
+                }
+            }
+        })
+        .await;
+        assert!(payload.is_ok(), "the new track was never offered");
+    }
+
+    #[tokio::test]
+    async fn offer_from_a_new_connection_replaces_the_peer() {
+        let sfu = sfu();
+
+        let before = Client::new(&sfu, "viewer", "bob").await;
+        before.pc.create_data_channel("sfu", None).await.unwrap();
+        before.connect().await;
+        let peer = Arc::clone(&sfu.peers.lock().await["viewer"]);
+
+        let after = Client::new(&sfu, "viewer", "bob").await;
+        after.pc.create_data_channel("sfu", None).await.unwrap();
+        after.connect().await;
+        assert!(!Arc::ptr_eq(&peer, &sfu.peers.lock().await["viewer"]));
+    }
+}
@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use inpixly_shared::ServerMessage;
+use tokio::time::{Instant, sleep};
+use tracing::{info, warn};
+
+use crate::{config::ShutdownConfig, state::AppState};
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Resolves on SIGINT or SIGTERM, after telling every connected member the server goes down.
+/// Meant as the graceful shutdown trigger of the listener.
+pub async fn signal(state: AppState, config: ShutdownConfig) {
+    wait_for_signal().await;
+    info!("Shutting down, notifying connected members.");
+    let rooms = state.rooms.read().await;
+    for room in rooms.values() {
+        // Members connected to other instances stay, only ours have to reconnect
+        room.notify_connected(ServerMessage::ServerGoingDown {
+            reconnect_after_secs: config.restart_hint_secs,
+        });
+    }
+}
+
+/// Wait for the notified connections to close, at most the drain period, then persist every room
+pub async fn drain(state: &AppState, config: &ShutdownConfig) {
+    let deadline = Instant::now() + Duration::from_secs(config.drain_secs);
+    while state.metrics.open_connections() > 0 && Instant::now() < deadline {
+        sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    let open = state.metrics.open_connections();
+    if open > 0 {
+        warn!("Drain period over with {open} connections still open.");
+    }
+    persist(state).await;
+}
+
+/// Save every room and wait until the store has them, for when the listener already drained
+pub async fn persist(state: &AppState) {
+    let saved = {
+        let rooms = state.rooms.read().await;
+        for room in rooms.values() {
+            state.save_room(room);
+        }
+        rooms.len()
+    };
+    state.flush_store().await;
+    info!("Saved {saved} rooms, exiting.");
+}
+
+async fn wait_for_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for SIGINT: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room::Room;
+
+    #[tokio::test]
+    async fn drain_ends_once_connections_close() {
+        let state = AppState::default();
+        let config = ShutdownConfig {
+            drain_secs: 30,
+            restart_hint_secs: None,
+        };
+        let connection = state.metrics.connection_opened();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(200)).await;
+            drop(connection);
+        });
+        let started = Instant::now();
+        drain(&state, &config).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn drain_persists_rooms() {
+        let state = AppState::default();
+        let room = Room::default();
+        let room_id = room.id.clone();
+        state.rooms.write().await.insert(room_id.clone(), room);
+
+        drain(&state, &ShutdownConfig::default()).await;
+        let stored = state.store.load_rooms().unwrap();
+        assert!(stored.iter().any(|room| room.id == room_id));
+    }
+}
@@ -1,25 +1,181 @@
-use crate::room::Room;
-use inpixly_shared::RoomId;
+use crate::{
+    bus::{LocalBus, RoomBus},
+    config::{
+        ChatConfig, Config, HeartbeatConfig, IceConfig, ProtocolConfig, SessionConfig, SfuConfig,
+    },
+    metrics::Metrics,
+    rate_limit::RateLimits,
+    room::Room,
+    sfu::Sfu,
+    storage::{MemoryStore, RoomStore, StoreWriter, StoredMember, StoredRoom},
+};
+use inpixly_shared::{RoomId, RoomMode};
 use std::{collections::HashMap, sync::Arc};
 use tokio::sync::RwLock;
+use tracing::{error, info};
 
 pub type Rooms = Arc<RwLock<HashMap<RoomId, Room>>>;
 
 #[derive(Clone)]
 pub struct AppState {
     pub rooms: Rooms,
+    pub sfu_config: Arc<SfuConfig>,
+    pub chat_config: Arc<ChatConfig>,
+    pub heartbeat_config: Arc<HeartbeatConfig>,
+    pub protocol_config: Arc<ProtocolConfig>,
+    pub session_config: Arc<SessionConfig>,
+    pub ice_config: Arc<IceConfig>,
+    /// Cost of newly hashed room passwords
+    pub password_params: argon2::Params,
+    pub rate_limits: Arc<RateLimits>,
+    pub store: Arc<dyn RoomStore>,
+    /// Persists rooms in the background, see [`AppState::save_room`]
+    writer: StoreWriter,
+    pub bus: Arc<dyn RoomBus>,
+    pub metrics: Arc<Metrics>,
 }
 
 impl AppState {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(
+        config: &Config,
+        store: Arc<dyn RoomStore>,
+        bus: Arc<dyn RoomBus>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
             rooms: Arc::new(RwLock::new(HashMap::new())),
+            sfu_config: Arc::new(config.sfu.clone()),
+            chat_config: Arc::new(config.chat.clone()),
+            heartbeat_config: Arc::new(config.heartbeat.clone()),
+            protocol_config: Arc::new(config.protocol.clone()),
+            session_config: Arc::new(config.session.clone()),
+            ice_config: Arc::new(config.ice.clone()),
+            password_params: config.password_hash.params()?,
+            rate_limits: Arc::new(RateLimits::new(&config.rate_limit)),
+            writer: StoreWriter::spawn(store.clone()),
+            store,
+            bus,
+            metrics: Arc::new(Metrics::new()),
+        })
+    }
+
+    /// Create the state with all rooms kept by the store
+    pub fn load(
+        config: &Config,
+        store: Arc<dyn RoomStore>,
+        bus: Arc<dyn RoomBus>,
+    ) -> anyhow::Result<Self> {
+        let state = Self::new(config, store, bus)?;
+        let mut rooms = HashMap::new();
+        for stored in state.store.load_rooms()? {
+            let room = state.room_from_stored(stored)?;
+            rooms.insert(room.id.clone(), room);
+        }
+        info!("Loaded {} rooms from storage", rooms.len());
+        Ok(Self {
+            rooms: Arc::new(RwLock::new(rooms)),
+            ..state
+        })
+    }
+
+    fn room_from_stored(&self, stored: StoredRoom) -> anyhow::Result<Room> {
+        let sfu = match stored.mode {
+            RoomMode::Mesh => None,
+            RoomMode::Sfu => Some(Arc::new(Sfu::new(&self.sfu_config)?)),
+        };
+        Ok(Room::from_stored(
+            stored,
+            sfu,
+            self.chat_config.history_size,
+            self.bus.clone(),
+        ))
+    }
+
+    /// Whether the room exists. With a shared bus the room is brought up to date with the
+    /// store first, other instances may have created it or changed its members.
+    pub async fn sync_room(&self, room_id: &RoomId) -> bool {
+        if !self.bus.is_shared() {
+            return self.rooms.read().await.contains_key(room_id);
+        }
+        let store = self.store.clone();
+        let id = room_id.clone();
+        let stored = match tokio::task::spawn_blocking(move || store.load_room(&id)).await {
+            Ok(Ok(stored)) => stored,
+            Ok(Err(e)) => {
+                error!("Failed to load room {room_id}: {e:#}");
+                return self.rooms.read().await.contains_key(room_id);
+            }
+            Err(e) => {
+                error!("Failed to load room {room_id}: {e}");
+                return self.rooms.read().await.contains_key(room_id);
+            }
+        };
+        let mut rooms = self.rooms.write().await;
+        let Some(stored) = stored else {
+            // Deleted by another instance
+            rooms.remove(room_id);
+            return false;
+        };
+        match rooms.get_mut(room_id) {
+            Some(room) => room.merge_stored(stored),
+            None => match self.room_from_stored(stored) {
+                Ok(room) => {
+                    rooms.insert(room_id.clone(), room);
+                }
+                Err(e) => {
+                    error!("Failed to load room {room_id}: {e:#}");
+                    return false;
+                }
+            },
         }
+        true
+    }
+
+    /// Write a new member to the store right away, unless another instance sharing it
+    /// took the username in the meantime. Returns whether the member was written.
+    pub async fn claim_member(&self, room_id: &RoomId, member: StoredMember) -> bool {
+        let store = self.store.clone();
+        let id = room_id.clone();
+        match tokio::task::spawn_blocking(move || store.add_member(&id, &member)).await {
+            Ok(Ok(claimed)) => claimed,
+            Ok(Err(e)) => {
+                error!("Failed to add member to room {room_id}: {e:#}");
+                false
+            }
+            Err(e) => {
+                error!("Failed to add member to room {room_id}: {e}");
+                false
+            }
+        }
+    }
+
+    /// Queue the current state of a room for the store.
+    /// Only takes a snapshot, so it is fine to call with the rooms locked.
+    pub fn save_room(&self, room: &Room) {
+        self.writer.save(room.to_stored());
+    }
+
+    pub fn delete_member(&self, room_id: &RoomId, token: &str) {
+        self.writer
+            .delete_member(room_id.clone(), token.to_string());
+    }
+
+    pub fn delete_room(&self, room_id: &RoomId) {
+        self.writer.delete(room_id.clone());
+    }
+
+    /// Wait for every queued save and delete to reach the store
+    pub async fn flush_store(&self) {
+        self.writer.flush().await;
     }
 }
 
 impl Default for AppState {
     fn default() -> Self {
-        Self::new()
+        Self::new(
+            &Config::default(),
+            Arc::new(MemoryStore::default()),
+            Arc::new(LocalBus::default()),
+        )
+        .expect("default config is valid")
     }
 }
@@ -0,0 +1,77 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use inpixly_shared::RoomId;
+
+use super::{RoomStore, StoredMember, StoredRoom};
+
+/// Keeps rooms only for the lifetime of the process
+#[derive(Default)]
+pub struct MemoryStore {
+    rooms: Mutex<HashMap<RoomId, StoredRoom>>,
+}
+
+impl RoomStore for MemoryStore {
+    fn load_rooms(&self) -> anyhow::Result<Vec<StoredRoom>> {
+        Ok(self.rooms.lock().unwrap().values().cloned().collect())
+    }
+
+    fn load_room(&self, room_id: &RoomId) -> anyhow::Result<Option<StoredRoom>> {
+        Ok(self.rooms.lock().unwrap().get(room_id).cloned())
+    }
+
+    fn save_room(&self, room: &StoredRoom) -> anyhow::Result<()> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(stored) = rooms.get_mut(&room.id) else {
+            rooms.insert(room.id.clone(), room.clone());
+            return Ok(());
+        };
+        let (members, bans) = (
+            std::mem::take(&mut stored.members),
+            std::mem::take(&mut stored.bans),
+        );
+        let last_activity = stored.last_activity.max(room.last_activity);
+        *stored = StoredRoom {
+            last_activity,
+            ..room.clone()
+        };
+        for member in members {
+            if !stored.members.iter().any(|m| m.token == member.token) {
+                stored.members.push(member);
+            }
+        }
+        for ban in bans {
+            if !stored.bans.iter().any(|b| b.token == ban.token) {
+                stored.bans.push(ban);
+            }
+        }
+        Ok(())
+    }
+
+    fn add_member(&self, room_id: &RoomId, member: &StoredMember) -> anyhow::Result<bool> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.get_mut(room_id) else {
+            return Ok(false);
+        };
+        if room
+            .members
+            .iter()
+            .any(|m| m.token == member.token || m.username == member.username)
+        {
+            return Ok(false);
+        }
+        room.members.push(member.clone());
+        Ok(true)
+    }
+
+    fn delete_member(&self, room_id: &RoomId, token: &str) -> anyhow::Result<()> {
+        if let Some(room) = self.rooms.lock().unwrap().get_mut(room_id) {
+            room.members.retain(|m| m.token != token);
+        }
+        Ok(())
+    }
+
+    fn delete_room(&self, room_id: &RoomId) -> anyhow::Result<()> {
+        self.rooms.lock().unwrap().remove(room_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+mod memory;
+mod sqlite;
+mod writer;
+
+use std::{net::IpAddr, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use inpixly_shared::{RoomId, RoomMode, Username};
+
+use crate::{config::StorageConfig, password::HashedPassword};
+
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+pub use writer::StoreWriter;
+
+/// Snapshot of a room as kept by a [`RoomStore`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredRoom {
+    pub id: RoomId,
+    pub owner_token: String,
+    pub password: Option<HashedPassword>,
+    pub mode: RoomMode,
+    pub last_activity: DateTime<Utc>,
+    pub members: Vec<StoredMember>,
+    pub bans: Vec<StoredBan>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMember {
+    pub token: String,
+    pub username: Username,
+    pub last_seen: DateTime<Utc>,
+    /// Presence as last written by the instance the member is connected to,
+    /// for other instances sharing the store
+    pub is_online: bool,
+    pub is_sharing: bool,
+}
+
+/// Former token, username and address of a banned member
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredBan {
+    pub token: String,
+    pub username: Username,
+    /// Where the member was connected from when banned
+    pub ip: Option<IpAddr>,
+}
+
+/// Backend that keeps rooms across server restarts.
+///
+/// Instances sharing rooms over a bus share the store as well, it is where they learn about
+/// rooms and members of each other.
+pub trait RoomStore: Send + Sync {
+    /// Load every stored room
+    fn load_rooms(&self) -> anyhow::Result<Vec<StoredRoom>>;
+
+    fn load_room(&self, room_id: &RoomId) -> anyhow::Result<Option<StoredRoom>>;
+
+    /// Insert or update a room together with the given members and bans.
+    /// Members left out are kept, another instance may have written them.
+    fn save_room(&self, room: &StoredRoom) -> anyhow::Result<()>;
+
+    /// Insert a new member unless its username is taken in the room, returns whether it was
+    fn add_member(&self, room_id: &RoomId, member: &StoredMember) -> anyhow::Result<bool>;
+
+    fn delete_member(&self, room_id: &RoomId, token: &str) -> anyhow::Result<()>;
+
+    fn delete_room(&self, room_id: &RoomId) -> anyhow::Result<()>;
+}
+
+/// Open the storage backend selected in the config,
+/// `password_params` hash passwords that older versions stored in plain text
+pub fn open(
+    config: &StorageConfig,
+    password_params: &argon2::Params,
+) -> anyhow::Result<Arc<dyn RoomStore>> {
+    Ok(match config {
+        StorageConfig::Memory => Arc::new(MemoryStore::default()),
+        StorageConfig::Sqlite { path } => Arc::new(SqliteStore::open(path, password_params)?),
+    })
+}
@@ -0,0 +1,443 @@
+use std::{path::Path, sync::Mutex};
+
+use anyhow::{Context, anyhow};
+use chrono::{DateTime, Utc};
+use inpixly_shared::Password;
+use inpixly_shared::{RoomId, RoomMode};
+use rusqlite::{Connection, Transaction, params};
+
+use super::{RoomStore, StoredBan, StoredMember, StoredRoom};
+use crate::password::HashedPassword;
+
+/// Migrations from each schema version to the next, `PRAGMA user_version` counts those applied
+const MIGRATIONS: &[Migration] = &[
+    create_tables,
+    hash_plaintext_passwords,
+    add_ban_addresses,
+    share_members,
+];
+
+type Migration = fn(&Transaction, &argon2::Params) -> anyhow::Result<()>;
+
+const TABLES: &str = "
+CREATE TABLE IF NOT EXISTS rooms (
+    id TEXT PRIMARY KEY,
+    owner_token TEXT NOT NULL,
+    password TEXT,
+    mode TEXT NOT NULL,
+    last_activity TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS members (
+    room_id TEXT NOT NULL,
+    token TEXT NOT NULL,
+    username TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    PRIMARY KEY (room_id, token)
+);
+CREATE TABLE IF NOT EXISTS bans (
+    room_id TEXT NOT NULL,
+    token TEXT NOT NULL,
+    username TEXT NOT NULL,
+    PRIMARY KEY (room_id, token)
+);
+";
+
+fn create_tables(tx: &Transaction, _: &argon2::Params) -> anyhow::Result<()> {
+    tx.execute_batch(TABLES)?;
+    Ok(())
+}
+
+/// Databases written before passwords were hashed keep them in plain text
+fn hash_plaintext_passwords(tx: &Transaction, params: &argon2::Params) -> anyhow::Result<()> {
+    let passwords = tx
+        .prepare("SELECT id, password FROM rooms WHERE password IS NOT NULL")?
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (id, password) in passwords {
+        if HashedPassword::from_phc(password.clone()).is_ok() {
+            continue;
+        }
+        let password: Password = password
+            .parse()
+            .with_context(|| format!("invalid password of room {id}"))?;
+        let hash = HashedPassword::new(&password, params)?;
+        tx.execute(
+            "UPDATE rooms SET password = ?1 WHERE id = ?2",
+            params![hash.as_str(), id],
+        )?;
+    }
+    Ok(())
+}
+
+fn add_ban_addresses(tx: &Transaction, _: &argon2::Params) -> anyhow::Result<()> {
+    tx.execute_batch("ALTER TABLE bans ADD COLUMN ip TEXT")?;
+    Ok(())
+}
+
+/// Instances sharing the database see each other's members, usernames stay unique in a room
+fn share_members(tx: &Transaction, _: &argon2::Params) -> anyhow::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE members ADD COLUMN is_online INTEGER NOT NULL DEFAULT 0;
+         ALTER TABLE members ADD COLUMN is_sharing INTEGER NOT NULL DEFAULT 0;
+         CREATE UNIQUE INDEX members_username ON members (room_id, username);",
+    )?;
+    Ok(())
+}
+
+/// Keeps rooms in a SQLite database file
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open the database, bringing its schema up to date.
+    /// Passwords left in plain text by older versions are hashed with `password_params`.
+    pub fn open(path: impl AsRef<Path>, password_params: &argon2::Params) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let conn = Connection::open(path)
+            .with_context(|| format!("failed to open database: {}", path.display()))?;
+        Self::with_connection(conn, password_params)
+    }
+
+    fn with_connection(
+        mut conn: Connection,
+        password_params: &argon2::Params,
+    ) -> anyhow::Result<Self> {
+        migrate(&mut conn, password_params)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+fn migrate(conn: &mut Connection, password_params: &argon2::Params) -> anyhow::Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    anyhow::ensure!(
+        version <= MIGRATIONS.len(),
+        "database schema version {version} is newer than this server supports"
+    );
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        migration(&tx, password_params)
+            .with_context(|| format!("failed to migrate database to version {}", version + 1))?;
+        tx.pragma_update(None, "user_version", version + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+impl RoomStore for SqliteStore {
+    fn load_rooms(&self) -> anyhow::Result<Vec<StoredRoom>> {
+        read_rooms(&self.conn.lock().unwrap(), None)
+    }
+
+    fn load_room(&self, room_id: &RoomId) -> anyhow::Result<Option<StoredRoom>> {
+        Ok(read_rooms(&self.conn.lock().unwrap(), Some(room_id))?.pop())
+    }
+
+    fn save_room(&self, room: &StoredRoom) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO rooms (id, owner_token, password, mode, last_activity)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (id) DO UPDATE SET
+                 owner_token = excluded.owner_token,
+                 password = excluded.password,
+                 mode = excluded.mode,
+                 last_activity = MAX(last_activity, excluded.last_activity)",
+            params![
+                room.id.as_str(),
+                room.owner_token,
+                room.password.as_ref().map(|p| p.as_str()),
+                mode_str(room.mode),
+                room.last_activity,
+            ],
+        )?;
+        for member in &room.members {
+            tx.execute(
+                "INSERT OR REPLACE INTO members
+                     (room_id, token, username, last_seen, is_online, is_sharing)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    room.id.as_str(),
+                    member.token,
+                    member.username.as_str(),
+                    member.last_seen,
+                    member.is_online,
+                    member.is_sharing,
+                ],
+            )?;
+        }
+        for ban in &room.bans {
+            tx.execute(
+                "INSERT OR REPLACE INTO bans (room_id, token, username, ip) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    room.id.as_str(),
+                    ban.token,
+                    ban.username.as_str(),
+                    ban.ip.map(|ip| ip.to_string()),
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn add_member(&self, room_id: &RoomId, member: &StoredMember) -> anyhow::Result<bool> {
+        let added = self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO members
+                 (room_id, token, username, last_seen, is_online, is_sharing)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                room_id.as_str(),
+                member.token,
+                member.username.as_str(),
+                member.last_seen,
+                member.is_online,
+                member.is_sharing,
+            ],
+        )?;
+        Ok(added == 1)
+    }
+
+    fn delete_member(&self, room_id: &RoomId, token: &str) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "DELETE FROM members WHERE room_id = ?1 AND token = ?2",
+            params![room_id.as_str(), token],
+        )?;
+        Ok(())
+    }
+
+    fn delete_room(&self, room_id: &RoomId) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM members WHERE room_id = ?1", [room_id.as_str()])?;
+        tx.execute("DELETE FROM bans WHERE room_id = ?1", [room_id.as_str()])?;
+        tx.execute("DELETE FROM rooms WHERE id = ?1", [room_id.as_str()])?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// Read every room, or only the given one
+fn read_rooms(conn: &Connection, room_id: Option<&RoomId>) -> anyhow::Result<Vec<StoredRoom>> {
+    let mut rooms_stmt = conn.prepare(
+        "SELECT id, owner_token, password, mode, last_activity FROM rooms
+         WHERE ?1 IS NULL OR id = ?1",
+    )?;
+    let mut members_stmt = conn.prepare(
+        "SELECT token, username, last_seen, is_online, is_sharing FROM members
+         WHERE room_id = ?1",
+    )?;
+    let mut bans_stmt = conn.prepare("SELECT token, username, ip FROM bans WHERE room_id = ?1")?;
+
+    let rows = rooms_stmt.query_map([room_id.map(|id| id.as_str())], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<String>>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, DateTime<Utc>>(4)?,
+        ))
+    })?;
+
+    let mut rooms = Vec::new();
+    for row in rows {
+        let (id, owner_token, password, mode, last_activity) = row?;
+        let members = members_stmt
+            .query_map([&id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, DateTime<Utc>>(2)?,
+                    row.get::<_, bool>(3)?,
+                    row.get::<_, bool>(4)?,
+                ))
+            })?
+            .map(|row| {
+                let (token, username, last_seen, is_online, is_sharing) = row?;
+                Ok(StoredMember {
+                    token,
+                    username: username.parse()?,
+                    last_seen,
+                    is_online,
+                    is_sharing,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .with_context(|| format!("invalid member in room {id}"))?;
+        let bans = bans_stmt
+            .query_map([&id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            })?
+            .map(|row| {
+                let (token, username, ip) = row?;
+                Ok(StoredBan {
+                    token,
+                    username: username.parse()?,
+                    ip: ip.map(|ip| ip.parse()).transpose()?,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .with_context(|| format!("invalid ban in room {id}"))?;
+
+        rooms.push(StoredRoom {
+            id: id.parse()?,
+            owner_token,
+            password: password
+                .map(HashedPassword::from_phc)
+                .transpose()
+                .with_context(|| format!("invalid password of room {id}"))?,
+            mode: parse_mode(&mode)?,
+            last_activity,
+            members,
+            bans,
+        });
+    }
+    Ok(rooms)
+}
+
+fn mode_str(mode: RoomMode) -> &'static str {
+    match mode {
+        RoomMode::Mesh => "mesh",
+        RoomMode::Sfu => "sfu",
+    }
+}
+
+fn parse_mode(mode: &str) -> anyhow::Result<RoomMode> {
+    match mode {
+        "mesh" => Ok(RoomMode::Mesh),
+        "sfu" => Ok(RoomMode::Sfu),
+        other => Err(anyhow!("unknown room mode: {other}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::OptionalExtension;
+
+    use super::*;
+
+    fn params() -> argon2::Params {
+        argon2::Params::new(argon2::Params::MIN_M_COST, 1, 1, None).unwrap()
+    }
+
+    fn store() -> SqliteStore {
+        SqliteStore::with_connection(Connection::open_in_memory().unwrap(), &params()).unwrap()
+    }
+
+    fn room() -> StoredRoom {
+        StoredRoom {
+            id: "550e8400-e29b-41d4-a716-446655440000".parse().unwrap(),
+            owner_token: "owner".to_string(),
+            password: Some(HashedPassword::new(&"secret".parse().unwrap(), &params()).unwrap()),
+            mode: RoomMode::Sfu,
+            last_activity: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            members: vec![StoredMember {
+                token: "member".to_string(),
+                username: "alice".parse().unwrap(),
+                last_seen: DateTime::from_timestamp(1_700_000_100, 0).unwrap(),
+                is_online: true,
+                is_sharing: false,
+            }],
+            bans: vec![StoredBan {
+                token: "banned".to_string(),
+                username: "mallory".parse().unwrap(),
+                ip: Some("203.0.113.7".parse().unwrap()),
+            }],
+        }
+    }
+
+    #[test]
+    fn save_and_load_room() {
+        let store = store();
+        let mut room = room();
+        store.save_room(&room).unwrap();
+        assert_eq!(store.load_rooms().unwrap(), vec![room.clone()]);
+
+        // Members and bans left out are kept, another instance may have written them
+        let mut update = room.clone();
+        update.members.clear();
+        update.bans.clear();
+        update.password = None;
+        store.save_room(&update).unwrap();
+        room.password = None;
+        assert_eq!(store.load_room(&room.id).unwrap(), Some(room.clone()));
+
+        store.delete_member(&room.id, "member").unwrap();
+        room.members.clear();
+        assert_eq!(store.load_room(&room.id).unwrap(), Some(room));
+    }
+
+    #[test]
+    fn keeps_usernames_unique_in_a_room() {
+        let store = store();
+        let room = room();
+        store.save_room(&room).unwrap();
+        let mut member = room.members[0].clone();
+        member.token = "other".to_string();
+        assert!(!store.add_member(&room.id, &member).unwrap());
+
+        member.username = "bob".parse().unwrap();
+        assert!(store.add_member(&room.id, &member).unwrap());
+        assert_eq!(store.load_room(&room.id).unwrap().unwrap().members.len(), 2);
+    }
+
+    #[test]
+    fn delete_room() {
+        let store = store();
+        let room = room();
+        store.save_room(&room).unwrap();
+        store.delete_room(&room.id).unwrap();
+        assert!(store.load_rooms().unwrap().is_empty());
+        let orphans: Option<String> = store
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT token FROM members", [], |row| row.get(0))
+            .optional()
+            .unwrap();
+        assert_eq!(orphans, None);
+    }
+
+    #[test]
+    fn hashes_plaintext_passwords_of_older_databases() {
+        // Schema and contents as written before passwords were hashed or versioned
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(TABLES).unwrap();
+        conn.execute(
+            "INSERT INTO rooms (id, owner_token, password, mode, last_activity)
+             VALUES ('550e8400-e29b-41d4-a716-446655440000', 'owner', 'secret', 'mesh', ?1)",
+            [Utc::now()],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO members (room_id, token, username, last_seen)
+             VALUES ('550e8400-e29b-41d4-a716-446655440000', 'member', 'alice', ?1)",
+            [Utc::now()],
+        )
+        .unwrap();
+
+        let store = SqliteStore::with_connection(conn, &params()).unwrap();
+        let rooms = store.load_rooms().unwrap();
+        let password = rooms[0].password.as_ref().unwrap();
+        assert_ne!(password.as_str(), "secret");
+        assert!(password.matches(&"secret".parse().unwrap()));
+        assert_eq!(rooms[0].members[0].username.as_str(), "alice");
+
+        let version: usize = store
+            .conn
+            .lock()
+            .unwrap()
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use inpixly_shared::RoomId;
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+use super::{RoomStore, StoredRoom};
+
+enum Write {
+    Save(Box<StoredRoom>),
+    DeleteMember(RoomId, String),
+    Delete(RoomId),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Applies writes to a [`RoomStore`] on a thread of its own, in the order they were queued.
+/// A slow disk then holds up neither the async runtime nor the rooms lock.
+#[derive(Clone)]
+pub struct StoreWriter {
+    tx: mpsc::UnboundedSender<Write>,
+}
+
+impl StoreWriter {
+    /// Start the writer thread, it ends once every clone of the writer is dropped
+    pub fn spawn(store: Arc<dyn RoomStore>) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("room-store".to_string())
+            .spawn(move || {
+                while let Some(write) = rx.blocking_recv() {
+                    match write {
+                        Write::Save(room) => {
+                            if let Err(e) = store.save_room(&room) {
+                                error!("Failed to save room {}: {e:#}", room.id);
+                            }
+                        }
+                        Write::DeleteMember(room_id, token) => {
+                            if let Err(e) = store.delete_member(&room_id, &token) {
+                                error!("Failed to delete member of room {room_id}: {e:#}");
+                            }
+                        }
+                        Write::Delete(room_id) => {
+                            if let Err(e) = store.delete_room(&room_id) {
+                                error!("Failed to delete room {room_id} from storage: {e:#}");
+                            }
+                        }
+                        Write::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn the room store thread");
+        Self { tx }
+    }
+
+    pub fn save(&self, room: StoredRoom) {
+        let _ = self.tx.send(Write::Save(Box::new(room)));
+    }
+
+    pub fn delete_member(&self, room_id: RoomId, token: String) {
+        let _ = self.tx.send(Write::DeleteMember(room_id, token));
+    }
+
+    pub fn delete(&self, room_id: RoomId) {
+        let _ = self.tx.send(Write::Delete(room_id));
+    }
+
+    /// Wait until every write queued so far reached the store
+    pub async fn flush(&self) {
+        let (done, done_rx) = oneshot::channel();
+        if self.tx.send(Write::Flush(done)).is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use chrono::Utc;
+    use inpixly_shared::RoomMode;
+
+    use super::*;
+    use crate::storage::{MemoryStore, StoredMember};
+
+    /// Takes its time with every write, like a busy disk
+    struct SlowStore {
+        inner: MemoryStore,
+        saves: Mutex<u32>,
+    }
+
+    impl RoomStore for SlowStore {
+        fn load_rooms(&self) -> anyhow::Result<Vec<StoredRoom>> {
+            self.inner.load_rooms()
+        }
+
+        fn save_room(&self, room: &StoredRoom) -> anyhow::Result<()> {
+            std::thread::sleep(Duration::from_millis(50));
+            *self.saves.lock().unwrap() += 1;
+            self.inner.save_room(room)
+        }
+
+        fn load_room(&self, room_id: &RoomId) -> anyhow::Result<Option<StoredRoom>> {
+            self.inner.load_room(room_id)
+        }
+
+        fn add_member(&self, room_id: &RoomId, member: &StoredMember) -> anyhow::Result<bool> {
+            self.inner.add_member(room_id, member)
+        }
+
+        fn delete_member(&self, room_id: &RoomId, token: &str) -> anyhow::Result<()> {
+            self.inner.delete_member(room_id, token)
+        }
+
+        fn delete_room(&self, room_id: &RoomId) -> anyhow::Result<()> {
+            self.inner.delete_room(room_id)
+        }
+    }
+
+    fn room(id: &str) -> StoredRoom {
+        StoredRoom {
+            id: id.parse().unwrap(),
+            owner_token: "owner".to_string(),
+            password: None,
+            mode: RoomMode::Mesh,
+            last_activity: Utc::now(),
+            members: Vec::new(),
+            bans: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_in_order_without_blocking() {
+        let store = Arc::new(SlowStore {
+            inner: MemoryStore::default(),
+            saves: Mutex::new(0),
+        });
+        let writer = StoreWriter::spawn(store.clone());
+        let kept = room("550e8400-e29b-41d4-a716-446655440000");
+        let deleted = room("6ba7b810-9dad-11d1-80b4-00c04fd430c8");
+
+        let started = std::time::Instant::now();
+        writer.save(kept.clone());
+        writer.save(deleted.clone());
+        writer.delete(deleted.id.clone());
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        writer.flush().await;
+        assert_eq!(*store.saves.lock().unwrap(), 2);
+        assert_eq!(store.load_rooms().unwrap(), vec![kept]);
+    }
+}
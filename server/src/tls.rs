@@ -0,0 +1,169 @@
+use std::{net::SocketAddr, path::Path, time::Duration, time::SystemTime};
+
+use anyhow::Context;
+use axum::{
+    Router,
+    http::{HeaderMap, StatusCode, Uri, header, uri::Authority},
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::{select, time::interval};
+use tracing::{error, info};
+
+use crate::config::TlsConfig;
+
+/// How often the certificate files are checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+pub async fn load(config: &TlsConfig) -> anyhow::Result<RustlsConfig> {
+    // Every rustls user in the process shares the provider, another one may have installed it first
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    RustlsConfig::from_pem_file(&config.cert_path, &config.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to load TLS certificate {} with key {}",
+                config.cert_path.display(),
+                config.key_path.display()
+            )
+        })
+}
+
+/// Reload the certificate when its files change or on SIGHUP.
+/// Only new handshakes use it, established connections and their WebSockets carry on.
+pub fn spawn_reload_task(config: TlsConfig, rustls: RustlsConfig) {
+    tokio::spawn(async move {
+        let mut hangup = Hangup::new();
+        let mut check = interval(RELOAD_CHECK_INTERVAL);
+        let mut modified = modified_times(&config);
+        loop {
+            select! {
+                _ = hangup.recv() => info!("Received SIGHUP, reloading TLS certificate."),
+                _ = check.tick() => {
+                    let now = modified_times(&config);
+                    if now == modified {
+                        continue;
+                    }
+                    modified = now;
+                    info!("TLS certificate files changed, reloading.");
+                }
+            }
+            match rustls
+                .reload_from_pem_file(&config.cert_path, &config.key_path)
+                .await
+            {
+                Ok(()) => info!("TLS certificate reloaded."),
+                Err(e) => error!("Failed to reload TLS certificate, keeping the old one: {e}"),
+            }
+        }
+    });
+}
+
+fn modified_times(config: &TlsConfig) -> [Option<SystemTime>; 2] {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    [modified(&config.cert_path), modified(&config.key_path)]
+}
+
+#[cfg(unix)]
+struct Hangup(Option<tokio::signal::unix::Signal>);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Self {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::hangup()) {
+            Ok(signal) => Self(Some(signal)),
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {e}");
+                Self(None)
+            }
+        }
+    }
+
+    async fn recv(&mut self) {
+        match &mut self.0 {
+            Some(signal) => {
+                signal.recv().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Self {
+        Self
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+/// Plain HTTP listener sending every request to the same path over HTTPS
+pub async fn serve_redirect(bind: SocketAddr, https_port: u16) -> anyhow::Result<()> {
+    let app = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        redirect(&headers, &uri, https_port)
+    });
+    let listener = tokio::net::TcpListener::bind(bind)
+        .await
+        .context("failed to bind HTTP redirect listener")?;
+    info!("Redirecting http://{bind} to HTTPS");
+    axum::serve(listener, app)
+        .await
+        .context("HTTP redirect server error")
+}
+
+fn redirect(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+    let Some(authority) = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+    else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    Redirect::permanent(&https_url(&authority, uri, https_port)).into_response()
+}
+
+fn https_url(authority: &Authority, uri: &Uri, https_port: u16) -> String {
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    match https_port {
+        443 => format!("https://{}{path}", authority.host()),
+        port => format!("https://{}:{port}{path}", authority.host()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(host: &str, uri: &str, https_port: u16) -> String {
+        https_url(&host.parse().unwrap(), &uri.parse().unwrap(), https_port)
+    }
+
+    #[test]
+    fn redirects_to_https_port() {
+        assert_eq!(
+            url("example.com", "/room/abc?x=1", 443),
+            "https://example.com/room/abc?x=1"
+        );
+        assert_eq!(
+            url("example.com:8080", "/", 8443),
+            "https://example.com:8443/"
+        );
+        assert_eq!(
+            url("[::1]:80", "/api/rooms", 3000),
+            "https://[::1]:3000/api/rooms"
+        );
+    }
+
+    #[test]
+    fn rejects_missing_host() {
+        let response = redirect(&HeaderMap::new(), &"/".parse().unwrap(), 443);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
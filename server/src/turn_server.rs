@@ -0,0 +1,452 @@
+use std::{
+    any::Any,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, bail};
+use async_trait::async_trait;
+use ipnet::IpNet;
+use tokio::{
+    net::UdpSocket,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tracing::{debug, info};
+use uuid::Uuid;
+use webrtc::{
+    turn::{
+        self,
+        auth::{AuthHandler, generate_auth_key},
+        relay::{RelayAddressGenerator, relay_range::RelayAddressGeneratorRanges},
+        server::{
+            Server,
+            config::{ConnConfig, ServerConfig},
+        },
+    },
+    util::{self, Conn, vnet::net::Net},
+};
+
+use crate::{
+    config::{TurnRestConfig, TurnServerConfig},
+    ice,
+};
+
+/// STUN responder and TURN relay running inside this process.
+/// Members authenticate with credentials minted from a secret that lives only as long as the server.
+pub struct TurnServer {
+    server: Server,
+    rest_config: TurnRestConfig,
+}
+
+impl TurnServer {
+    pub async fn start(config: &TurnServerConfig) -> anyhow::Result<Self> {
+        let advertised_ip = match config.public_ip {
+            Some(ip) => ip,
+            None if config.bind.ip().is_unspecified() => {
+                bail!(
+                    "turn_server.public_ip is required when binding to {}",
+                    config.bind
+                )
+            }
+            None => config.bind.ip(),
+        };
+
+        let socket = UdpSocket::bind(config.bind)
+            .await
+            .with_context(|| format!("failed to bind TURN server to {}", config.bind))?;
+        let local_addr = socket.local_addr()?;
+        let advertised = SocketAddr::new(advertised_ip, local_addr.port());
+
+        let secret = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let relays = LimitedRelays {
+            inner: RelayAddressGeneratorRanges {
+                relay_address: advertised_ip,
+                min_port: config.relay_port_min,
+                max_port: config.relay_port_max,
+                max_retries: 0,
+                address: config.bind.ip().to_string(),
+                net: Arc::new(Net::new(None)),
+            },
+            permits: Arc::new(Semaphore::new(config.max_allocations)),
+            peers: PeerFilter {
+                allowed: config.allowed_peers.clone().into(),
+            },
+        };
+        let server = Server::new(ServerConfig {
+            conn_configs: vec![ConnConfig {
+                conn: Arc::new(socket),
+                relay_addr_generator: Box::new(relays),
+            }],
+            realm: config.realm.clone(),
+            auth_handler: Arc::new(SharedSecretAuth {
+                secret: secret.clone(),
+            }),
+            channel_bind_timeout: Default::default(),
+            alloc_close_notify: None,
+        })
+        .await
+        .context("failed to start TURN server")?;
+
+        info!("TURN server listening on udp://{local_addr}, advertised as {advertised}");
+        Ok(Self {
+            server,
+            rest_config: TurnRestConfig {
+                urls: vec![
+                    format!("stun:{advertised}"),
+                    format!("turn:{advertised}?transport=udp"),
+                ],
+                secret,
+                ttl_secs: config.credential_ttl_secs,
+            },
+        })
+    }
+
+    /// How clients reach this server, to hand out in place of an external TURN server
+    pub fn rest_config(&self) -> TurnRestConfig {
+        self.rest_config.clone()
+    }
+
+    pub async fn close(&self) -> anyhow::Result<()> {
+        Ok(self.server.close().await?)
+    }
+}
+
+/// Accepts the credentials minted by [`ice::turn_credentials`] until they expire
+struct SharedSecretAuth {
+    secret: String,
+}
+
+impl AuthHandler for SharedSecretAuth {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, turn::Error> {
+        let expiry = username
+            .split_once(':')
+            .and_then(|(expiry, _)| expiry.parse::<u64>().ok())
+            .ok_or_else(|| turn::Error::Other(format!("malformed TURN username {username}")))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if expiry < now {
+            debug!(%src_addr, username, "Refusing expired TURN credentials.");
+            return Err(turn::Error::Other(format!(
+                "expired TURN credentials {username}"
+            )));
+        }
+        let password = ice::turn_password(&self.secret, username);
+        Ok(generate_auth_key(username, realm, &password))
+    }
+}
+
+/// Hands out relays within a port range while there are permits left.
+/// A permit is returned when its relay socket is dropped with the allocation.
+struct LimitedRelays {
+    inner: RelayAddressGeneratorRanges,
+    permits: Arc<Semaphore>,
+    peers: PeerFilter,
+}
+
+/// Keeps relays from reaching the server's own network, unless the operator allowed it
+#[derive(Clone)]
+struct PeerFilter {
+    allowed: Arc<[IpNet]>,
+}
+
+impl PeerFilter {
+    fn check(&self, peer: SocketAddr) -> util::Result<()> {
+        let ip = peer.ip().to_canonical();
+        if is_internal(ip) && !self.allowed.iter().any(|net| net.contains(&ip)) {
+            debug!(%peer, "Refusing to relay to an internal address.");
+            return Err(util::Error::Other(format!(
+                "relaying to {peer} is not allowed"
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                || ip.is_unspecified()
+        }
+    }
+}
+
+#[async_trait]
+impl RelayAddressGenerator for LimitedRelays {
+    fn validate(&self) -> Result<(), turn::Error> {
+        self.inner.validate()
+    }
+
+    async fn allocate_conn(
+        &self,
+        use_ipv4: bool,
+        requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), turn::Error> {
+        let permit = Arc::clone(&self.permits)
+            .try_acquire_owned()
+            .map_err(|_| turn::Error::Other("TURN allocation quota exhausted".to_string()))?;
+        let (conn, relay_addr) = self.inner.allocate_conn(use_ipv4, requested_port).await?;
+        let conn = RelayConn {
+            inner: conn,
+            peers: self.peers.clone(),
+            _permit: permit,
+        };
+        Ok((Arc::new(conn), relay_addr))
+    }
+}
+
+struct RelayConn {
+    inner: Arc<dyn Conn + Send + Sync>,
+    peers: PeerFilter,
+    _permit: OwnedSemaphorePermit,
+}
+
+#[async_trait]
+impl Conn for RelayConn {
+    async fn connect(&self, addr: SocketAddr) -> util::Result<()> {
+        self.peers.check(addr)?;
+        self.inner.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> util::Result<usize> {
+        self.inner.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> util::Result<(usize, SocketAddr)> {
+        self.inner.recv_from(buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> util::Result<usize> {
+        self.inner.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> util::Result<usize> {
+        self.peers.check(target)?;
+        self.inner.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> util::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    async fn close(&self) -> util::Result<()> {
+        self.inner.close().await
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+    use webrtc::turn::client::{Client, ClientConfig};
+
+    use super::*;
+    use crate::config::IceConfig;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn loopback_config(max_allocations: usize) -> TurnServerConfig {
+        TurnServerConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            public_ip: None,
+            realm: "inpixly".to_string(),
+            relay_port_min: 40000,
+            relay_port_max: 60000,
+            credential_ttl_secs: 600,
+            max_allocations,
+            // The peers in these tests are on loopback
+            allowed_peers: vec!["127.0.0.0/8".parse().unwrap()],
+        }
+    }
+
+    async fn client(server: &TurnServer, username: String, password: String) -> Client {
+        let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stun_url = &server.rest_config().urls[0];
+        let addr = stun_url.strip_prefix("stun:").unwrap().to_string();
+        let client = Client::new(ClientConfig {
+            stun_serv_addr: addr.clone(),
+            turn_serv_addr: addr,
+            username,
+            password,
+            realm: "inpixly".to_string(),
+            software: String::new(),
+            rto_in_ms: 0,
+            conn: Arc::new(conn),
+            vnet: None,
+        })
+        .await
+        .unwrap();
+        client.listen().await.unwrap();
+        client
+    }
+
+    /// A client with the credentials the server would hand to a member joining with `token`
+    async fn member_client(server: &TurnServer, token: &str) -> Client {
+        let ice_config = IceConfig {
+            servers: Vec::new(),
+            turn: Some(server.rest_config()),
+        };
+        let ice_server = ice::ice_servers_for(&ice_config, token).remove(0);
+        assert!(ice_server.urls.iter().any(|url| url.starts_with("turn:")));
+        client(
+            server,
+            ice_server.username.unwrap(),
+            ice_server.credential.unwrap(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn relays_between_client_and_peer() {
+        let server = TurnServer::start(&loopback_config(10)).await.unwrap();
+        let client = member_client(&server, "member-token").await;
+        let relay = timeout(TIMEOUT, client.allocate()).await.unwrap().unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 1500];
+
+        relay
+            .send_to(b"from client", peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (n, from) = timeout(TIMEOUT, peer.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], b"from client");
+        assert_eq!(from, relay_addr);
+
+        peer.send_to(b"from peer", relay_addr).await.unwrap();
+        let (n, from) = timeout(TIMEOUT, relay.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], b"from peer");
+        assert_eq!(from, peer.local_addr().unwrap());
+
+        client.close().await.unwrap();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_to_relay_to_internal_addresses() {
+        let config = TurnServerConfig {
+            allowed_peers: Vec::new(),
+            ..loopback_config(10)
+        };
+        let server = TurnServer::start(&config).await.unwrap();
+        let client = member_client(&server, "member-token").await;
+        let relay = timeout(TIMEOUT, client.allocate()).await.unwrap().unwrap();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 1500];
+        let _ = relay
+            .send_to(b"from client", peer.local_addr().unwrap())
+            .await;
+        let received = timeout(Duration::from_secs(1), peer.recv_from(&mut buf)).await;
+        assert!(received.is_err(), "relayed to a loopback peer");
+
+        client.close().await.unwrap();
+        server.close().await.unwrap();
+    }
+
+    #[test]
+    fn denies_internal_peers_by_default() {
+        let filter = PeerFilter {
+            allowed: vec!["10.1.0.0/16".parse().unwrap()].into(),
+        };
+        for denied in [
+            "127.0.0.1:9",
+            "10.2.0.1:9",
+            "192.168.1.1:9",
+            "169.254.169.254:80",
+            "0.0.0.0:9",
+            "[::1]:9",
+            "[fe80::1]:9",
+            "[fd00:ec2::254]:80",
+            "[::ffff:127.0.0.1]:9",
+        ] {
+            assert!(filter.check(denied.parse().unwrap()).is_err(), "{denied}");
+        }
+        for allowed in ["10.1.2.3:9", "203.0.113.7:9", "[2001:db8::1]:9"] {
+            assert!(filter.check(allowed.parse().unwrap()).is_ok(), "{allowed}");
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_binding_requests() {
+        let server = TurnServer::start(&loopback_config(10)).await.unwrap();
+        let client = member_client(&server, "member-token").await;
+        let mapped = timeout(TIMEOUT, client.send_binding_request())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(mapped.ip().is_loopback());
+
+        client.close().await.unwrap();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_expired_credentials() {
+        let server = TurnServer::start(&loopback_config(10)).await.unwrap();
+        let expired = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let (username, password) =
+            ice::turn_credentials(&server.rest_config().secret, "member-token", expired);
+        let client = client(&server, username, password).await;
+        assert!(timeout(TIMEOUT, client.allocate()).await.unwrap().is_err());
+
+        client.close().await.unwrap();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn enforces_allocation_quota() {
+        let server = TurnServer::start(&loopback_config(1)).await.unwrap();
+        let first = member_client(&server, "first-token").await;
+        let second = member_client(&server, "second-token").await;
+
+        let _relay = timeout(TIMEOUT, first.allocate()).await.unwrap().unwrap();
+        assert!(timeout(TIMEOUT, second.allocate()).await.unwrap().is_err());
+
+        first.close().await.unwrap();
+        second.close().await.unwrap();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn requires_public_ip_for_unspecified_bind() {
+        let config = TurnServerConfig {
+            bind: "0.0.0.0:0".parse().unwrap(),
+            ..loopback_config(10)
+        };
+        assert!(TurnServer::start(&config).await.is_err());
+    }
+}
@@ -1,6 +1,10 @@
 use crate::{
-    room::RoomEvent,
-    state::{AppState, Rooms},
+    bus::BusEvent,
+    config::ProtocolConfig,
+    ice, password,
+    room::{LastSeen, Member, RoomEvent},
+    sfu::{Sfu, SignalSink},
+    state::AppState,
 };
 use axum::{
     extract::{
@@ -10,15 +14,36 @@ use axum::{
     response::Response,
 };
 use futures_util::{Sink, SinkExt, StreamExt};
-use inpixly_shared::{ErrorKind, JoinRequest, RoomId, Username, WsMessage};
+use inpixly_shared::{
+    Capability, ClientMessage, ErrorKind, JoinRequest, Negotiated, ProtocolInfo, RoomId, RoomMode,
+    ServerMessage, SignalingPayload, Username,
+    codec::{self, Encoding, Frame},
+};
+use serde::de::DeserializeOwned;
 use std::time::Duration;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{select, sync::broadcast, time::timeout};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+    time::{Instant, MissedTickBehavior, interval_at, timeout},
+};
 use tracing::{debug, error, info, info_span, warn};
 
 type WsSender = futures_util::stream::SplitSink<WebSocket, Message>;
 type WsReceiver = futures_util::stream::SplitStream<WebSocket>;
 
+/// What the server announces in the handshake
+fn server_protocol(config: &ProtocolConfig) -> ProtocolInfo {
+    let mut capabilities = vec![Capability::Sfu, Capability::ChatHistory];
+    if config.binary_framing {
+        capabilities.push(Capability::BinaryFraming);
+    }
+    ProtocolInfo::current(capabilities)
+}
+
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     Path(room_id): Path<RoomId>,
@@ -30,14 +55,13 @@ pub async fn ws_handler(
 
 #[tracing::instrument(skip(socket, state), fields(room_id = %room_id, client_addr = %addr))]
 async fn handle_socket(mut socket: WebSocket, room_id: RoomId, state: AppState, addr: SocketAddr) {
-    {
-        let rooms = state.rooms.read().await;
-        if !rooms.contains_key(&room_id) {
-            send_ws_error(&mut socket, ErrorKind::RoomNotFound).await;
-            return;
-        }
+    let _connection = state.metrics.connection_opened();
+    if !state.sync_room(&room_id).await {
+        send_ws_error(&state, &mut socket, Encoding::Json, ErrorKind::RoomNotFound).await;
+        return;
     }
-    let Some(mut member) = handshake_with_timeout(&state, &mut socket, &room_id).await else {
+    let Some(mut member) = handshake_with_timeout(&state, &mut socket, &room_id, addr.ip()).await
+    else {
         return;
     };
     let (mut sender, mut ws_reader) = socket.split();
@@ -45,90 +69,191 @@ async fn handle_socket(mut socket: WebSocket, room_id: RoomId, state: AppState,
 
     info!(?member_span, "User joined room.");
 
+    if member.resumed
+        && let Some(sfu) = &member.sfu
+        && let Some(sink) = sfu_sink(&state, &member).await
+    {
+        sfu.reattach(&member.token, sink).await;
+    }
+
+    let heartbeat_period = Duration::from_secs(state.heartbeat_config.interval_secs.max(1));
+    let mut heartbeat = interval_at(Instant::now() + heartbeat_period, heartbeat_period);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut missed_pongs = 0;
+
     loop {
-        select! {
+        let event = select! {
             biased;
-            event = member.room_broadcast_rx.recv() => match event {
-                Ok(RoomEvent::Kick { token, success }) if token == member.token => {
-                    info!("Received kick event for this member.");
-                    let _ = sender.send(Message::Close(None)).await;
-                    sender.close().await.ok();
-                    if let Some(disconnect_guard) = success.lock().unwrap().take() {
-                        member.disconnect(disconnect_guard);
-                    }
-                    break;
+            event = member.direct_rx.recv() => match event {
+                Some(event) => event,
+                // Channel was dropped from the room, a newer session took over
+                None => break,
+            },
+            event = member.room_rx.recv() => match event {
+                Ok(BusEvent::Broadcast { message }) => RoomEvent::Broadcast(message),
+                Ok(BusEvent::Direct { to, message }) if to == member.username => {
+                    RoomEvent::Direct(message)
                 }
-                Ok(RoomEvent::Kick { .. }) => {}
-                Ok(RoomEvent::Broadcast(ws_msg)) => {
-                    if matches!(&ws_msg, WsMessage::MemberJoined { username } if username == &member.username) {
-                        continue;
-                    }
-                    send_ws_json(&mut sender, &ws_msg).await;
+                // Relayed through the bus for a member connected elsewhere
+                Ok(BusEvent::Direct { .. }) => continue,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    resync_lagged_member(
+                        &state,
+                        &room_id,
+                        &member.token,
+                        member.encoding,
+                        skipped,
+                        &mut sender,
+                    )
+                    .await;
+                    continue;
                 }
-                // Ok(ws_msg) => {
-                //     // Force disconnect if another tab took over this session
-                //     if matches!(&ws_msg, WsMessage::ForceDisconnect { token } if token == &member.token) {
-                //         info!("Force disconnecting due to new connection from another tab.");
-                //         if let Ok(json) = serde_json::to_string(&ws_msg) {
-                //             let _ = sender.send(Message::Text(json.into())).await;
-                //         }
-                //         send_ws_json(&mut sender, &WsMessage::Error(ErrorKind::TokenTaken)).await;
-                //         break;
-                //     }
-
-                //     // Don't send MemberJoined to the member who just joined (they already know)
-                //     if matches!(&ws_msg, WsMessage::MemberJoined { username } if username == &member.username) {
-                //         continue;
-                //     }
-
-                //     // Don't forward ForceDisconnect for other users
-                //     if matches!(&ws_msg, WsMessage::ForceDisconnect { .. }) {
-                //         continue;
-                //     }
-
-                //     if let Ok(json) = serde_json::to_string(&ws_msg) {
-                //         if sender.send(Message::Text(json.into())).await.is_err() {
-                //             break;
-                //         }
-                //     }
-                // }
-                Err(_) => break,
+                Err(broadcast::error::RecvError::Closed) => break,
             },
+            _ = heartbeat.tick() => {
+                if missed_pongs >= state.heartbeat_config.max_missed_pongs {
+                    warn!(missed_pongs, "Connection stopped answering pings, closing.");
+                    break;
+                }
+                missed_pongs += 1;
+                if sender.send(Message::Ping(Default::default())).await.is_err() {
+                    break;
+                }
+                continue;
+            }
             msg = ws_reader.next() => {
                 match msg {
                     Some(Ok(Message::Text(text))) if text.len() > 30_000 => {
                         warn!("Received excessively long message, disconnecting.");
                         break;
                     }
-                    Some(Ok(Message::Text(text))) => {
-                        if let Err(e) = handle_client_message(&text, &room_id, &member.username, &state).await {
+                    Some(Ok(Message::Binary(bytes))) if bytes.len() > 30_000 => {
+                        warn!("Received excessively long message, disconnecting.");
+                        break;
+                    }
+                    Some(Ok(frame @ (Message::Text(_) | Message::Binary(_)))) => {
+                        if let Err(e) = handle_client_message(&frame, &member, &state, &mut sender).await {
                             error!("Error handling client message: {}", e);
                         }
                     }
                     Some(Ok(Message::Close(_))) | None => {
                         break;
                     }
-                    Some(Ok(Message::Pong(_)))| Some(Ok(Message::Ping(_))) => {
-                        continue;
+                    Some(Ok(Message::Pong(_))) => {
+                        missed_pongs = 0;
+                        member.last_seen.mark();
                     }
+                    Some(Ok(Message::Ping(_))) => {}
                     msg => {
                         warn!("Invalid WebSocket message: {msg:?}");
                         break;
                     }
                 }
+                continue;
+            }
+        };
+
+        match event {
+            RoomEvent::Kick { success } => {
+                info!("Received kick event for this member.");
+                let _ = sender.send(Message::Close(None)).await;
+                sender.close().await.ok();
+                if let Some(disconnect_guard) = success.lock().unwrap().take() {
+                    member.disconnect(disconnect_guard);
+                }
+                break;
+            }
+            RoomEvent::Broadcast(ServerMessage::MemberJoined { username })
+                if username == member.username => {}
+            RoomEvent::Direct(ws_msg @ ServerMessage::ServerGoingDown { .. }) => {
+                send_ws_message(&mut sender, member.encoding, &ws_msg).await;
+                let _ = sender.send(Message::Close(None)).await;
+                break;
+            }
+            RoomEvent::Direct(ws_msg @ ServerMessage::Removed { .. }) => {
+                send_ws_message(&mut sender, member.encoding, &ws_msg).await;
+                let _ = sender.send(Message::Close(None)).await;
+                // Removed through another instance, this one still knows the member
+                if let Some(room) = state.rooms.write().await.get_mut(&room_id) {
+                    room.forget_member(&member.token);
+                }
+                break;
+            }
+            RoomEvent::Broadcast(ws_msg) | RoomEvent::Direct(ws_msg) => {
+                send_ws_message(&mut sender, member.encoding, &ws_msg).await;
             }
         }
     }
 }
 
+/// Bring a member that missed broadcast events back in sync by resending the room state:
+/// who is in the room and who is sharing, and in SFU rooms the tracks forwarded to it
+async fn resync_lagged_member(
+    state: &AppState,
+    room_id: &RoomId,
+    token: &str,
+    encoding: Encoding,
+    skipped: u64,
+    sender: &mut (impl Sink<Message> + Unpin),
+) {
+    state.sync_room(room_id).await;
+    let (members, sfu) = {
+        let mut rooms = state.rooms.write().await;
+        let Some(room) = rooms.get_mut(room_id) else {
+            return;
+        };
+        state.metrics.broadcast_lags.inc();
+        let lag_count = room
+            .members
+            .get_mut(token)
+            .map(|m| m.record_lag())
+            .unwrap_or_default();
+        warn!(
+            skipped,
+            lag_count, "Member lagged behind room broadcast, resyncing."
+        );
+        (room.get_member_list(), room.sfu.clone())
+    };
+    send_ws_message(sender, encoding, &ServerMessage::MemberList { members }).await;
+    if let Some(sfu) = sfu
+        && let Err(e) = sfu.renegotiate(token).await
+    {
+        warn!("Failed to renegotiate forwarded tracks after lag: {e:#}");
+    }
+}
+
+/// Delivers signaling from the forwarding unit to the member's current connection
+async fn sfu_sink(state: &AppState, member: &WsMember) -> Option<SignalSink> {
+    let member_tx = {
+        let rooms = state.rooms.read().await;
+        rooms.get(&member.room_id)?.member_channel(&member.token)?
+    };
+    Some(Arc::new(move |payload| {
+        let _ = member_tx.try_send(RoomEvent::Direct(ServerMessage::Sfu(payload)));
+    }))
+}
+
 struct WsMember {
-    rooms: Rooms,
+    state: AppState,
     room_id: RoomId,
-    room_broadcast_rx: broadcast::Receiver<RoomEvent>,
+    /// Events for the whole room, or for members wherever they are connected
+    room_rx: broadcast::Receiver<BusEvent>,
+    direct_rx: mpsc::Receiver<RoomEvent>,
     token: String,
+    /// Id of this connection among the member's sessions
+    session: u64,
     username: Username,
+    /// Updated on every pong, shared with the member in the room
+    last_seen: LastSeen,
     is_owner: bool,
+    /// Whether this connection took over a session still in its grace period
+    resumed: bool,
     disconnect_token: Option<tokio_util::sync::DropGuard>,
+    /// Forwarding unit of the room, the member's peer in it is keyed by its token
+    /// and outlives the connection for the reconnect grace period
+    sfu: Option<Arc<Sfu>>,
+    /// How messages to this member are encoded
+    encoding: Encoding,
 }
 
 impl WsMember {
@@ -139,15 +264,33 @@ impl WsMember {
 
 impl Drop for WsMember {
     fn drop(&mut self) {
-        let rooms = self.rooms.clone();
+        let state = self.state.clone();
         let room_id = self.room_id.clone();
         let token = self.token.clone();
+        let session = self.session;
         let disconnect_token = self.disconnect_token.take();
+        let sfu = self.sfu.take();
 
         tokio::spawn(async move {
-            let mut rooms = rooms.write().await;
-            if let Some(room) = rooms.get_mut(&room_id) {
-                room.on_disconnect(&token, disconnect_token);
+            // Give the member a chance to reconnect before the room sees it leave
+            if disconnect_token.is_none() {
+                let grace = Duration::from_secs(state.session_config.reconnect_grace_secs);
+                tokio::time::sleep(grace).await;
+            }
+            state.sync_room(&room_id).await;
+            let mut rooms = state.rooms.write().await;
+            // Gone unless a newer session took over
+            let gone = match rooms.get_mut(&room_id) {
+                Some(room) => {
+                    room.on_disconnect(&token, session, disconnect_token);
+                    state.save_room(room);
+                    !room.members.get(&token).is_some_and(|m| m.is_online())
+                }
+                None => true,
+            };
+            drop(rooms);
+            if gone && let Some(sfu) = sfu {
+                sfu.remove_peer(&token).await;
             }
         });
     }
@@ -157,27 +300,29 @@ async fn handshake_with_timeout(
     state: &AppState,
     socket: &mut WebSocket,
     room_id: &RoomId,
+    ip: IpAddr,
 ) -> Option<WsMember> {
     const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
 
-    match timeout(JOIN_TIMEOUT, handshake(state, socket, room_id)).await {
+    match timeout(JOIN_TIMEOUT, handshake(state, socket, room_id, ip)).await {
         Ok(result) => result,
         Err(_) => {
             warn!("Join timeout exceeded.");
-            send_ws_error(socket, ErrorKind::JoinTimeout).await;
+            send_ws_error(state, socket, Encoding::Json, ErrorKind::JoinTimeout).await;
             None
         }
     }
 }
 
-async fn handshake(state: &AppState, socket: &mut WebSocket, room_id: &RoomId) -> Option<WsMember> {
+async fn handshake(
+    state: &AppState,
+    socket: &mut WebSocket,
+    room_id: &RoomId,
+    ip: IpAddr,
+) -> Option<WsMember> {
     loop {
-        let text = match socket.next().await {
-            Some(Ok(Message::Text(text))) => text,
-            Some(Ok(Message::Binary(_))) => {
-                warn!("Received unexpected binary message, disconnecting.");
-                return None;
-            }
+        let frame = match socket.next().await {
+            Some(Ok(frame @ (Message::Text(_) | Message::Binary(_)))) => frame,
             Some(Ok(Message::Close(_))) | None => {
                 return None;
             }
@@ -190,8 +335,8 @@ async fn handshake(state: &AppState, socket: &mut WebSocket, room_id: &RoomId) -
             }
         };
 
-        let join_request = match serde_json::from_str(&text) {
-            Ok(WsMessage::Join(join_request)) => join_request,
+        let (join_request, client_protocol) = match decode_frame(&frame) {
+            Ok(ClientMessage::Join { request, protocol }) => (request, protocol),
             Ok(other) => {
                 warn!("Expected Join message during handshake, got: {:?}", other);
                 continue;
@@ -201,18 +346,38 @@ async fn handshake(state: &AppState, socket: &mut WebSocket, room_id: &RoomId) -
                     "Failed to parse WebSocket message during handshake: {}",
                     err
                 );
+                // Another protocol version may not get its join across at all
+                if let Ok(client_protocol) = decode_frame::<ProtocolInfo>(&frame)
+                    && let Err(error) =
+                        server_protocol(&state.protocol_config).negotiate(&client_protocol)
+                {
+                    send_ws_error(state, socket, Encoding::Json, error).await;
+                }
                 return None;
             }
         };
 
-        let member = match join_room(state, room_id, socket, join_request, None).await {
-            Ok(member) => member,
+        let protocol = match server_protocol(&state.protocol_config).negotiate(&client_protocol) {
+            Ok(protocol) => protocol,
             Err(error) => {
-                send_ws_error(socket, error).await;
+                warn!(
+                    client_version = client_protocol.protocol_version,
+                    "Client speaks an incompatible protocol version."
+                );
+                send_ws_error(state, socket, Encoding::Json, error).await;
                 return None;
             }
         };
 
+        let member =
+            match join_room(state, room_id, ip, socket, join_request, &protocol, None).await {
+                Ok(member) => member,
+                Err(error) => {
+                    send_ws_error(state, socket, Encoding::Json, error).await;
+                    return None;
+                }
+            };
+
         return Some(member);
     }
 }
@@ -220,22 +385,81 @@ async fn handshake(state: &AppState, socket: &mut WebSocket, room_id: &RoomId) -
 async fn join_room(
     state: &AppState,
     room_id: &RoomId,
+    ip: IpAddr,
     socket: &mut WebSocket,
     request: JoinRequest,
+    protocol: &Negotiated,
     terminate_old_session_token: Option<tokio_util::sync::CancellationToken>,
 ) -> Result<WsMember, ErrorKind> {
-    if let Some(token) = &terminate_old_session_token {
-        token.cancelled().await;
+    match &terminate_old_session_token {
+        Some(token) => token.cancelled().await,
+        // Only the first pass counts, a retry after taking over an old session does not
+        None => {
+            let is_member = match &request {
+                JoinRequest::WithToken { token, .. } => {
+                    let rooms = state.rooms.read().await;
+                    rooms.get(room_id).is_some_and(|room| room.is_member(token))
+                }
+                JoinRequest::WithUsername { .. } => false,
+            };
+            state.rate_limits.join(ip, room_id, is_member)?
+        }
+    }
+
+    // Other instances sharing the store may pick the same username meanwhile,
+    // so the member is claimed in the store before it joins
+    let claimed = match &request {
+        JoinRequest::WithUsername { username, .. } if state.bus.is_shared() => {
+            Some(claim_username(state, room_id, ip, username).await?)
+        }
+        _ => None,
+    };
+
+    // Media of SFU rooms only flows through the server, a client that cannot talk to it sees no one
+    {
+        let rooms = state.rooms.read().await;
+        let room = rooms.get(room_id).ok_or(ErrorKind::RoomNotFound)?;
+        if room.mode() == RoomMode::Sfu && !protocol.supports(Capability::Sfu) {
+            let server = server_protocol(&state.protocol_config);
+            return Err(ErrorKind::IncompatibleProtocol {
+                protocol_version: server.protocol_version,
+                min_protocol_version: server.min_protocol_version,
+            });
+        }
+    }
+
+    // Password hashing is slow, so verify before locking the rooms
+    if let JoinRequest::WithUsername { password, .. } = &request {
+        state.rate_limits.check_password(ip, room_id)?;
+        let hash = {
+            let rooms = state.rooms.read().await;
+            let room = rooms.get(room_id).ok_or(ErrorKind::RoomNotFound)?;
+            room.password.clone()
+        };
+        let password = password.clone();
+        let result =
+            tokio::task::spawn_blocking(move || password::verify(hash.as_ref(), password.as_ref()))
+                .await
+                .map_err(|e| ErrorKind::Other {
+                    message: e.to_string(),
+                })?;
+        if let Err(ErrorKind::IncorrectPassword) = result {
+            state.rate_limits.password_failed(ip, room_id);
+        }
+        result?;
     }
 
     let mut rooms = state.rooms.write().await;
     let room = rooms.get_mut(room_id).ok_or(ErrorKind::RoomNotFound)?;
 
     let member = match request {
-        JoinRequest::WithToken { ref token } => {
+        JoinRequest::WithToken {
+            ref token,
+            ref owner_token,
+        } => {
             debug!("Attempting token-based login for token: {}", token);
-            let username = match room.login_member(token) {
-                Ok(username) => username,
+            let (username, resumed) = match room.login_member(token) {
+                Ok(login) => login,
                 Err(ErrorKind::TokenAlreadyInUse) if terminate_old_session_token.is_none() => {
                     let Some(disconnect_token) = room.force_logout_member(token) else {
                         return Err(ErrorKind::TokenAlreadyInUse);
@@ -244,198 +468,625 @@ async fn join_room(
                     return Box::pin(join_room(
                         state,
                         room_id,
+                        ip,
                         socket,
                         request,
+                        protocol,
                         Some(disconnect_token),
                     ))
                     .await;
                 }
                 Err(e) => return Err(e),
             };
-            let is_owner = room.is_owner(token);
+            room.record_address(token, ip);
+            let is_owner = owner_token.as_deref().is_some_and(|t| room.is_owner(t));
+            let (direct_rx, session) = room
+                .register_member_channel(token)
+                .ok_or(ErrorKind::TokenNotFound)?;
+            let last_seen = room.members[token].seen_handle();
             WsMember {
-                rooms: Arc::clone(&state.rooms),
-                room_broadcast_rx: room.broadcast_tx.subscribe(),
+                state: state.clone(),
+                room_rx: room.subscribe(),
+                direct_rx,
                 room_id: room_id.clone(),
                 token: token.clone(),
+                session,
+                last_seen,
                 username,
                 is_owner,
+                resumed,
                 disconnect_token: None,
+                sfu: room.sfu.clone(),
+                encoding: Encoding::negotiated(protocol),
             }
         }
-        JoinRequest::WithUsername { username, password } => {
-            room.verify_password(password.as_ref())?;
-            let (username, token) = room.add_member(username, true)?;
+        JoinRequest::WithUsername { ref username, .. } => {
+            room.check_address(ip)?;
+            let (username, token) = match claimed {
+                Some(member) => room.insert_member(member),
+                None => room.add_member(username.clone(), true)?,
+            };
+            room.record_address(&token, ip);
+            let (direct_rx, session) = room
+                .register_member_channel(&token)
+                .ok_or(ErrorKind::TokenNotFound)?;
+            let last_seen = room.members[&token].seen_handle();
             WsMember {
-                rooms: Arc::clone(&state.rooms),
-                room_broadcast_rx: room.broadcast_tx.subscribe(),
+                state: state.clone(),
+                room_rx: room.subscribe(),
+                direct_rx,
                 room_id: room_id.clone(),
                 token,
+                session,
+                last_seen,
                 username,
                 is_owner: false,
+                resumed: false,
                 disconnect_token: None,
+                sfu: room.sfu.clone(),
+                encoding: Encoding::negotiated(protocol),
             }
         }
     };
 
-    let messages = [
-        WsMessage::JoinedAs {
+    state.metrics.member_joined(&request);
+    state.save_room(room);
+
+    let mut messages = vec![
+        ServerMessage::JoinedAs {
             username: member.username.clone(),
             token: member.token.clone(),
             is_owner: member.is_owner,
+            resumed: member.resumed,
+            ice_servers: ice::ice_servers_for(&state.ice_config, &member.token),
+            protocol: server_protocol(&state.protocol_config),
         },
-        WsMessage::MemberList {
+        ServerMessage::MemberList {
             members: room.get_member_list(),
         },
     ];
+    if protocol.supports(Capability::ChatHistory) {
+        let (chat_history, has_more_chat) = room.chat.page(None, state.chat_config.page_size);
+        messages.push(ServerMessage::ChatHistory {
+            before: None,
+            messages: chat_history,
+            has_more: has_more_chat,
+        });
+    }
     drop(rooms);
 
     for msg in &messages {
-        send_ws_json(socket, msg).await;
+        send_ws_message(socket, member.encoding, msg).await;
     }
     Ok(member)
 }
 
-async fn send_ws_error(sender: &mut (impl Sink<Message> + Unpin), error_kind: ErrorKind) {
-    send_ws_json(sender, &WsMessage::Error(error_kind)).await;
-}
+/// Pick a username for a new member that is unique across all instances sharing the store
+async fn claim_username(
+    state: &AppState,
+    room_id: &RoomId,
+    ip: IpAddr,
+    requested: &Username,
+) -> Result<Member, ErrorKind> {
+    const ATTEMPTS: usize = 5;
 
-async fn send_ws_json(sender: &mut (impl Sink<Message> + Unpin), msg: &WsMessage) {
-    match serde_json::to_string(msg) {
-        Ok(json) => {
-            let _ = sender.send(Message::Text(json.into())).await;
+    for _ in 0..ATTEMPTS {
+        if !state.sync_room(room_id).await {
+            return Err(ErrorKind::RoomNotFound);
+        }
+        let username = {
+            let rooms = state.rooms.read().await;
+            let room = rooms.get(room_id).ok_or(ErrorKind::RoomNotFound)?;
+            room.check_address(ip)?;
+            room.pick_username(requested.clone())?
+        };
+        let member = Member::new(username, true);
+        if state.claim_member(room_id, member.to_stored()).await {
+            return Ok(member);
         }
+    }
+    Err(ErrorKind::UsernameTaken)
+}
+
+async fn send_ws_error(
+    state: &AppState,
+    sender: &mut (impl Sink<Message> + Unpin),
+    encoding: Encoding,
+    error_kind: ErrorKind,
+) {
+    state.metrics.error_sent(&error_kind);
+    send_ws_message(sender, encoding, &ServerMessage::Error(error_kind)).await;
+}
+
+async fn send_ws_message(
+    sender: &mut (impl Sink<Message> + Unpin),
+    encoding: Encoding,
+    msg: &ServerMessage,
+) {
+    let message = match codec::encode(msg, encoding) {
+        Ok(Frame::Text(text)) => Message::Text(text.into()),
+        Ok(Frame::Binary(bytes)) => Message::Binary(bytes.into()),
         Err(err) => {
             error!("Failed to serialize message '{msg:?}': {err}");
+            return;
         }
-    }
+    };
+    let _ = sender.send(message).await;
 }
 
-// // async fn handle_join(
-// //     state: &AppState,
-// //     room_id: &RoomId,
-// //     request: JoinRequest,
-// // ) -> Result<WsMember, ErrorKind> {
-// //     let mut rooms = state.rooms.write().await;
-// //     let room = rooms.get_mut(room_id).ok_or(ErrorKind::RoomNotFound)?;
-
-// //     match request {
-// //         JoinRequest::WithToken { token } => {
-// //             // Check if member exists and if already online
-// //             let was_online = room
-// //                 .find_member_by_token_mut(&token)
-// //                 .map(|m| m.online)
-// //                 .ok_or(ErrorKind::TokenNotFound)?;
-
-// //             // If already online, force disconnect the old connection
-// //             if was_online {
-// //                 room.broadcast(WsMessage::ForceDisconnect {
-// //                     token: token.clone(),
-// //                 });
-// //             }
-
-// //             // Now update the member
-// //             let member = room.find_member_by_token_mut(&token).unwrap();
-// //             member.online = true;
-// //             member.last_seen = chrono::Utc::now();
-// //             let username = member.username.clone();
-// //             let is_owner = room.is_owner(&token);
-// //             room.touch();
-// //             Ok(WsMember {
-// //                 rooms: Arc::clone(&state.rooms),
-// //                 room_id: room_id.clone(),
-// //                 token,
-// //                 username,
-// //                 is_owner,
-// //             })
-// //         }
-// //         JoinRequest::WithUsername { username, password } => {
-// //             room.verify_password(password.as_ref())?;
-// //             let (assigned_username, token) = room.add_member(username)?;
-// //             let is_owner = room.is_owner(&token);
-// //             Ok(WsMember {
-// //                 rooms: Arc::clone(&state.rooms),
-// //                 room_id: room_id.clone(),
-// //                 token,
-// //                 username: assigned_username,
-// //                 is_owner,
-// //             })
-// //         }
-// //     }
-// // }
+/// Decode a data frame from a client, JSON in text frames and MessagePack in binary ones
+fn decode_frame<T: DeserializeOwned>(frame: &Message) -> anyhow::Result<T> {
+    match frame {
+        Message::Text(text) => Ok(codec::decode_text(text)?),
+        Message::Binary(bytes) => Ok(codec::decode_binary(bytes)?),
+        other => anyhow::bail!("not a data frame: {other:?}"),
+    }
+}
 
 async fn handle_client_message(
-    text: &str,
-    room_id: &RoomId,
-    username: &Username,
+    frame: &Message,
+    member: &WsMember,
     state: &AppState,
+    sender: &mut (impl Sink<Message> + Unpin),
 ) -> anyhow::Result<()> {
-    let msg: WsMessage = serde_json::from_str(text)?;
+    let msg: ClientMessage = decode_frame(frame)?;
+    let room_id = &member.room_id;
+    let username = &member.username;
 
     match msg {
-        WsMessage::Offer { to, sdp } => {
-            // forward_signaling(
-            //     room_id,
-            //     from_username,
-            //     &to,
-            //     SignalingPayload::Offer { sdp },
-            //     state,
-            // )
-            // .await;
+        ClientMessage::Offer { to, sdp } => {
+            forward_signaling(member, &to, SignalingPayload::Offer { sdp }, state, sender).await;
         }
-        WsMessage::Answer { to, sdp } => {
-            // forward_signaling(
-            //     room_id,
-            //     from_username,
-            //     &to,
-            //     SignalingPayload::Answer { sdp },
-            //     state,
-            // )
-            // .await;
+        ClientMessage::Answer { to, sdp } => {
+            forward_signaling(member, &to, SignalingPayload::Answer { sdp }, state, sender).await;
         }
-        WsMessage::IceCandidate { to, candidate } => {
-            // forward_signaling(
-            //     room_id,
-            //     from_username,
-            //     &to,
-            //     SignalingPayload::IceCandidate { candidate },
-            //     state,
-            // )
-            // .await;
+        ClientMessage::IceCandidate { to, candidate } => {
+            forward_signaling(
+                member,
+                &to,
+                SignalingPayload::IceCandidate { candidate },
+                state,
+                sender,
+            )
+            .await;
         }
-        WsMessage::ChatMessage { message } => {
-            let rooms = state.rooms.read().await;
-            if let Some(room) = rooms.get(room_id) {
-                room.broadcast(RoomEvent::Broadcast(WsMessage::Chat {
-                    from: username.clone(),
-                    message,
-                }));
+        ClientMessage::ChatMessage { message } => {
+            let mut rooms = state.rooms.write().await;
+            if let Some(room) = rooms.get_mut(room_id) {
+                let entry = room.chat.push(username.clone(), message);
+                state.metrics.chat_messages.inc();
+                room.broadcast(ServerMessage::Chat {
+                    from: entry.from,
+                    message: entry.message,
+                });
             }
         }
-        WsMessage::Leave => {
+        ClientMessage::FetchChatHistory { before } => {
+            let history = {
+                let rooms = state.rooms.read().await;
+                rooms
+                    .get(room_id)
+                    .map(|room| room.chat.page(Some(before), state.chat_config.page_size))
+            };
+            if let Some((messages, has_more)) = history {
+                send_ws_message(
+                    sender,
+                    member.encoding,
+                    &ServerMessage::ChatHistory {
+                        before: Some(before),
+                        messages,
+                        has_more,
+                    },
+                )
+                .await;
+            }
+        }
+        ClientMessage::Sfu(payload) => {
+            let Some(sfu) = &member.sfu else {
+                send_ws_error(state, sender, member.encoding, ErrorKind::SfuNotEnabled).await;
+                return Ok(());
+            };
+            let Some(sink) = sfu_sink(state, member).await else {
+                return Ok(());
+            };
+            sfu.signal(&member.token, username, payload, sink).await?;
+        }
+        ClientMessage::KickMember { username: target } => {
+            remove_member(member, &target, false, state, sender).await;
+        }
+        ClientMessage::BanMember { username: target } => {
+            remove_member(member, &target, true, state, sender).await;
+        }
+        ClientMessage::SetSharing { sharing } => {
+            let mut rooms = state.rooms.write().await;
+            if let Some(room) = rooms.get_mut(room_id) {
+                room.set_sharing(&member.token, sharing);
+            }
+        }
+        ClientMessage::Leave => {
             // Handled by connection close
         }
-        _ => {
-            // Ignore other message types from client
+        ClientMessage::Join { .. } => {
+            warn!("Ignoring Join from a member that already joined.");
         }
     }
 
     Ok(())
 }
 
-// // async fn forward_signaling(
-// //     room_id: &RoomId,
-// //     from: &str,
-// //     _to: &str,
-// //     payload: SignalingPayload,
-// //     state: &AppState,
-// // ) {
-// //     let rooms = state.rooms.read().await;
-// //     if let Some(room) = rooms.get(room_id) {
-// //         // Broadcast signaling message - recipient will filter by 'to' field
-// //         room.broadcast(WsMessage::SignalingMessage {
-// //             from: from.to_string(),
-// //             payload,
-// //         });
-// //     }
-// // }
+/// Kick or ban a member on behalf of the room owner
+async fn remove_member(
+    member: &WsMember,
+    target: &Username,
+    ban: bool,
+    state: &AppState,
+    sender: &mut (impl Sink<Message> + Unpin),
+) {
+    if !member.is_owner {
+        send_ws_error(state, sender, member.encoding, ErrorKind::NotOwner).await;
+        return;
+    }
+    if target == &member.username {
+        return;
+    }
+    state.sync_room(&member.room_id).await;
+    let result = {
+        let mut rooms = state.rooms.write().await;
+        match rooms.get_mut(&member.room_id) {
+            Some(room) => room.remove_member(target, ban).map(|token| {
+                state.save_room(room);
+                state.delete_member(&member.room_id, &token);
+            }),
+            None => Err(ErrorKind::RoomNotFound),
+        }
+    };
+    if let Err(error) = result {
+        send_ws_error(state, sender, member.encoding, error).await;
+    }
+}
+
+/// Relay a signaling payload to the addressed member only.
+/// Replies to the sender with an error if the recipient is unknown or offline.
+async fn forward_signaling(
+    member: &WsMember,
+    to: &str,
+    payload: SignalingPayload,
+    state: &AppState,
+    sender: &mut (impl Sink<Message> + Unpin),
+) {
+    let relayed = state.metrics.relayed_signaling(&payload);
+    let message = ServerMessage::SignalingMessage {
+        from: member.username.to_string(),
+        payload,
+    };
+    let mut result = relay_in_room(state, &member.room_id, to, message.clone()).await;
+    // The recipient may have joined or come back through another instance sharing the store
+    if state.bus.is_shared()
+        && matches!(
+            result,
+            Err(ErrorKind::MemberNotFound { .. } | ErrorKind::MemberOffline { .. })
+        )
+    {
+        state.sync_room(&member.room_id).await;
+        result = relay_in_room(state, &member.room_id, to, message).await;
+    }
+    match result {
+        Ok(()) => {
+            relayed.inc();
+        }
+        Err(error) => {
+            debug!("Failed to relay signaling message to {to}: {error:?}");
+            send_ws_error(state, sender, member.encoding, error).await;
+        }
+    }
+}
+
+async fn relay_in_room(
+    state: &AppState,
+    room_id: &RoomId,
+    to: &str,
+    message: ServerMessage,
+) -> Result<(), ErrorKind> {
+    let rooms = state.rooms.read().await;
+    rooms
+        .get(room_id)
+        .ok_or(ErrorKind::RoomNotFound)?
+        .relay(to, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api,
+        bus::{BrokerBus, FakeBroker, LocalBus, ROOM_CHANNEL_CAPACITY},
+        config::{Config, HeartbeatConfig, SessionConfig},
+        room::Room,
+        storage::MemoryStore,
+    };
+    use tokio_tungstenite::tungstenite;
+
+    type Client = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    fn decode_sent(sent: &[Message]) -> Vec<ServerMessage> {
+        sent.iter()
+            .map(|frame| decode_frame(frame).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn lagged_member_gets_room_state_again() {
+        let state = AppState::default();
+        let mut room = Room::default();
+        let (_, alice) = room.add_member("alice".parse().unwrap(), true).unwrap();
+        let (_, bob) = room.add_member("bob".parse().unwrap(), true).unwrap();
+        let mut room_rx = room.subscribe();
+        room.set_sharing(&bob, true);
+        for i in 0..=ROOM_CHANNEL_CAPACITY {
+            room.broadcast(ServerMessage::Chat {
+                from: "bob".parse().unwrap(),
+                message: i.to_string(),
+            });
+        }
+        let room_id = room.id.clone();
+        state.rooms.write().await.insert(room_id.clone(), room);
+
+        let Err(broadcast::error::RecvError::Lagged(skipped)) = room_rx.recv().await else {
+            panic!("expected the member to lag behind");
+        };
+        let mut sent = Vec::new();
+        resync_lagged_member(&state, &room_id, &alice, Encoding::Json, skipped, &mut sent).await;
+
+        let messages = decode_sent(&sent);
+        let [ServerMessage::MemberList { members }] = messages.as_slice() else {
+            panic!("expected a member list, got {messages:?}");
+        };
+        let sharers: Vec<&str> = members
+            .iter()
+            .filter(|m| m.is_sharing)
+            .map(|m| m.username.as_str())
+            .collect();
+        assert_eq!(sharers, ["bob"]);
+        assert_eq!(state.metrics.broadcast_lags.get(), 1);
+    }
+
+    /// Serve the API on a free local port
+    async fn serve(state: AppState) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = api::router()
+            .with_state(state)
+            .into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    #[tokio::test]
+    async fn heartbeat_closes_connections_that_stop_answering_pings() {
+        let config = Config {
+            heartbeat: HeartbeatConfig {
+                interval_secs: 1,
+                max_missed_pongs: 1,
+            },
+            session: SessionConfig {
+                reconnect_grace_secs: 0,
+            },
+            ..Config::default()
+        };
+        let state = AppState::new(
+            &config,
+            Arc::new(MemoryStore::default()),
+            Arc::new(LocalBus::default()),
+        )
+        .unwrap();
+        let room = Room::default();
+        let room_id = room.id.clone();
+        state.rooms.write().await.insert(room_id.clone(), room);
+        let addr = serve(state.clone()).await;
+
+        let url = format!("ws://{addr}/api/rooms/{room_id}/ws");
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let join = ClientMessage::Join {
+            request: JoinRequest::WithUsername {
+                username: "alice".parse().unwrap(),
+                password: None,
+            },
+            protocol: ProtocolInfo::current(Vec::new()),
+        };
+        let join = serde_json::to_string(&join).unwrap();
+        ws.send(tungstenite::Message::text(join)).await.unwrap();
+        let joined_at = chrono::Utc::now();
+
+        // Reading answers the pings, the first pong is recorded as a sign of life
+        let _ = timeout(Duration::from_millis(1500), async {
+            while ws.next().await.is_some() {}
+        })
+        .await;
+        {
+            let rooms = state.rooms.read().await;
+            let alice = rooms[&room_id].members.values().next().unwrap();
+            assert!(alice.is_online());
+            assert!(alice.last_seen() > joined_at);
+        }
+
+        // Stop reading, the next ping goes unanswered and the one after closes the connection
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        let rooms = state.rooms.read().await;
+        assert!(!rooms[&room_id].members.values().next().unwrap().is_online());
+    }
+
+    async fn send_client(ws: &mut Client, msg: ClientMessage) {
+        let msg = serde_json::to_string(&msg).unwrap();
+        ws.send(tungstenite::Message::text(msg)).await.unwrap();
+    }
+
+    /// Wait for the first server message `matches` accepts, skipping the others
+    async fn expect<T>(ws: &mut Client, mut matches: impl FnMut(ServerMessage) -> Option<T>) -> T {
+        let next = async {
+            loop {
+                match ws.next().await {
+                    Some(Ok(tungstenite::Message::Text(text))) => {
+                        if let Some(found) = matches(codec::decode_text(&text).unwrap()) {
+                            return found;
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    other => panic!("connection ended: {other:?}"),
+                }
+            }
+        };
+        timeout(Duration::from_secs(5), next)
+            .await
+            .expect("timed out waiting for a server message")
+    }
+
+    /// Connect to a room and ask to join it with a username
+    async fn send_join(
+        addr: SocketAddr,
+        room_id: &RoomId,
+        username: &str,
+        capabilities: Vec<Capability>,
+    ) -> Client {
+        let url = format!("ws://{addr}/api/rooms/{room_id}/ws");
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let join = ClientMessage::Join {
+            request: JoinRequest::WithUsername {
+                username: username.parse().unwrap(),
+                password: None,
+            },
+            protocol: ProtocolInfo::current(capabilities),
+        };
+        send_client(&mut ws, join).await;
+        ws
+    }
+
+    /// Join a room with a username, returns the socket and the username the member got
+    async fn join_as(addr: SocketAddr, room_id: &RoomId, username: &str) -> (Client, Username) {
+        let mut ws = send_join(addr, room_id, username, Vec::new()).await;
+        let username = expect(&mut ws, |msg| match msg {
+            ServerMessage::JoinedAs { username, .. } => Some(username),
+            ServerMessage::Error(error) => panic!("join refused: {error:?}"),
+            _ => None,
+        })
+        .await;
+        (ws, username)
+    }
+
+    #[tokio::test]
+    async fn instances_sharing_store_and_bus_share_rooms() {
+        let store = Arc::new(MemoryStore::default());
+        let broker = FakeBroker::default();
+        let instance = || {
+            let bus = Arc::new(BrokerBus::new(broker.clone(), "rooms".to_string()));
+            AppState::new(&Config::default(), store.clone(), bus).unwrap()
+        };
+        let (first, second) = (instance(), instance());
+        let room = Room::new(None, &first.password_params, None, 10, first.bus.clone()).unwrap();
+        let room_id = room.id.clone();
+        first.save_room(&room);
+        first.rooms.write().await.insert(room_id.clone(), room);
+        first.flush_store().await;
+        let (first_addr, second_addr) = (serve(first).await, serve(second).await);
+
+        let (mut alice, _) = join_as(first_addr, &room_id, "alice").await;
+        // Created on the first instance, joined through the second under a name unique room-wide
+        let (mut bob, bob_name) = join_as(second_addr, &room_id, "alice").await;
+        assert_eq!(bob_name.as_str(), "alice1");
+        let members = expect(&mut bob, |msg| match msg {
+            ServerMessage::MemberList { members } => Some(members),
+            _ => None,
+        })
+        .await;
+        assert!(
+            members
+                .iter()
+                .any(|m| m.username.as_str() == "alice" && m.is_online)
+        );
+        let joined = expect(&mut alice, |msg| match msg {
+            ServerMessage::MemberJoined { username } => Some(username),
+            _ => None,
+        })
+        .await;
+        assert_eq!(joined, bob_name);
+
+        let chat = ClientMessage::ChatMessage {
+            message: "hello".to_string(),
+        };
+        send_client(&mut alice, chat).await;
+        let chat = expect(&mut bob, |msg| match msg {
+            ServerMessage::Chat { from, message } => Some((from.to_string(), message)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(chat, ("alice".to_string(), "hello".to_string()));
+
+        let offer = ClientMessage::Offer {
+            to: "alice1".to_string(),
+            sdp: "offer".to_string(),
+        };
+        send_client(&mut alice, offer).await;
+        let signal = |msg| match msg {
+            ServerMessage::SignalingMessage { from, payload } => Some((from, payload)),
+            ServerMessage::Error(error) => panic!("signaling failed: {error:?}"),
+            _ => None,
+        };
+        let (from, payload) = expect(&mut bob, signal).await;
+        assert_eq!(from, "alice");
+        assert_eq!(
+            payload,
+            SignalingPayload::Offer {
+                sdp: "offer".to_string()
+            }
+        );
+
+        let answer = ClientMessage::Answer {
+            to: "alice".to_string(),
+            sdp: "answer".to_string(),
+        };
+        send_client(&mut bob, answer).await;
+        let (from, payload) = expect(&mut alice, signal).await;
+        assert_eq!(from, "alice1");
+        assert_eq!(
+            payload,
+            SignalingPayload::Answer {
+                sdp: "answer".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn sfu_rooms_refuse_clients_without_sfu_support() {
+        let state = AppState::default();
+        let sfu = Arc::new(Sfu::new(&state.sfu_config).unwrap());
+        let room = Room::new(
+            None,
+            &state.password_params,
+            Some(sfu),
+            10,
+            state.bus.clone(),
+        );
+        let room = room.unwrap();
+        let room_id = room.id.clone();
+        state.rooms.write().await.insert(room_id.clone(), room);
+        let addr = serve(state.clone()).await;
+
+        let mut ws = send_join(addr, &room_id, "alice", Vec::new()).await;
+        let error = expect(&mut ws, |msg| match msg {
+            ServerMessage::Error(error) => Some(error),
+            _ => None,
+        })
+        .await;
+        assert!(matches!(error, ErrorKind::IncompatibleProtocol { .. }));
+        assert!(state.rooms.read().await[&room_id].members.is_empty());
+
+        let mut ws = send_join(addr, &room_id, "alice", vec![Capability::Sfu]).await;
+        expect(&mut ws, |msg| match msg {
+            ServerMessage::JoinedAs { .. } => Some(()),
+            ServerMessage::Error(error) => panic!("join refused: {error:?}"),
+            _ => None,
+        })
+        .await;
+    }
+}
@@ -0,0 +1,267 @@
+//! Joins a room and publishes video into it without a browser
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, atomic::AtomicBool},
+    time::Duration,
+};
+
+use inpixly_client::{Client, Disconnect, Error, FileTokenStore, RoomConnection};
+use inpixly_shared::{ErrorKind, Password, RoomId, Username};
+use tokio::select;
+use tracing::{info, warn};
+use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use webrtc::{
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    track::track_local::track_local_static_sample::TrackLocalStaticSample,
+};
+
+use crate::{publisher::Publisher, source::SourceOptions};
+
+mod publisher;
+mod source;
+
+const USAGE: &str = "\
+Usage: inpixly-share [OPTIONS] <SERVER_URL> <ROOM_ID>
+
+Options:
+  --source <SOURCE>      test-pattern, or a .y4m or .ivf file [default: test-pattern]
+  --username <NAME>      Username to join with [default: share]
+  --password <PASSWORD>  Password of the room
+  --tokens <PATH>        Keep member tokens in this file to rejoin as the same member
+  --size <WxH>           Size of the test pattern [default: 1280x720]
+  --fps <FPS>            Frame rate of the test pattern [default: 30]
+  --loop                 Start files over once they end
+
+The test pattern and .y4m files are sent as AV1, .ivf files in the codec they hold.
+Share a VP8 .ivf file with viewers whose browsers cannot decode AV1.";
+
+/// Used when the server restarts without saying when it will be back
+const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest wait between attempts to rejoin while the server cannot be reached
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Args {
+    server_url: String,
+    room_id: RoomId,
+    source: String,
+    username: Username,
+    password: Option<Password>,
+    tokens: Option<PathBuf>,
+    source_options: SourceOptions,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut positional = Vec::new();
+    let mut source = "test-pattern".to_string();
+    let mut username = "share".to_string();
+    let mut password = None;
+    let mut tokens = None;
+    let mut source_options = SourceOptions {
+        width: 1280,
+        height: 720,
+        fps: 30,
+        looping: false,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow::anyhow!("{arg} needs a value"))
+        };
+        match arg.as_str() {
+            "--source" => source = value()?,
+            "--username" => username = value()?,
+            "--password" => password = Some(value()?),
+            "--tokens" => tokens = Some(PathBuf::from(value()?)),
+            "--size" => {
+                let size = value()?;
+                let (width, height) = size
+                    .split_once('x')
+                    .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                    .ok_or_else(|| anyhow::anyhow!("Invalid size {size}, expected WxH"))?;
+                source_options.width = width;
+                source_options.height = height;
+            }
+            "--fps" => source_options.fps = value()?.parse()?,
+            "--loop" => source_options.looping = true,
+            _ if arg.starts_with("--") => anyhow::bail!("Unknown option {arg}"),
+            _ => positional.push(arg),
+        }
+    }
+    let [server_url, room_id] = <[String; 2]>::try_from(positional)
+        .map_err(|_| anyhow::anyhow!("Expected a server URL and a room id"))?;
+    Ok(Args {
+        server_url,
+        room_id: room_id.parse()?,
+        source,
+        username: username.parse()?,
+        password: password.map(|password| password.parse()).transpose()?,
+        tokens,
+        source_options,
+    })
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    let mut client = Client::new(&args.server_url)?;
+    if let Some(path) = &args.tokens {
+        client = client.with_token_store(FileTokenStore::open(path)?);
+    }
+    let room = client.room_info(&args.room_id).await?;
+    anyhow::ensure!(room.exists, "Room {} does not exist", args.room_id);
+
+    let source = source::open(&args.source, &args.source_options)?;
+    let track = Arc::new(TrackLocalStaticSample::new(
+        RTCRtpCodecCapability {
+            mime_type: source.codec().mime_type().to_string(),
+            ..Default::default()
+        },
+        "video".to_string(),
+        "inpixly-share".to_string(),
+    ));
+    let keyframe = Arc::new(AtomicBool::new(false));
+    // The source keeps playing across reconnects, viewers ask for a keyframe when they come back
+    let mut playing = tokio::spawn(source::play(
+        source,
+        Arc::clone(&track),
+        Arc::clone(&keyframe),
+    ));
+
+    loop {
+        let connection = select! {
+            connection = join_with_retry(&client, &args) => connection?,
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        };
+        info!(
+            "Joined room {} as {}.",
+            args.room_id, connection.joined.username
+        );
+        let sender = connection.sender.clone();
+        let publisher = Publisher::new(
+            &connection.joined,
+            room.mode,
+            connection.sender,
+            Arc::clone(&track),
+            Arc::clone(&keyframe),
+        )?;
+        let run = publisher.run(connection.events);
+        tokio::pin!(run);
+
+        let disconnect = select! {
+            disconnect = &mut run => disconnect,
+            result = &mut playing => {
+                let _ = sender.leave();
+                run.await;
+                result??;
+                info!("Source ended.");
+                return Ok(());
+            }
+            _ = tokio::signal::ctrl_c() => {
+                let _ = sender.leave();
+                run.await;
+                return Ok(());
+            }
+        };
+        let delay = match disconnect {
+            Disconnect::ServerGoingDown {
+                reconnect_after_secs,
+            } => reconnect_after_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_RECONNECT_DELAY),
+            Disconnect::Closed => DEFAULT_RECONNECT_DELAY,
+            Disconnect::Removed { banned: true } => anyhow::bail!("Banned from the room"),
+            Disconnect::Removed { banned: false } => anyhow::bail!("Kicked from the room"),
+            Disconnect::TakenOver => anyhow::bail!("Joined the room from somewhere else"),
+        };
+        info!("Disconnected, rejoining in {}s.", delay.as_secs());
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Join, retrying with a growing delay while the server cannot be reached.
+/// Gives up once the server refuses us, as when banned or the room is gone.
+async fn join_with_retry(client: &Client, args: &Args) -> anyhow::Result<RoomConnection> {
+    let mut delay = DEFAULT_RECONNECT_DELAY;
+    loop {
+        match join(client, args).await {
+            Ok(connection) => return Ok(connection),
+            Err(e @ (Error::Http(_) | Error::WebSocket(_) | Error::Closed)) => {
+                warn!("Failed to join: {e}, retrying in {}s.", delay.as_secs());
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Rejoin as the member of a stored token, or join as a new member
+async fn join(client: &Client, args: &Args) -> Result<RoomConnection, Error> {
+    let stored = client
+        .tokens(&args.room_id)
+        .is_some_and(|tokens| tokens.member_token.is_some());
+    if stored {
+        match client.join(&args.room_id).await {
+            Err(Error::Rejected(ErrorKind::TokenNotFound)) => {
+                info!("Stored token expired, joining as a new member.");
+            }
+            result => return result,
+        }
+    }
+    client
+        .join_as(&args.room_id, args.username.clone(), args.password.clone())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> anyhow::Result<Args> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_options_in_any_order() {
+        let args = args(&[
+            "--size",
+            "640x360",
+            "http://localhost:3000",
+            "--source",
+            "clip.ivf",
+            "550e8400-e29b-41d4-a716-446655440000",
+            "--loop",
+        ])
+        .unwrap();
+        assert_eq!(args.server_url, "http://localhost:3000");
+        assert_eq!(args.source, "clip.ivf");
+        assert_eq!(args.username.as_str(), "share");
+        assert_eq!(
+            (args.source_options.width, args.source_options.height),
+            (640, 360)
+        );
+        assert!(args.source_options.looping);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(args(&["http://localhost:3000"]).is_err());
+        assert!(args(&["--size", "640", "http://localhost:3000", "room"]).is_err());
+        assert!(args(&["--frobnicate", "http://localhost:3000", "room"]).is_err());
+    }
+}
@@ -0,0 +1,546 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use inpixly_client::{Disconnect, Event, Joined, RoomEvents, RoomSender};
+use inpixly_shared::{MemberInfo, RoomMode, SignalingPayload, Username};
+use tracing::{debug, error, info, warn};
+use webrtc::{
+    api::{
+        API, APIBuilder, interceptor_registry::register_default_interceptors,
+        media_engine::MediaEngine, setting_engine::SettingEngine,
+    },
+    ice_transport::{ice_candidate::RTCIceCandidateInit, ice_server::RTCIceServer},
+    interceptor::registry::Registry,
+    peer_connection::{
+        RTCPeerConnection, configuration::RTCConfiguration,
+        peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, signaling_state::RTCSignalingState,
+    },
+    rtcp::payload_feedbacks::{
+        full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+    },
+    track::track_local::{TrackLocal, track_local_static_sample::TrackLocalStaticSample},
+};
+
+/// The other end of a peer connection
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Remote {
+    /// A member of a mesh room
+    Member(Username),
+    /// The forwarding unit of an SFU room
+    Sfu,
+}
+
+impl Remote {
+    fn signal(&self, sender: &RoomSender, payload: SignalingPayload) {
+        let result = match self {
+            Self::Member(username) => sender.signal(username, payload),
+            Self::Sfu => sender.sfu(payload),
+        };
+        if let Err(e) = result {
+            debug!("Failed to send signaling to {self:?}: {e}");
+        }
+    }
+}
+
+/// Publishes one video track to everyone in a room.
+/// In mesh rooms every member gets its own peer connection, in SFU rooms the server does.
+pub struct Publisher {
+    api: API,
+    config: RTCConfiguration,
+    mode: RoomMode,
+    username: Username,
+    sender: RoomSender,
+    track: Arc<TrackLocalStaticSample>,
+    keyframe: Arc<AtomicBool>,
+    peers: HashMap<Remote, Arc<RTCPeerConnection>>,
+}
+
+impl Publisher {
+    /// `keyframe` is set whenever a viewer needs a keyframe to start decoding
+    pub fn new(
+        joined: &Joined,
+        mode: RoomMode,
+        sender: RoomSender,
+        track: Arc<TrackLocalStaticSample>,
+        keyframe: Arc<AtomicBool>,
+    ) -> anyhow::Result<Self> {
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs()?;
+        let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+        let mut setting_engine = SettingEngine::default();
+        // Lets a sharer and a viewer on the same machine find each other, as in CI
+        setting_engine.set_include_loopback_candidate(true);
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(setting_engine)
+            .build();
+
+        let config = RTCConfiguration {
+            ice_servers: joined
+                .ice_servers
+                .iter()
+                .map(|server| RTCIceServer {
+                    urls: server.urls.clone(),
+                    username: server.username.clone().unwrap_or_default(),
+                    credential: server.credential.clone().unwrap_or_default(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        Ok(Self {
+            api,
+            config,
+            mode,
+            username: joined.username.clone(),
+            sender,
+            track,
+            keyframe,
+            peers: HashMap::new(),
+        })
+    }
+
+    /// Answer the room's signaling until the connection ends
+    pub async fn run(mut self, mut events: RoomEvents) -> Disconnect {
+        if let Err(e) = self.sender.set_sharing(true) {
+            debug!("Failed to announce sharing: {e}");
+        }
+        if self.mode == RoomMode::Sfu {
+            // The forwarding unit never offers first, unlike browsers in mesh rooms
+            if let Err(e) = self.offer_to_sfu().await {
+                warn!("Failed to publish to the SFU: {e:#}");
+            }
+        }
+
+        let disconnect = loop {
+            let Some(event) = events.next_event().await else {
+                break Disconnect::Closed;
+            };
+            let result = match event {
+                Event::MemberList(members) => {
+                    self.drop_offline(&members).await;
+                    Ok(())
+                }
+                Event::MemberLeft(username) => {
+                    self.remove(&Remote::Member(username)).await;
+                    Ok(())
+                }
+                Event::Signaling { from, payload } => match from.parse() {
+                    Ok(from) => self.signal(Remote::Member(from), payload).await,
+                    Err(_) => {
+                        warn!("Ignoring signaling from invalid username {from}.");
+                        Ok(())
+                    }
+                },
+                Event::Sfu(payload) => self.signal(Remote::Sfu, payload).await,
+                Event::Announcement(message) => {
+                    info!("Announcement: {message}");
+                    Ok(())
+                }
+                Event::Error(error) => {
+                    warn!("Server reported an error: {error:?}");
+                    Ok(())
+                }
+                Event::Disconnected(disconnect) => break disconnect,
+                // Browsers offer to everyone who joins, there is nothing to start here
+                Event::MemberJoined(_)
+                | Event::Chat { .. }
+                | Event::ChatHistory { .. }
+                | Event::SharingChanged { .. } => Ok(()),
+            };
+            if let Err(e) = result {
+                warn!("Signaling failed: {e:#}");
+            }
+        };
+
+        for (_, pc) in self.peers.drain() {
+            let _ = pc.close().await;
+        }
+        disconnect
+    }
+
+    async fn signal(&mut self, remote: Remote, payload: SignalingPayload) -> anyhow::Result<()> {
+        match payload {
+            SignalingPayload::Offer { sdp } => {
+                let pc = match self.peers.get(&remote) {
+                    Some(pc) => Arc::clone(pc),
+                    None => self.add_peer(remote.clone()).await?,
+                };
+                if pc.signaling_state() != RTCSignalingState::Stable {
+                    // We are the impolite side, browsers roll back their own offer
+                    debug!("Ignoring colliding offer from {remote:?}.");
+                    return Ok(());
+                }
+                if !sdp.lines().any(|line| line.starts_with("m=")) {
+                    // Browsers open with an offer without media sections, which webrtc-rs
+                    // cannot apply. Offering our track makes them roll it back and answer.
+                    return offer(&pc, &remote, &self.sender).await;
+                }
+                pc.set_remote_description(RTCSessionDescription::offer(sdp)?)
+                    .await?;
+                let answer = pc.create_answer(None).await?;
+                pc.set_local_description(answer.clone()).await?;
+                remote.signal(&self.sender, SignalingPayload::Answer { sdp: answer.sdp });
+
+                // The offer only carried media of the remote, our track needs another round
+                if !is_publishing(&pc).await {
+                    offer(&pc, &remote, &self.sender).await?;
+                }
+            }
+            SignalingPayload::Answer { sdp } => {
+                if let Some(pc) = self.peers.get(&remote) {
+                    let mime_type = self.track.codec().mime_type;
+                    if !accepts_codec(&sdp, &mime_type) {
+                        error!(
+                            "{remote:?} cannot decode {mime_type} and will not see the video. \
+                             The test pattern and .y4m files are sent as AV1, \
+                             share a VP8 .ivf file instead."
+                        );
+                    }
+                    pc.set_remote_description(RTCSessionDescription::answer(sdp)?)
+                        .await?;
+                }
+            }
+            SignalingPayload::IceCandidate { candidate } => {
+                if let Some(pc) = self.peers.get(&remote) {
+                    pc.add_ice_candidate(RTCIceCandidateInit {
+                        candidate,
+                        sdp_mid: Some("0".to_string()),
+                        sdp_mline_index: Some(0),
+                        username_fragment: None,
+                    })
+                    .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn offer_to_sfu(&mut self) -> anyhow::Result<()> {
+        let pc = self.add_peer(Remote::Sfu).await?;
+        offer(&pc, &Remote::Sfu, &self.sender).await
+    }
+
+    /// Create a peer connection sending our track
+    async fn add_peer(&mut self, remote: Remote) -> anyhow::Result<Arc<RTCPeerConnection>> {
+        let pc = Arc::new(self.api.new_peer_connection(self.config.clone()).await?);
+
+        let sender = self.sender.clone();
+        let ice_remote = remote.clone();
+        pc.on_ice_candidate(Box::new(move |candidate| {
+            let Some(candidate) = candidate else {
+                return Box::pin(async {});
+            };
+            match candidate.to_json() {
+                Ok(init) => ice_remote.signal(
+                    &sender,
+                    SignalingPayload::IceCandidate {
+                        candidate: init.candidate,
+                    },
+                ),
+                Err(e) => warn!("Failed to serialize ICE candidate: {e}"),
+            }
+            Box::pin(async {})
+        }));
+
+        let keyframe = Arc::clone(&self.keyframe);
+        let state_remote = remote.clone();
+        pc.on_peer_connection_state_change(Box::new(move |state| {
+            info!("Connection to {state_remote:?} is {state}.");
+            if state == RTCPeerConnectionState::Connected {
+                keyframe.store(true, Ordering::Relaxed);
+            }
+            Box::pin(async {})
+        }));
+
+        let rtp_sender = pc
+            .add_track(Arc::clone(&self.track) as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+        // Viewers ask for a keyframe when they lost packets they cannot decode without
+        let keyframe = Arc::clone(&self.keyframe);
+        tokio::spawn(async move {
+            while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+                let wants_keyframe = packets.iter().any(|p| {
+                    p.as_any().is::<PictureLossIndication>() || p.as_any().is::<FullIntraRequest>()
+                });
+                if wants_keyframe {
+                    keyframe.store(true, Ordering::Relaxed);
+                }
+            }
+        });
+
+        info!("Publishing to {remote:?} as {}.", self.username);
+        self.peers.insert(remote, Arc::clone(&pc));
+        Ok(pc)
+    }
+
+    /// Close the connections to members that went offline while we missed their `MemberLeft`
+    async fn drop_offline(&mut self, members: &[MemberInfo]) {
+        let offline: Vec<Remote> = self
+            .peers
+            .keys()
+            .filter(|remote| match remote {
+                Remote::Member(username) => !members
+                    .iter()
+                    .any(|member| member.is_online && member.username == *username),
+                Remote::Sfu => false,
+            })
+            .cloned()
+            .collect();
+        for remote in offline {
+            self.remove(&remote).await;
+        }
+    }
+
+    async fn remove(&mut self, remote: &Remote) {
+        if let Some(pc) = self.peers.remove(remote)
+            && let Err(e) = pc.close().await
+        {
+            debug!("Failed to close connection to {remote:?}: {e}");
+        }
+    }
+}
+
+/// Whether our track has been negotiated into a media section
+async fn is_publishing(pc: &RTCPeerConnection) -> bool {
+    for transceiver in pc.get_transceivers().await {
+        if transceiver.mid().is_some() && transceiver.sender().await.track().await.is_some() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether the video sections of a session description offer the codec of `mime_type`.
+/// Descriptions without video say nothing about it.
+fn accepts_codec(sdp: &str, mime_type: &str) -> bool {
+    let Some((_, codec)) = mime_type.split_once('/') else {
+        return true;
+    };
+    let mut has_video = false;
+    let mut in_video = false;
+    for line in sdp.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            in_video = media.starts_with("video");
+            has_video |= in_video;
+        } else if in_video
+            && let Some((_, encoding)) = line
+                .strip_prefix("a=rtpmap:")
+                .and_then(|rtpmap| rtpmap.split_once(' '))
+            && encoding
+                .split('/')
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case(codec))
+        {
+            return true;
+        }
+    }
+    !has_video
+}
+
+async fn offer(pc: &RTCPeerConnection, remote: &Remote, sender: &RoomSender) -> anyhow::Result<()> {
+    let offer = pc.create_offer(None).await?;
+    pc.set_local_description(offer.clone()).await?;
+    remote.signal(sender, SignalingPayload::Offer { sdp: offer.sdp });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use inpixly_client::Client;
+    use inpixly_server::state::AppState;
+    use inpixly_shared::CreateRoomRequest;
+    use tokio::{net::TcpListener, sync::mpsc};
+    use webrtc::{
+        api::media_engine::MIME_TYPE_AV1, rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    };
+
+    use super::*;
+    use crate::source::{self, TestPattern};
+
+    async fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = inpixly_server::api::router().with_state(AppState::default());
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+        format!("http://{addr}")
+    }
+
+    /// Peer connection factory of the viewer, its own so nothing is shared with the sharer
+    fn viewer_api() -> API {
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs().unwrap();
+        let mut setting_engine = SettingEngine::default();
+        setting_engine.set_include_loopback_candidate(true);
+        APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_setting_engine(setting_engine)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn browser_receives_published_video() {
+        let url = serve().await;
+        let viewer_client = Client::new(&url).unwrap();
+        let room = viewer_client
+            .create_room(&CreateRoomRequest {
+                username: "viewer".parse().unwrap(),
+                password: None,
+                mode: RoomMode::Mesh,
+            })
+            .await
+            .unwrap();
+        let mut viewer = viewer_client.join(&room.room_id).await.unwrap();
+
+        let sharer = Client::new(&url)
+            .unwrap()
+            .join_as(&room.room_id, "sharer".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_AV1.to_string(),
+                ..Default::default()
+            },
+            "video".to_string(),
+            "inpixly-share".to_string(),
+        ));
+        let keyframe = Arc::new(AtomicBool::new(false));
+        let pattern = Box::new(TestPattern::new(64, 48, 30).unwrap());
+        tokio::spawn(source::play(
+            pattern,
+            Arc::clone(&track),
+            Arc::clone(&keyframe),
+        ));
+        let publisher = Publisher::new(
+            &sharer.joined,
+            RoomMode::Mesh,
+            sharer.sender,
+            track,
+            keyframe,
+        )
+        .unwrap();
+        tokio::spawn(publisher.run(sharer.events));
+
+        // Negotiate like the frontend: offer without media to whoever joins, answer the rest
+        let sharer_name: Username = "sharer".parse().unwrap();
+        let api = viewer_api();
+        let pc = Arc::new(
+            api.new_peer_connection(RTCConfiguration::default())
+                .await
+                .unwrap(),
+        );
+        let (track_tx, mut track_rx) = mpsc::unbounded_channel();
+        pc.on_track(Box::new(move |remote, _, _| {
+            let track_tx = track_tx.clone();
+            Box::pin(async move {
+                let received = remote.read_rtp().await.is_ok();
+                let _ = track_tx.send((remote.codec().capability.mime_type, received));
+            })
+        }));
+        let sender = viewer.sender.clone();
+        let to = sharer_name.clone();
+        pc.on_ice_candidate(Box::new(move |candidate| {
+            if let Some(init) = candidate.and_then(|candidate| candidate.to_json().ok()) {
+                let _ = sender.signal(
+                    &to,
+                    SignalingPayload::IceCandidate {
+                        candidate: init.candidate,
+                    },
+                );
+            }
+            Box::pin(async {})
+        }));
+        let viewer_pc = Arc::clone(&pc);
+        tokio::spawn(async move {
+            while let Some(event) = viewer.events.next_event().await {
+                match event {
+                    Event::MemberJoined(username) if username == sharer_name => {
+                        // Never applied locally, webrtc-rs cannot roll it back like
+                        // browsers do once the sharer offers in return
+                        let offer = viewer_pc.create_offer(None).await.unwrap();
+                        viewer
+                            .sender
+                            .signal(&username, SignalingPayload::Offer { sdp: offer.sdp })
+                            .unwrap();
+                    }
+                    Event::Signaling { from, payload } => match payload {
+                        SignalingPayload::Offer { sdp } => {
+                            let offer = RTCSessionDescription::offer(sdp).unwrap();
+                            viewer_pc.set_remote_description(offer).await.unwrap();
+                            let answer = viewer_pc.create_answer(None).await.unwrap();
+                            viewer_pc
+                                .set_local_description(answer.clone())
+                                .await
+                                .unwrap();
+                            viewer
+                                .sender
+                                .signal(
+                                    &from.parse().unwrap(),
+                                    SignalingPayload::Answer { sdp: answer.sdp },
+                                )
+                                .unwrap();
+                        }
+                        SignalingPayload::Answer { sdp } => {
+                            let answer = RTCSessionDescription::answer(sdp).unwrap();
+                            viewer_pc.set_remote_description(answer).await.unwrap();
+                        }
+                        SignalingPayload::IceCandidate { candidate } => {
+                            let _ = viewer_pc
+                                .add_ice_candidate(RTCIceCandidateInit {
+                                    candidate,
+                                    sdp_mid: Some("0".to_string()),
+                                    sdp_mline_index: Some(0),
+                                    username_fragment: None,
+                                })
+                                .await;
+                        }
+                    },
+                    _ => {}
+                }
+            }
+        });
+
+        let (mime_type, received) = tokio::time::timeout(Duration::from_secs(30), track_rx.recv())
+            .await
+            .expect("viewer never received the published track")
+            .unwrap();
+        assert_eq!(mime_type, MIME_TYPE_AV1);
+        assert!(received);
+    }
+
+    #[test]
+    fn notices_answers_without_our_codec() {
+        let answer = |rtpmap: &str| {
+            format!(
+                "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\nc=IN IP4 0.0.0.0\r\n{rtpmap}\r\n\
+                 m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n"
+            )
+        };
+        assert!(accepts_codec(
+            &answer("a=rtpmap:45 AV1/90000"),
+            MIME_TYPE_AV1
+        ));
+        assert!(!accepts_codec(
+            &answer("a=rtpmap:96 VP8/90000"),
+            MIME_TYPE_AV1
+        ));
+        assert!(accepts_codec("v=0\r\n", MIME_TYPE_AV1));
+    }
+}
@@ -0,0 +1,61 @@
+use rav1e::prelude::*;
+
+/// Real-time AV1 encoding of 8-bit 4:2:0 frames, for sources producing raw video
+pub struct Av1Encoder {
+    context: Context<u8>,
+    width: usize,
+    keyframe: bool,
+}
+
+impl Av1Encoder {
+    /// `frame_rate` in frames per `Rational::den` seconds
+    pub fn new(width: usize, height: usize, frame_rate: Rational) -> anyhow::Result<Self> {
+        let mut config = EncoderConfig::with_speed_preset(10);
+        config.width = width;
+        config.height = height;
+        config.time_base = Rational::new(frame_rate.den, frame_rate.num);
+        config.low_latency = true;
+        // Viewers that missed the keyframe they asked for still start within a few seconds
+        config.max_key_frame_interval = frame_rate.num.div_ceil(frame_rate.den) * 3;
+        config.min_key_frame_interval = 0;
+        // Every frame of lookahead is a frame of latency
+        config.speed_settings.rdo_lookahead_frames = 1;
+        let context = Config::new()
+            .with_encoder_config(config)
+            .new_context()
+            .map_err(|e| anyhow::anyhow!("Invalid encoder configuration: {e}"))?;
+        Ok(Self {
+            context,
+            width,
+            keyframe: false,
+        })
+    }
+
+    pub fn request_keyframe(&mut self) {
+        self.keyframe = true;
+    }
+
+    /// Encode one frame from its planes.
+    /// Returns `None` while the encoder still buffers the first frames.
+    pub fn encode(&mut self, y: &[u8], u: &[u8], v: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut frame = self.context.new_frame();
+        let chroma_width = self.width.div_ceil(2);
+        frame.planes[0].copy_from_raw_u8(y, self.width, 1);
+        frame.planes[1].copy_from_raw_u8(u, chroma_width, 1);
+        frame.planes[2].copy_from_raw_u8(v, chroma_width, 1);
+
+        let parameters = std::mem::take(&mut self.keyframe).then(|| FrameParameters {
+            frame_type_override: FrameTypeOverride::Key,
+            ..Default::default()
+        });
+        self.context.send_frame((frame, parameters))?;
+        loop {
+            match self.context.receive_packet() {
+                Ok(packet) => return Ok(Some(packet.data)),
+                Err(EncoderStatus::Encoded) => continue,
+                Err(EncoderStatus::NeedMoreData) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use webrtc::media::{
+    Error as MediaError,
+    io::ivf_reader::{IVFFileHeader, IVFReader},
+};
+
+use super::{Codec, EncodedFrame, VideoSource};
+
+/// Replays the frames of an IVF file as they are, VP8, VP9 or AV1
+pub struct IvfFile {
+    path: PathBuf,
+    looping: bool,
+    reader: IVFReader<BufReader<File>>,
+    codec: Codec,
+    /// Duration of one unit of the frame timestamps
+    tick: Duration,
+    last_timestamp: Option<u64>,
+}
+
+impl IvfFile {
+    pub fn open(path: &Path, looping: bool) -> anyhow::Result<Self> {
+        let (reader, header) = read_header(path)?;
+        let codec = match &header.four_cc {
+            b"VP80" => Codec::Vp8,
+            b"VP90" => Codec::Vp9,
+            b"AV01" => Codec::Av1,
+            four_cc => anyhow::bail!("Unsupported IVF codec {}", String::from_utf8_lossy(four_cc)),
+        };
+        anyhow::ensure!(
+            header.timebase_numerator > 0 && header.timebase_denominator > 0,
+            "IVF file has no time base"
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            looping,
+            reader,
+            codec,
+            tick: Duration::from_secs(header.timebase_numerator.into())
+                / header.timebase_denominator,
+            last_timestamp: None,
+        })
+    }
+}
+
+fn read_header(path: &Path) -> anyhow::Result<(IVFReader<BufReader<File>>, IVFFileHeader)> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    IVFReader::new(BufReader::new(file))
+        .with_context(|| format!("Failed to read IVF header of {}", path.display()))
+}
+
+impl VideoSource for IvfFile {
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn next_frame(&mut self) -> anyhow::Result<Option<EncodedFrame>> {
+        loop {
+            match self.reader.parse_next_frame() {
+                Ok((data, header)) => {
+                    // Show each frame until the timestamp of the next, assuming a steady rate
+                    let ticks = match self.last_timestamp.replace(header.timestamp) {
+                        Some(last) if header.timestamp > last => header.timestamp - last,
+                        _ => 1,
+                    };
+                    return Ok(Some(EncodedFrame {
+                        data: data.to_vec(),
+                        duration: self.tick * ticks as u32,
+                    }));
+                }
+                Err(MediaError::Io(e)) if e.0.kind() == io::ErrorKind::UnexpectedEof => {
+                    if !self.looping {
+                        return Ok(None);
+                    }
+                    self.reader = read_header(&self.path)?.0;
+                    self.last_timestamp = None;
+                }
+                Err(e) => return Err(e).context("Failed to read IVF frame"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// IVF file of VP8 frames at 30 fps with the given timestamps
+    fn ivf_file(timestamps: &[u64]) -> Vec<u8> {
+        let mut file = Vec::new();
+        file.extend_from_slice(b"DKIF");
+        file.extend_from_slice(&0u16.to_le_bytes());
+        file.extend_from_slice(&32u16.to_le_bytes());
+        file.extend_from_slice(b"VP80");
+        file.extend_from_slice(&640u16.to_le_bytes());
+        file.extend_from_slice(&480u16.to_le_bytes());
+        file.extend_from_slice(&30u32.to_le_bytes());
+        file.extend_from_slice(&1u32.to_le_bytes());
+        file.extend_from_slice(&(timestamps.len() as u32).to_le_bytes());
+        file.extend_from_slice(&0u32.to_le_bytes());
+        for &timestamp in timestamps {
+            file.extend_from_slice(&4u32.to_le_bytes());
+            file.extend_from_slice(&timestamp.to_le_bytes());
+            file.extend_from_slice(&[timestamp as u8; 4]);
+        }
+        file
+    }
+
+    #[test]
+    fn replays_frames_with_their_timing() {
+        let path = std::env::temp_dir().join(format!("inpixly-share-{}.ivf", Uuid::new_v4()));
+        std::fs::write(&path, ivf_file(&[0, 1, 3])).unwrap();
+
+        let mut source = IvfFile::open(&path, false).unwrap();
+        assert_eq!(source.codec(), Codec::Vp8);
+        let frames: Vec<_> = std::iter::from_fn(|| source.next_frame().unwrap()).collect();
+        let tick = Duration::from_secs(1) / 30;
+        assert_eq!(
+            frames.iter().map(|f| f.duration).collect::<Vec<_>>(),
+            vec![tick, tick, tick * 2]
+        );
+        assert_eq!(frames[2].data, vec![3; 4]);
+
+        let mut source = IvfFile::open(&path, true).unwrap();
+        for expected in [0, 1, 3, 0] {
+            assert_eq!(
+                source.next_frame().unwrap().unwrap().data,
+                vec![expected; 4]
+            );
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
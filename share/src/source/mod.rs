@@ -0,0 +1,135 @@
+use std::{
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::{sync::mpsc, time::Instant};
+use tracing::debug;
+use webrtc::{
+    api::media_engine::{MIME_TYPE_AV1, MIME_TYPE_VP8, MIME_TYPE_VP9},
+    media::Sample,
+    track::track_local::track_local_static_sample::TrackLocalStaticSample,
+};
+
+mod encoder;
+mod ivf;
+mod test_pattern;
+mod y4m;
+
+pub use ivf::IvfFile;
+pub use test_pattern::TestPattern;
+pub use y4m::Y4mFile;
+
+/// Video codecs webrtc-rs can packetize and browsers decode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+impl Codec {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Vp8 => MIME_TYPE_VP8,
+            Self::Vp9 => MIME_TYPE_VP9,
+            Self::Av1 => MIME_TYPE_AV1,
+        }
+    }
+}
+
+/// One encoded frame, shown for `duration` before the next one
+#[derive(Debug, Clone)]
+pub struct EncodedFrame {
+    pub data: Vec<u8>,
+    pub duration: Duration,
+}
+
+/// Produces the published video, already encoded.
+/// Called from a blocking thread, one frame at a time at the pace of playback.
+pub trait VideoSource: Send {
+    fn codec(&self) -> Codec;
+
+    /// The next frame, `None` once the source has ended
+    fn next_frame(&mut self) -> anyhow::Result<Option<EncodedFrame>>;
+
+    /// Make the next frame a keyframe so new viewers can start decoding.
+    /// Sources replaying encoded files cannot, their viewers wait for the next one in the file.
+    fn request_keyframe(&mut self) {}
+}
+
+/// How the command line configures sources
+#[derive(Debug, Clone)]
+pub struct SourceOptions {
+    /// Size of the test pattern, files keep their own
+    pub width: usize,
+    pub height: usize,
+    /// Frame rate of the test pattern
+    pub fps: u64,
+    /// Start files over once they end
+    pub looping: bool,
+}
+
+/// Open a source by name: `test-pattern`, or a `.y4m` or `.ivf` file
+pub fn open(name: &str, options: &SourceOptions) -> anyhow::Result<Box<dyn VideoSource>> {
+    if name == "test-pattern" {
+        let pattern = TestPattern::new(options.width, options.height, options.fps)?;
+        return Ok(Box::new(pattern));
+    }
+    let path = Path::new(name);
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("y4m") => Ok(Box::new(Y4mFile::open(path, options.looping)?)),
+        Some("ivf") => Ok(Box::new(IvfFile::open(path, options.looping)?)),
+        _ => anyhow::bail!("Unknown source {name}, expected test-pattern or a .y4m or .ivf file"),
+    }
+}
+
+/// Write the frames of `source` into `track` in real time until the source ends.
+/// Setting `keyframe` asks the source for a keyframe before its next frame.
+pub async fn play(
+    mut source: Box<dyn VideoSource>,
+    track: Arc<TrackLocalStaticSample>,
+    keyframe: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    // Encoding is CPU bound, it runs one frame ahead on a blocking thread
+    let (frames_tx, mut frames_rx) = mpsc::channel(1);
+    let encoding = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        loop {
+            if keyframe.swap(false, Ordering::Relaxed) {
+                source.request_keyframe();
+            }
+            let Some(frame) = source.next_frame()? else {
+                return Ok(());
+            };
+            if frames_tx.blocking_send(frame).is_err() {
+                return Ok(());
+            }
+        }
+    });
+
+    let mut due = Instant::now();
+    while let Some(frame) = frames_rx.recv().await {
+        tokio::time::sleep_until(due).await;
+        let sample = Sample {
+            data: frame.data.into(),
+            duration: frame.duration,
+            ..Default::default()
+        };
+        // Fails when a single viewer went away, the others still want the frame
+        if let Err(e) = track.write_sample(&sample).await {
+            debug!("Failed to write sample: {e}");
+        }
+        // A frame that took too long to encode delays the rest rather than causing a burst
+        due = (due + frame.duration).max(Instant::now());
+    }
+    encoding.await.context("Encoding thread panicked")?
+}
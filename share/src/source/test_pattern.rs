@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use rav1e::prelude::Rational;
+
+use super::{Codec, EncodedFrame, VideoSource, encoder::Av1Encoder};
+
+/// The seven 75% color bars as Y, U and V, white to blue
+const BARS: [(u8, u8, u8); 7] = [
+    (180, 128, 128),
+    (162, 44, 142),
+    (131, 156, 44),
+    (112, 72, 58),
+    (84, 184, 198),
+    (65, 100, 212),
+    (35, 212, 114),
+];
+
+/// Color bars with a white square crossing them every four seconds, so motion and
+/// dropped frames are easy to spot
+pub struct TestPattern {
+    encoder: Av1Encoder,
+    width: usize,
+    height: usize,
+    fps: u64,
+    frame_number: u64,
+    bars: [Vec<u8>; 3],
+}
+
+impl TestPattern {
+    pub fn new(width: usize, height: usize, fps: u64) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            width >= 16 && height >= 16,
+            "Test pattern must be at least 16x16"
+        );
+        anyhow::ensure!(fps > 0, "Frame rate must be positive");
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        let bar = |x: usize, plane_width: usize| BARS[x * BARS.len() / plane_width];
+        let bars = [
+            plane(width, height, |x| bar(x, width).0),
+            plane(chroma_width, chroma_height, |x| bar(x, chroma_width).1),
+            plane(chroma_width, chroma_height, |x| bar(x, chroma_width).2),
+        ];
+        Ok(Self {
+            encoder: Av1Encoder::new(width, height, Rational::new(fps, 1))?,
+            width,
+            height,
+            fps,
+            frame_number: 0,
+            bars,
+        })
+    }
+
+    /// The planes of the current frame
+    fn draw(&self) -> [Vec<u8>; 3] {
+        let mut planes = self.bars.clone();
+        let size = self.height / 4;
+        let travel = self.width - size;
+        let position = self.frame_number % (self.fps * 4);
+        let left = (position as usize * travel) / (self.fps as usize * 4);
+        let top = (self.height - size) / 2;
+        for row in top..top + size {
+            let start = row * self.width + left;
+            planes[0][start..start + size].fill(235);
+        }
+        let chroma_width = self.width.div_ceil(2);
+        for row in top / 2..(top + size) / 2 {
+            let start = row * chroma_width + left / 2;
+            planes[1][start..start + size / 2].fill(128);
+            planes[2][start..start + size / 2].fill(128);
+        }
+        planes
+    }
+}
+
+fn plane(width: usize, height: usize, column: impl Fn(usize) -> u8) -> Vec<u8> {
+    let row: Vec<u8> = (0..width).map(column).collect();
+    row.repeat(height)
+}
+
+impl VideoSource for TestPattern {
+    fn codec(&self) -> Codec {
+        Codec::Av1
+    }
+
+    fn next_frame(&mut self) -> anyhow::Result<Option<EncodedFrame>> {
+        loop {
+            let [y, u, v] = self.draw();
+            self.frame_number += 1;
+            if let Some(data) = self.encoder.encode(&y, &u, &v)? {
+                return Ok(Some(EncodedFrame {
+                    data,
+                    duration: Duration::from_secs(1) / self.fps as u32,
+                }));
+            }
+        }
+    }
+
+    fn request_keyframe(&mut self) {
+        self.encoder.request_keyframe();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn produces_av1_frames() {
+        let mut pattern = TestPattern::new(64, 48, 30).unwrap();
+        for _ in 0..3 {
+            let frame = pattern.next_frame().unwrap().unwrap();
+            assert!(!frame.data.is_empty());
+            assert_eq!(frame.duration, Duration::from_secs(1) / 30);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use rav1e::prelude::Rational;
+use y4m::{Colorspace, Decoder};
+
+use super::{Codec, EncodedFrame, VideoSource, encoder::Av1Encoder};
+
+/// Raw frames of a Y4M file encoded to AV1, only 8-bit 4:2:0 is supported
+pub struct Y4mFile {
+    path: PathBuf,
+    looping: bool,
+    decoder: Decoder<BufReader<File>>,
+    encoder: Av1Encoder,
+    frame_duration: Duration,
+}
+
+impl Y4mFile {
+    pub fn open(path: &Path, looping: bool) -> anyhow::Result<Self> {
+        let decoder = open_decoder(path)?;
+        anyhow::ensure!(
+            matches!(
+                decoder.get_colorspace(),
+                Colorspace::C420
+                    | Colorspace::C420jpeg
+                    | Colorspace::C420paldv
+                    | Colorspace::C420mpeg2
+            ),
+            "Unsupported Y4M colorspace {:?}, convert to 8-bit 4:2:0",
+            decoder.get_colorspace()
+        );
+        let frame_rate = decoder.get_framerate();
+        anyhow::ensure!(
+            frame_rate.num > 0 && frame_rate.den > 0,
+            "Y4M file has no frame rate"
+        );
+        let encoder = Av1Encoder::new(
+            decoder.get_width(),
+            decoder.get_height(),
+            Rational::new(frame_rate.num as u64, frame_rate.den as u64),
+        )?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            looping,
+            decoder,
+            encoder,
+            frame_duration: Duration::from_secs(frame_rate.den as u64) / frame_rate.num as u32,
+        })
+    }
+}
+
+fn open_decoder(path: &Path) -> anyhow::Result<Decoder<BufReader<File>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    y4m::decode(BufReader::new(file))
+        .with_context(|| format!("Failed to read Y4M header of {}", path.display()))
+}
+
+impl VideoSource for Y4mFile {
+    fn codec(&self) -> Codec {
+        Codec::Av1
+    }
+
+    fn next_frame(&mut self) -> anyhow::Result<Option<EncodedFrame>> {
+        loop {
+            let encoded = match self.decoder.read_frame() {
+                Ok(frame) => self.encoder.encode(
+                    frame.get_y_plane(),
+                    frame.get_u_plane(),
+                    frame.get_v_plane(),
+                )?,
+                Err(y4m::Error::EOF) if self.looping => {
+                    self.decoder = open_decoder(&self.path)?;
+                    continue;
+                }
+                Err(y4m::Error::EOF) => return Ok(None),
+                Err(e) => return Err(e).context("Failed to read Y4M frame"),
+            };
+            if let Some(data) = encoded {
+                return Ok(Some(EncodedFrame {
+                    data,
+                    duration: self.frame_duration,
+                }));
+            }
+        }
+    }
+
+    fn request_keyframe(&mut self) {
+        self.encoder.request_keyframe();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn loops_over_raw_frames() {
+        let path = std::env::temp_dir().join(format!("inpixly-share-{}.y4m", Uuid::new_v4()));
+        let mut file = Vec::new();
+        let mut encoder = y4m::encode(32, 32, y4m::Ratio::new(25, 1))
+            .with_colorspace(Colorspace::C420)
+            .write_header(&mut file)
+            .unwrap();
+        for shade in [16, 128] {
+            let (luma, chroma) = (vec![shade; 32 * 32], vec![128; 16 * 16]);
+            encoder
+                .write_frame(&y4m::Frame::new([&luma, &chroma, &chroma], None))
+                .unwrap();
+        }
+        std::fs::write(&path, file).unwrap();
+
+        let mut source = Y4mFile::open(&path, true).unwrap();
+        // More frames than the file has, it starts over instead of ending
+        for _ in 0..5 {
+            let frame = source.next_frame().unwrap().unwrap();
+            assert_eq!(frame.duration, Duration::from_millis(40));
+        }
+        let mut source = Y4mFile::open(&path, false).unwrap();
+        while source.next_frame().unwrap().is_some() {}
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+//! Speed of the binary encoding against JSON, for the messages that make up most traffic.
+//! Throughput is per encoded byte, so the reports show the size of each encoding as well.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use inpixly_shared::{
+    ChatEntry, ServerMessage, SignalingPayload,
+    codec::{self, Encoding, Frame},
+};
+
+const ENCODINGS: [Encoding; 2] = [Encoding::Json, Encoding::MessagePack];
+
+/// Offer as browsers send it for one video and one data channel
+fn sdp() -> String {
+    let mut sdp = String::from(
+        "v=0\r\no=- 4611731400430051336 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\n\
+         a=group:BUNDLE 0 1\r\na=extmap-allow-mixed\r\na=msid-semantic: WMS stream\r\n\
+         m=video 9 UDP/TLS/RTP/SAVPF 96 97 102 103 104 105 106 107 108 109 127 125\r\n\
+         c=IN IP4 0.0.0.0\r\na=rtcp:9 IN IP4 0.0.0.0\r\na=ice-ufrag:Fh4r\r\n\
+         a=ice-pwd:aKQ8u2nTuy0lEWq0Wd5GBQ1z\r\na=ice-options:trickle\r\n\
+         a=fingerprint:sha-256 6B:8B:5D:EA:59:04:20:23:29:C8:87:1C:CC:87:32:BE:DD:8C:66:A5:\
+         8E:50:55:EA:6D:98:0B:1A:49:1E:2C:50\r\na=setup:actpass\r\na=mid:0\r\na=sendonly\r\n",
+    );
+    for payload_type in [96, 97, 102, 103, 104, 105, 106, 107, 108, 109, 127, 125] {
+        sdp.push_str(&format!(
+            "a=rtpmap:{payload_type} VP8/90000\r\na=rtcp-fb:{payload_type} goog-remb\r\n\
+             a=rtcp-fb:{payload_type} transport-cc\r\na=rtcp-fb:{payload_type} ccm fir\r\n\
+             a=rtcp-fb:{payload_type} nack\r\na=rtcp-fb:{payload_type} nack pli\r\n"
+        ));
+    }
+    sdp.push_str(
+        "m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\nc=IN IP4 0.0.0.0\r\n\
+         a=ice-ufrag:Fh4r\r\na=ice-pwd:aKQ8u2nTuy0lEWq0Wd5GBQ1z\r\na=setup:actpass\r\n\
+         a=mid:1\r\na=sctp-port:5000\r\na=max-message-size:262144\r\n",
+    );
+    sdp
+}
+
+fn messages() -> Vec<(&'static str, ServerMessage)> {
+    vec![
+        (
+            "offer",
+            ServerMessage::SignalingMessage {
+                from: "alice".to_string(),
+                payload: SignalingPayload::Offer { sdp: sdp() },
+            },
+        ),
+        (
+            "ice_candidate",
+            ServerMessage::SignalingMessage {
+                from: "alice".to_string(),
+                payload: SignalingPayload::IceCandidate {
+                    candidate: r#"{"candidate":"candidate:842163049 1 udp 1677729535 203.0.113.7 46154 typ srflx raddr 192.168.1.20 rport 46154 generation 0 ufrag Fh4r network-cost 999","sdpMid":"0","sdpMLineIndex":0}"#
+                        .to_string(),
+                },
+            },
+        ),
+        (
+            "chat_history",
+            ServerMessage::ChatHistory {
+                before: None,
+                messages: (0..50)
+                    .map(|id| ChatEntry {
+                        id,
+                        from: "alice".parse().unwrap(),
+                        message: format!("Message number {id} of the chat log"),
+                    })
+                    .collect(),
+                has_more: true,
+            },
+        ),
+    ]
+}
+
+fn frame_len(frame: &Frame) -> usize {
+    match frame {
+        Frame::Text(text) => text.len(),
+        Frame::Binary(bytes) => bytes.len(),
+    }
+}
+
+fn encode(c: &mut Criterion) {
+    for (name, message) in messages() {
+        let mut group = c.benchmark_group(format!("encode/{name}"));
+        for encoding in ENCODINGS {
+            let size = frame_len(&codec::encode(&message, encoding).unwrap());
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(
+                BenchmarkId::from_parameter(format!("{encoding:?}")),
+                &message,
+                |b, message| b.iter(|| codec::encode(message, encoding).unwrap()),
+            );
+        }
+        group.finish();
+    }
+}
+
+fn decode(c: &mut Criterion) {
+    for (name, message) in messages() {
+        let mut group = c.benchmark_group(format!("decode/{name}"));
+        for encoding in ENCODINGS {
+            let frame = codec::encode(&message, encoding).unwrap();
+            group.throughput(Throughput::Bytes(frame_len(&frame) as u64));
+            group.bench_with_input(
+                BenchmarkId::from_parameter(format!("{encoding:?}")),
+                &frame,
+                |b, frame| {
+                    b.iter(|| match frame {
+                        Frame::Text(text) => codec::decode_text::<ServerMessage>(text).unwrap(),
+                        Frame::Binary(bytes) => {
+                            codec::decode_binary::<ServerMessage>(bytes).unwrap()
+                        }
+                    })
+                },
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
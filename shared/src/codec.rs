@@ -0,0 +1,146 @@
+use std::fmt;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{Capability, Negotiated};
+
+/// How a peer encodes the messages it sends.
+/// Text frames always carry JSON and binary frames MessagePack, so receivers decode by frame type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    /// MessagePack once both sides announced binary framing, JSON otherwise
+    pub fn negotiated(protocol: &Negotiated) -> Self {
+        if protocol.supports(Capability::BinaryFraming) {
+            Self::MessagePack
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// A message encoded for a WebSocket frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct CodecError(String);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+pub fn encode<T: Serialize>(message: &T, encoding: Encoding) -> Result<Frame, CodecError> {
+    match encoding {
+        Encoding::Json => serde_json::to_string(message)
+            .map(Frame::Text)
+            .map_err(|e| CodecError(e.to_string())),
+        // Maps rather than arrays, fields added with a default stay readable both ways
+        Encoding::MessagePack => rmp_serde::to_vec_named(message)
+            .map(Frame::Binary)
+            .map_err(|e| CodecError(e.to_string())),
+    }
+}
+
+/// Decode the content of a text frame
+pub fn decode_text<T: DeserializeOwned>(text: &str) -> Result<T, CodecError> {
+    serde_json::from_str(text).map_err(|e| CodecError(e.to_string()))
+}
+
+/// Decode the content of a binary frame
+pub fn decode_binary<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    rmp_serde::from_slice(bytes).map_err(|e| CodecError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ClientMessage, ProtocolInfo, ServerMessage,
+        tests::{client_messages, server_messages},
+    };
+
+    fn decode<T: DeserializeOwned>(frame: &Frame) -> T {
+        match frame {
+            Frame::Text(text) => decode_text(text).unwrap(),
+            Frame::Binary(bytes) => decode_binary(bytes).unwrap(),
+        }
+    }
+
+    #[test]
+    fn messages_round_trip_in_every_encoding() {
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            for message in client_messages() {
+                let frame = encode(&message, encoding).unwrap();
+                assert_eq!(decode::<ClientMessage>(&frame), message);
+            }
+            for message in server_messages() {
+                let frame = encode(&message, encoding).unwrap();
+                assert_eq!(decode::<ServerMessage>(&frame), message);
+            }
+        }
+    }
+
+    #[test]
+    fn encoding_picks_frame_type() {
+        let message = ClientMessage::Leave;
+        assert!(matches!(
+            encode(&message, Encoding::Json),
+            Ok(Frame::Text(_))
+        ));
+        assert!(matches!(
+            encode(&message, Encoding::MessagePack),
+            Ok(Frame::Binary(_))
+        ));
+    }
+
+    #[test]
+    fn binary_needs_both_sides() {
+        let server = ProtocolInfo::current(vec![Capability::BinaryFraming]);
+        let client = ProtocolInfo::current(vec![Capability::BinaryFraming]);
+        let legacy = ProtocolInfo::current(vec![]);
+        assert_eq!(
+            Encoding::negotiated(&server.negotiate(&client).unwrap()),
+            Encoding::MessagePack
+        );
+        assert_eq!(
+            Encoding::negotiated(&server.negotiate(&legacy).unwrap()),
+            Encoding::Json
+        );
+    }
+
+    /// Encoded length in JSON and in MessagePack
+    fn sizes<T: Serialize>(message: &T) -> (usize, usize) {
+        let Ok(Frame::Text(json)) = encode(message, Encoding::Json) else {
+            panic!("expected a text frame");
+        };
+        let Ok(Frame::Binary(bytes)) = encode(message, Encoding::MessagePack) else {
+            panic!("expected a binary frame");
+        };
+        (json.len(), bytes.len())
+    }
+
+    #[test]
+    fn message_pack_is_smaller() {
+        for message in client_messages() {
+            let (json, binary) = sizes(&message);
+            assert!(binary < json, "{message:?}: {binary} bytes, JSON {json}");
+        }
+        for message in server_messages() {
+            let (json, binary) = sizes(&message);
+            assert!(binary < json, "{message:?}: {binary} bytes, JSON {json}");
+        }
+    }
+}
@@ -2,12 +2,23 @@ use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::str::FromStr;
 
-/// WebSocket messages for signaling and presence
-#[derive(Debug, Clone, Serialize, Deserialize)]
+pub mod codec;
+mod protocol;
+
+pub use protocol::{Capability, MIN_PROTOCOL_VERSION, Negotiated, PROTOCOL_VERSION, ProtocolInfo};
+
+/// WebSocket messages sent by clients, see [`codec`] for how they are framed.
+/// Shares the `type` tag with [`ServerMessage`], a name means the same in both directions.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
-pub enum WsMessage {
-    // Client -> Server
-    Join(JoinRequest),
+pub enum ClientMessage {
+    /// First message of every connection
+    Join {
+        #[serde(flatten)]
+        request: JoinRequest,
+        #[serde(flatten)]
+        protocol: ProtocolInfo,
+    },
     Leave,
     Offer {
         to: String,
@@ -24,12 +35,44 @@ pub enum WsMessage {
     ChatMessage {
         message: String,
     },
+    /// Ask for chat messages older than the given entry id
+    FetchChatHistory {
+        before: u64,
+    },
+    /// Owner only: disconnect a member and forget its token
+    KickMember {
+        username: Username,
+    },
+    /// Owner only: like a kick, but the token and username are refused from then on,
+    /// and so are new members joining from the member's address
+    BanMember {
+        username: Username,
+    },
+    /// Signaling with the server's forwarding unit in SFU rooms
+    Sfu(SignalingPayload),
+    /// This member started or stopped sharing its screen
+    SetSharing {
+        sharing: bool,
+    },
+}
 
-    // Server -> Client
+/// WebSocket messages sent by the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
     JoinedAs {
         username: Username,
         token: String,
         is_owner: bool,
+        /// The member reconnected within its grace period, the room never saw it leave
+        #[serde(default)]
+        resumed: bool,
+        /// STUN and TURN servers to build peer connections with
+        #[serde(default)]
+        ice_servers: Vec<IceServer>,
+        /// Versions and capabilities of the server
+        #[serde(flatten)]
+        protocol: ProtocolInfo,
     },
     MemberJoined {
         username: Username,
@@ -48,15 +91,46 @@ pub enum WsMessage {
         from: Username,
         message: String,
     },
+    /// Chat log replayed after joining (`before` is `None`) or a page of older messages,
+    /// oldest first
+    ChatHistory {
+        before: Option<u64>,
+        messages: Vec<ChatEntry>,
+        has_more: bool,
+    },
     Error(ErrorKind),
     ForceDisconnect,
+    /// The room owner removed this member, sent right before the connection is closed
+    Removed {
+        banned: bool,
+    },
+    /// Notice from the operators of the server, not part of the chat log
+    Announcement {
+        message: String,
+    },
+    /// The server is shutting down and closes the connection right after this
+    ServerGoingDown {
+        /// When to try reconnecting, if the server expects to be back by then
+        #[serde(default)]
+        reconnect_after_secs: Option<u64>,
+    },
+    /// Signaling from the server's forwarding unit in SFU rooms
+    Sfu(SignalingPayload),
+    /// A member started or stopped sharing its screen
+    SharingChanged {
+        username: Username,
+        sharing: bool,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "kind", rename_all = "snake_case")]
 pub enum JoinRequest {
     WithToken {
         token: String,
+        /// Owner token of the room, grants owner privileges to this session
+        #[serde(default)]
+        owner_token: Option<String>,
     },
     WithUsername {
         username: Username,
@@ -64,7 +138,7 @@ pub enum JoinRequest {
     },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "kind", rename_all = "snake_case")]
 pub enum SignalingPayload {
     Offer { sdp: String },
@@ -72,19 +146,51 @@ pub enum SignalingPayload {
     IceCandidate { candidate: String },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "kind", rename_all = "snake_case")]
 pub enum ErrorKind {
     TokenNotFound,
     TokenAlreadyInUse,
     RoomNotFound,
-    InvalidUsername { message: String },
+    InvalidUsername {
+        message: String,
+    },
     UsernameTaken,
     PasswordRequired,
     IncorrectPassword,
     JoinTimeout,
-    TooManyAttempts,
-    Other { message: String },
+    TooManyAttempts {
+        retry_after_secs: u64,
+    },
+    MemberNotFound {
+        username: String,
+    },
+    MemberOffline {
+        username: String,
+    },
+    SfuNotEnabled,
+    NotOwner,
+    Banned,
+    /// The protocol versions of the client and the server do not overlap,
+    /// carries the versions the server speaks
+    IncompatibleProtocol {
+        protocol_version: u32,
+        min_protocol_version: u32,
+    },
+    Other {
+        message: String,
+    },
+}
+
+/// How media flows between the members of a room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomMode {
+    /// Every member connects directly to every other member
+    #[default]
+    Mesh,
+    /// Every member connects only to the server, which forwards media to the others
+    Sfu,
 }
 
 /// A validated room ID (UUID format)
@@ -331,6 +437,28 @@ impl AsRef<str> for Username {
 pub struct MemberInfo {
     pub username: Username,
     pub is_online: bool,
+    /// Whether the member is sharing its screen
+    #[serde(default)]
+    pub is_sharing: bool,
+}
+
+/// A STUN or TURN server, as in the `iceServers` of an `RTCConfiguration`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// A chat message retained in the room's log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatEntry {
+    /// Increasing id of the message within its room
+    pub id: u64,
+    pub from: Username,
+    pub message: String,
 }
 
 /// Request for POST /api/rooms
@@ -338,6 +466,8 @@ pub struct MemberInfo {
 pub struct CreateRoomRequest {
     pub username: Username,
     pub password: Option<Password>,
+    #[serde(default)]
+    pub mode: RoomMode,
 }
 
 /// Response from POST /api/rooms
@@ -354,6 +484,8 @@ pub struct CreateRoomResponse {
 pub struct RoomInfoResponse {
     pub exists: bool,
     pub has_password: bool,
+    #[serde(default)]
+    pub mode: RoomMode,
 }
 
 #[cfg(test)]
@@ -512,4 +644,204 @@ mod tests {
         let s: String = p.into();
         assert_eq!(s, "secret123");
     }
+
+    fn username(name: &str) -> Username {
+        name.parse().unwrap()
+    }
+
+    pub(crate) fn client_messages() -> Vec<ClientMessage> {
+        vec![
+            ClientMessage::Join {
+                request: JoinRequest::WithToken {
+                    token: "member-token".to_string(),
+                    owner_token: Some("owner-token".to_string()),
+                },
+                protocol: ProtocolInfo::current(vec![Capability::Sfu]),
+            },
+            ClientMessage::Join {
+                request: JoinRequest::WithUsername {
+                    username: username("alice"),
+                    password: Some("secret".parse().unwrap()),
+                },
+                protocol: ProtocolInfo::current(vec![Capability::ChatHistory]),
+            },
+            ClientMessage::Leave,
+            ClientMessage::Offer {
+                to: "bob".to_string(),
+                sdp: "v=0".to_string(),
+            },
+            ClientMessage::Answer {
+                to: "bob".to_string(),
+                sdp: "v=0".to_string(),
+            },
+            ClientMessage::IceCandidate {
+                to: "bob".to_string(),
+                candidate: "candidate:1".to_string(),
+            },
+            ClientMessage::ChatMessage {
+                message: "hello".to_string(),
+            },
+            ClientMessage::FetchChatHistory { before: 42 },
+            ClientMessage::KickMember {
+                username: username("bob"),
+            },
+            ClientMessage::BanMember {
+                username: username("bob"),
+            },
+            ClientMessage::Sfu(SignalingPayload::Offer {
+                sdp: "v=0".to_string(),
+            }),
+            ClientMessage::SetSharing { sharing: true },
+        ]
+    }
+
+    pub(crate) fn server_messages() -> Vec<ServerMessage> {
+        vec![
+            ServerMessage::JoinedAs {
+                username: username("alice"),
+                token: "member-token".to_string(),
+                is_owner: true,
+                resumed: false,
+                ice_servers: vec![IceServer {
+                    urls: vec!["turn:example.com".to_string()],
+                    username: Some("user".to_string()),
+                    credential: Some("pass".to_string()),
+                }],
+                protocol: ProtocolInfo::current(vec![Capability::Sfu, Capability::ChatHistory]),
+            },
+            ServerMessage::MemberJoined {
+                username: username("bob"),
+            },
+            ServerMessage::MemberLeft {
+                username: username("bob"),
+            },
+            ServerMessage::MemberList {
+                members: vec![MemberInfo {
+                    username: username("bob"),
+                    is_online: true,
+                    is_sharing: true,
+                }],
+            },
+            ServerMessage::SignalingMessage {
+                from: "bob".to_string(),
+                payload: SignalingPayload::IceCandidate {
+                    candidate: "candidate:1".to_string(),
+                },
+            },
+            ServerMessage::Chat {
+                from: username("bob"),
+                message: "hi".to_string(),
+            },
+            ServerMessage::ChatHistory {
+                before: Some(7),
+                messages: vec![ChatEntry {
+                    id: 6,
+                    from: username("bob"),
+                    message: "hi".to_string(),
+                }],
+                has_more: true,
+            },
+            ServerMessage::Error(ErrorKind::TooManyAttempts {
+                retry_after_secs: 30,
+            }),
+            ServerMessage::ForceDisconnect,
+            ServerMessage::Removed { banned: true },
+            ServerMessage::Announcement {
+                message: "Maintenance".to_string(),
+            },
+            ServerMessage::ServerGoingDown {
+                reconnect_after_secs: Some(5),
+            },
+            ServerMessage::Sfu(SignalingPayload::Answer {
+                sdp: "v=0".to_string(),
+            }),
+            ServerMessage::SharingChanged {
+                username: username("bob"),
+                sharing: false,
+            },
+        ]
+    }
+
+    /// Wire name of every client message, a new variant needs a sample above as well
+    fn client_type(message: &ClientMessage) -> &'static str {
+        match message {
+            ClientMessage::Join { .. } => "join",
+            ClientMessage::Leave => "leave",
+            ClientMessage::Offer { .. } => "offer",
+            ClientMessage::Answer { .. } => "answer",
+            ClientMessage::IceCandidate { .. } => "ice_candidate",
+            ClientMessage::ChatMessage { .. } => "chat_message",
+            ClientMessage::FetchChatHistory { .. } => "fetch_chat_history",
+            ClientMessage::KickMember { .. } => "kick_member",
+            ClientMessage::BanMember { .. } => "ban_member",
+            ClientMessage::Sfu(_) => "sfu",
+            ClientMessage::SetSharing { .. } => "set_sharing",
+        }
+    }
+
+    /// Wire name of every server message, a new variant needs a sample above as well
+    fn server_type(message: &ServerMessage) -> &'static str {
+        match message {
+            ServerMessage::JoinedAs { .. } => "joined_as",
+            ServerMessage::MemberJoined { .. } => "member_joined",
+            ServerMessage::MemberLeft { .. } => "member_left",
+            ServerMessage::MemberList { .. } => "member_list",
+            ServerMessage::SignalingMessage { .. } => "signaling_message",
+            ServerMessage::Chat { .. } => "chat",
+            ServerMessage::ChatHistory { .. } => "chat_history",
+            ServerMessage::Error(_) => "error",
+            ServerMessage::ForceDisconnect => "force_disconnect",
+            ServerMessage::Removed { .. } => "removed",
+            ServerMessage::Announcement { .. } => "announcement",
+            ServerMessage::ServerGoingDown { .. } => "server_going_down",
+            ServerMessage::Sfu(_) => "sfu",
+            ServerMessage::SharingChanged { .. } => "sharing_changed",
+        }
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        for message in client_messages() {
+            let json = serde_json::to_value(&message).unwrap();
+            assert_eq!(json["type"], client_type(&message));
+            let parsed: ClientMessage = serde_json::from_value(json).unwrap();
+            assert_eq!(parsed, message);
+        }
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        for message in server_messages() {
+            let json = serde_json::to_value(&message).unwrap();
+            assert_eq!(json["type"], server_type(&message));
+            let parsed: ServerMessage = serde_json::from_value(json).unwrap();
+            assert_eq!(parsed, message);
+        }
+    }
+
+    #[test]
+    fn wire_format_is_unchanged() {
+        let chat: ClientMessage =
+            serde_json::from_str(r#"{"type": "chat_message", "message": "hi"}"#).unwrap();
+        assert_eq!(
+            chat,
+            ClientMessage::ChatMessage {
+                message: "hi".to_string()
+            }
+        );
+        let error = serde_json::to_value(ServerMessage::Error(ErrorKind::MemberOffline {
+            username: "bob".to_string(),
+        }))
+        .unwrap();
+        assert_eq!(
+            error,
+            serde_json::json!({"type": "error", "kind": "member_offline", "username": "bob"})
+        );
+    }
+
+    #[test]
+    fn directions_do_not_mix() {
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"type": "force_disconnect"}"#).is_err());
+        assert!(serde_json::from_str::<ServerMessage>(r#"{"type": "leave"}"#).is_err());
+    }
 }
@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ErrorKind;
+
+/// Version of the WebSocket protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this build can still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features, each side announces the ones it supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Media forwarded by the server in SFU rooms
+    Sfu,
+    /// Chat log replayed after joining and paged through `FetchChatHistory`
+    ChatHistory,
+    /// Decodes MessagePack in binary WebSocket frames, see [`crate::codec`]
+    BinaryFraming,
+    /// Announced by a newer peer and unknown to this build, never negotiated
+    #[serde(other)]
+    Unknown,
+}
+
+/// Protocol versions and capabilities a peer supports, exchanged when joining
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolInfo {
+    /// Missing from clients predating versioning, which speak version 0
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub min_protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+impl ProtocolInfo {
+    /// The versions of this build with the given capabilities
+    pub fn current(capabilities: Vec<Capability>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    /// The version and capabilities both sides speak, the same whichever side asks.
+    /// Fails with our own versions when the ranges do not overlap.
+    pub fn negotiate(&self, peer: &ProtocolInfo) -> Result<Negotiated, ErrorKind> {
+        let protocol_version = self.protocol_version.min(peer.protocol_version);
+        if protocol_version < self.min_protocol_version.max(peer.min_protocol_version) {
+            return Err(ErrorKind::IncompatibleProtocol {
+                protocol_version: self.protocol_version,
+                min_protocol_version: self.min_protocol_version,
+            });
+        }
+        let capabilities = self
+            .capabilities
+            .iter()
+            .copied()
+            .filter(|capability| {
+                *capability != Capability::Unknown && peer.capabilities.contains(capability)
+            })
+            .collect();
+        Ok(Negotiated {
+            protocol_version,
+            capabilities,
+        })
+    }
+}
+
+/// Outcome of [`ProtocolInfo::negotiate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub protocol_version: u32,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Negotiated {
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientMessage, JoinRequest};
+
+    fn info(protocol_version: u32, min_protocol_version: u32) -> ProtocolInfo {
+        ProtocolInfo {
+            protocol_version,
+            min_protocol_version,
+            capabilities: vec![Capability::Sfu, Capability::ChatHistory],
+        }
+    }
+
+    #[test]
+    fn same_version_is_compatible() {
+        let ours = ProtocolInfo::current(vec![Capability::ChatHistory]);
+        let negotiated = ours.negotiate(&ours).unwrap();
+        assert_eq!(negotiated.protocol_version, PROTOCOL_VERSION);
+        assert!(negotiated.supports(Capability::ChatHistory));
+    }
+
+    #[test]
+    fn overlapping_ranges_use_lower_version() {
+        let newer = info(3, 2);
+        let older = info(2, 1);
+        assert_eq!(newer.negotiate(&older).unwrap().protocol_version, 2);
+        assert_eq!(
+            older.negotiate(&newer).unwrap(),
+            newer.negotiate(&older).unwrap()
+        );
+    }
+
+    #[test]
+    fn disjoint_ranges_are_incompatible() {
+        let newer = info(4, 3);
+        let older = info(2, 1);
+        assert!(matches!(
+            newer.negotiate(&older),
+            Err(ErrorKind::IncompatibleProtocol {
+                protocol_version: 4,
+                min_protocol_version: 3,
+            })
+        ));
+        assert!(matches!(
+            older.negotiate(&newer),
+            Err(ErrorKind::IncompatibleProtocol {
+                protocol_version: 2,
+                min_protocol_version: 1,
+            })
+        ));
+    }
+
+    #[test]
+    fn capabilities_need_both_sides() {
+        let server = ProtocolInfo::current(vec![Capability::Sfu, Capability::ChatHistory]);
+        let client =
+            ProtocolInfo::current(vec![Capability::ChatHistory, Capability::BinaryFraming]);
+        let negotiated = server.negotiate(&client).unwrap();
+        assert_eq!(negotiated.capabilities, vec![Capability::ChatHistory]);
+        assert_eq!(
+            client.negotiate(&server).unwrap().capabilities,
+            negotiated.capabilities
+        );
+    }
+
+    #[test]
+    fn unknown_capabilities_are_ignored() {
+        let peer: ProtocolInfo = serde_json::from_str(
+            r#"{"protocol_version": 1, "min_protocol_version": 1,
+                "capabilities": ["chat_history", "telepathy"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            peer.capabilities,
+            vec![Capability::ChatHistory, Capability::Unknown]
+        );
+        let negotiated = peer.negotiate(&peer).unwrap();
+        assert_eq!(negotiated.capabilities, vec![Capability::ChatHistory]);
+    }
+
+    #[test]
+    fn unversioned_join_is_incompatible() {
+        let message: ClientMessage =
+            serde_json::from_str(r#"{"type": "join", "kind": "with_token", "token": "abc"}"#)
+                .unwrap();
+        let ClientMessage::Join { request, protocol } = message else {
+            panic!("expected a join message");
+        };
+        assert!(matches!(request, JoinRequest::WithToken { token, .. } if token == "abc"));
+        assert_eq!(protocol.protocol_version, 0);
+        assert!(ProtocolInfo::current(vec![]).negotiate(&protocol).is_err());
+    }
+
+    #[test]
+    fn join_round_trips() {
+        let join = ClientMessage::Join {
+            request: JoinRequest::WithUsername {
+                username: "alice".parse().unwrap(),
+                password: None,
+            },
+            protocol: ProtocolInfo::current(vec![Capability::Sfu]),
+        };
+        let json = serde_json::to_string(&join).unwrap();
+        let ClientMessage::Join { request, protocol } = serde_json::from_str(&json).unwrap() else {
+            panic!("expected a join message");
+        };
+        assert!(
+            matches!(request, JoinRequest::WithUsername { username, .. } if username.as_str() == "alice")
+        );
+        assert_eq!(protocol, ProtocolInfo::current(vec![Capability::Sfu]));
+    }
+}